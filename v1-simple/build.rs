@@ -1,3 +1,8 @@
 fn main() {
     build_info_build::build_script();
+
+    tonic_build::configure()
+        .build_client(false)
+        .compile(&["proto/geyser_filter.proto"], &["proto"])
+        .expect("Failed to compile the gRPC protocol");
 }
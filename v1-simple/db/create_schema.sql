@@ -21,13 +21,15 @@ CREATE INDEX account_owner ON account (owner);
 
 CREATE INDEX account_slot ON account (slot);
 
--- The table storing slot information
+-- The table storing slot information, partitions are created and removed by the filter
 CREATE TABLE slot (
     slot BIGINT PRIMARY KEY,
     parent BIGINT,
     status VARCHAR(16) NOT NULL,
     updated_on TIMESTAMP
-);
+) PARTITION BY RANGE (slot);
+
+CREATE TABLE slot_genesis PARTITION OF slot FOR VALUES FROM (0) TO (1);
 
 INSERT INTO slot(slot, parent, status)
 VALUES (0, NULL, 'rooted');
@@ -206,9 +208,10 @@ CREATE INDEX spl_token_mint_index_mint_key ON spl_token_mint_index (mint_key);
 CREATE UNIQUE INDEX spl_token_mint_index_mint_pair ON spl_token_mint_index (mint_key, account_key);
 
 /**
- * The following is for keeping historical data for accounts and is not required for plugin to work.
+ * The following is for keeping historical data for accounts, it is written by the filter
+ * in the History and Both persistence modes and is not required in the LatestState mode.
  */
--- The table storing historical data for accounts
+-- The table storing historical data for accounts, partitions are created and removed by the filter
 CREATE TABLE account_audit (
     pubkey BYTEA,
     owner BYTEA,
@@ -220,21 +223,20 @@ CREATE TABLE account_audit (
     write_version BIGINT NOT NULL,
     updated_on TIMESTAMP NOT NULL,
     txn_signature BYTEA
-);
+) PARTITION BY RANGE (slot);
 
 CREATE INDEX account_audit_pubkey_slot_wv ON  account_audit (pubkey, slot, write_version);
+CREATE INDEX account_audit_owner ON account_audit (owner);
 
-CREATE FUNCTION audit_account_update() RETURNS trigger AS $audit_account_update$
-    BEGIN
-		INSERT INTO account_audit (pubkey, owner, lamports, slot, executable,
-		                           rent_epoch, data, write_version, updated_on, txn_signature)
-            VALUES (NEW.pubkey, NEW.owner, NEW.lamports, NEW.slot,
-                    NEW.executable, NEW.rent_epoch, NEW.data,
-                    NEW.write_version, NEW.updated_on, NEW.txn_signature);
-        RETURN NEW;
-    END;
-
-$audit_account_update$ LANGUAGE plpgsql;
-
-CREATE TRIGGER account_update_trigger AFTER INSERT OR UPDATE OR DELETE ON account
-    FOR EACH ROW EXECUTE PROCEDURE audit_account_update();
+/**
+ * The following is used only in the exactly once mode of the filter, it creates the table itself if it is missing.
+ */
+-- The next offset to read of every topic partition, written in the same transaction as the rows read before it
+CREATE TABLE kafka_offsets (
+    consumer_group VARCHAR NOT NULL,
+    topic VARCHAR NOT NULL,
+    partition INT NOT NULL,
+    next_offset BIGINT NOT NULL,
+    updated_on TIMESTAMP NOT NULL,
+    PRIMARY KEY (consumer_group, topic, partition)
+);
@@ -0,0 +1,58 @@
+/**
+ * The tables of create_schema.sql for the embedded SQLite backend.
+ * The filter applies this schema itself when it opens the database.
+ */
+-- The table storing accounts
+CREATE TABLE IF NOT EXISTS account (
+    pubkey BLOB PRIMARY KEY,
+    owner BLOB,
+    lamports INTEGER NOT NULL,
+    slot INTEGER NOT NULL,
+    executable BOOLEAN NOT NULL,
+    rent_epoch INTEGER NOT NULL,
+    data BLOB,
+    write_version INTEGER NOT NULL,
+    updated_on TEXT NOT NULL,
+    txn_signature BLOB
+);
+
+CREATE INDEX IF NOT EXISTS account_owner ON account (owner);
+
+CREATE INDEX IF NOT EXISTS account_slot ON account (slot);
+
+-- The table storing slot information
+CREATE TABLE IF NOT EXISTS slot (
+    slot INTEGER PRIMARY KEY,
+    parent INTEGER,
+    status TEXT NOT NULL,
+    updated_on TEXT
+);
+
+INSERT OR IGNORE INTO slot(slot, parent, status)
+VALUES (0, NULL, 'rooted');
+
+-- The table storing block metadata, rewards are a JSON array
+CREATE TABLE IF NOT EXISTS block (
+    slot INTEGER PRIMARY KEY,
+    blockhash TEXT,
+    rewards TEXT,
+    block_time INTEGER,
+    block_height INTEGER,
+    updated_on TEXT NOT NULL
+);
+
+-- The table storing historical data for accounts
+CREATE TABLE IF NOT EXISTS account_audit (
+    pubkey BLOB,
+    owner BLOB,
+    lamports INTEGER NOT NULL,
+    slot INTEGER NOT NULL,
+    executable BOOLEAN NOT NULL,
+    rent_epoch INTEGER NOT NULL,
+    data BLOB,
+    write_version INTEGER NOT NULL,
+    updated_on TEXT NOT NULL,
+    txn_signature BLOB
+);
+
+CREATE INDEX IF NOT EXISTS account_audit_pubkey_slot_wv ON account_audit (pubkey, slot, write_version);
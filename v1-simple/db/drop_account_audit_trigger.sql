@@ -0,0 +1,8 @@
+/**
+ * The filter writes account_audit itself according to its persistence_mode,
+ * so the audit trigger has to be dropped on databases created with an older create_schema.sql,
+ * otherwise every account version is stored twice.
+ */
+DROP TRIGGER IF EXISTS account_update_trigger ON account;
+
+DROP FUNCTION IF EXISTS audit_account_update();
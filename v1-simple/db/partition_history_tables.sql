@@ -0,0 +1,53 @@
+/**
+ * Converts account_audit and slot tables created with an older create_schema.sql to tables
+ * partitioned by slot range. The existing rows become a single partition, which is removed
+ * by the retention policy once all of its slots are out of the retention window.
+ *
+ * Stop the filter before running the script. partition_size must match partition_size_slots
+ * from the filter config.
+ */
+DO $$
+DECLARE
+    partition_size CONSTANT BIGINT := 432000;
+    boundary BIGINT;
+BEGIN
+    -- account_audit
+    SELECT (COALESCE(max(slot), 0) / partition_size + 1) * partition_size INTO boundary FROM account_audit;
+
+    ALTER TABLE account_audit RENAME TO account_audit_legacy;
+    ALTER INDEX account_audit_pubkey_slot_wv RENAME TO account_audit_legacy_pubkey_slot_wv;
+
+    CREATE TABLE account_audit (
+        pubkey BYTEA,
+        owner BYTEA,
+        lamports BIGINT NOT NULL,
+        slot BIGINT NOT NULL,
+        executable BOOL NOT NULL,
+        rent_epoch BIGINT NOT NULL,
+        data BYTEA,
+        write_version BIGINT NOT NULL,
+        updated_on TIMESTAMP NOT NULL,
+        txn_signature BYTEA
+    ) PARTITION BY RANGE (slot);
+
+    CREATE INDEX account_audit_pubkey_slot_wv ON account_audit (pubkey, slot, write_version);
+    CREATE INDEX account_audit_owner ON account_audit (owner);
+
+    EXECUTE format('ALTER TABLE account_audit ATTACH PARTITION account_audit_legacy FOR VALUES FROM (MINVALUE) TO (%s)', boundary);
+
+    -- slot
+    SELECT (COALESCE(max(slot), 0) / partition_size + 1) * partition_size INTO boundary FROM slot;
+
+    ALTER TABLE slot RENAME TO slot_legacy;
+    ALTER TABLE slot_legacy RENAME CONSTRAINT slot_pkey TO slot_legacy_pkey;
+
+    CREATE TABLE slot (
+        slot BIGINT PRIMARY KEY,
+        parent BIGINT,
+        status VARCHAR(16) NOT NULL,
+        updated_on TIMESTAMP
+    ) PARTITION BY RANGE (slot);
+
+    EXECUTE format('ALTER TABLE slot ATTACH PARTITION slot_legacy FOR VALUES FROM (MINVALUE) TO (%s)', boundary);
+END
+$$;
@@ -0,0 +1,302 @@
+use std::fmt;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use kafka_common::kafka_structs::KafkaSlotStatus;
+use kafka_common::kafka_structs::NotifyBlockMetaData;
+use kafka_common::kafka_structs::UpdateSlotStatus;
+use reqwest::Client;
+use reqwest::StatusCode;
+
+use crate::config::ClickHouseSinkConfig;
+use crate::db::DbAccountInfo;
+use crate::db::DbBlockInfo;
+use crate::db_retry::ErrorKind;
+use crate::sink::Sink;
+
+const ACCOUNT_COLUMNS: &str = "pubkey, lamports, owner, executable, rent_epoch, data, write_version, txn_signature, slot, is_startup, retrieved_time";
+const BLOCK_COLUMNS: &str = "slot, hash, notify_block_json, retrieved_time";
+const SLOT_COLUMNS: &str = "slot, parent, slot_status, retrieved_time";
+
+#[derive(Debug)]
+struct ClickHouseError {
+    status: StatusCode,
+    message: String,
+}
+
+impl fmt::Display for ClickHouseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ClickHouse returned {}: {}",
+            self.status,
+            self.message.trim()
+        )
+    }
+}
+
+impl std::error::Error for ClickHouseError {}
+
+// Encoder of the RowBinary format, see https://clickhouse.com/docs/en/interfaces/formats#rowbinary
+#[derive(Default)]
+struct RowBinary(Vec<u8>);
+
+impl RowBinary {
+    fn leb128(&mut self, mut value: u64) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.0.push(byte);
+                return;
+            }
+            self.0.push(byte | 0x80);
+        }
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.0.push(value as u8);
+    }
+
+    fn enum8(&mut self, value: i8) {
+        self.0.push(value as u8);
+    }
+
+    fn bytes(&mut self, value: &[u8]) {
+        self.leb128(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+
+    // Array(UInt8)
+    fn array_u8(&mut self, value: &[u8]) {
+        self.bytes(value);
+    }
+
+    // Array(Nullable(UInt8)), the elements are never null
+    fn array_nullable_u8(&mut self, value: &[u8]) {
+        self.leb128(value.len() as u64);
+        for byte in value {
+            self.0.push(0);
+            self.0.push(*byte);
+        }
+    }
+
+    fn nullable_u64(&mut self, value: Option<u64>) {
+        match value {
+            Some(value) => {
+                self.0.push(0);
+                self.u64(value);
+            }
+            None => self.0.push(1),
+        }
+    }
+
+    // DateTime64 with the default precision of 3
+    fn datetime64(&mut self, value: &NaiveDateTime) {
+        self.0
+            .extend_from_slice(&value.and_utc().timestamp_millis().to_le_bytes());
+    }
+}
+
+fn slot_status(status: &KafkaSlotStatus) -> i8 {
+    match status {
+        KafkaSlotStatus::Processed => 1,
+        KafkaSlotStatus::Rooted => 2,
+        KafkaSlotStatus::Confirmed => 3,
+    }
+}
+
+// Writes the rows in RowBinary batches over the HTTP interface
+pub struct ClickHouseSink {
+    config: ClickHouseSinkConfig,
+    client: Client,
+}
+
+impl ClickHouseSink {
+    pub fn new(config: ClickHouseSinkConfig) -> Self {
+        Self {
+            config,
+            client: Client::new(),
+        }
+    }
+
+    async fn insert(&self, table: &str, columns: &str, body: RowBinary) -> Result<()> {
+        let query = format!("INSERT INTO {table} ({columns}) FORMAT RowBinary");
+
+        let mut request = self
+            .client
+            .post(&self.config.url)
+            .query(&[("database", &self.config.database), ("query", &query)])
+            .body(body.0);
+
+        if let Some(user) = &self.config.user {
+            request = request.header("X-ClickHouse-User", user);
+        }
+        if let Some(password) = &self.config.password {
+            request = request.header("X-ClickHouse-Key", password);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| anyhow!(e).context(format!("Failed to send INSERT INTO {table}")))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let message = response.text().await.unwrap_or_default();
+            return Err(anyhow!(ClickHouseError { status, message }));
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for ClickHouseSink {
+    fn name(&self) -> &str {
+        "clickhouse"
+    }
+
+    fn batch_size(&self) -> usize {
+        self.config.batch_size
+    }
+
+    fn classify_error(&self, error: &anyhow::Error) -> ErrorKind {
+        if let Some(e) = error.downcast_ref::<ClickHouseError>() {
+            // Rows that can't be parsed are rejected with Bad Request
+            return match e.status {
+                StatusCode::BAD_REQUEST => ErrorKind::Permanent,
+                _ => ErrorKind::Transient,
+            };
+        }
+
+        match error
+            .chain()
+            .find_map(|e| e.downcast_ref::<reqwest::Error>())
+        {
+            Some(e) if e.is_connect() || e.is_timeout() => ErrorKind::Connection,
+            _ => ErrorKind::Transient,
+        }
+    }
+
+    async fn write_accounts(&self, accounts: &[DbAccountInfo]) -> Result<()> {
+        let mut body = RowBinary::default();
+        for account in accounts {
+            body.array_u8(&account.pubkey);
+            body.u64(account.lamports as u64);
+            body.array_u8(&account.owner);
+            body.bool(account.executable);
+            body.u64(account.rent_epoch as u64);
+            body.array_u8(&account.data);
+            body.u64(account.write_version as u64);
+            body.array_nullable_u8(account.txn_signature.as_deref().unwrap_or_default());
+            body.u64(account.slot as u64);
+            body.bool(account.is_startup);
+            body.datetime64(&account.retrieved_time);
+        }
+
+        self.insert(&self.config.account_table, ACCOUNT_COLUMNS, body)
+            .await
+    }
+
+    async fn write_blocks(&self, blocks: &[DbBlockInfo]) -> Result<()> {
+        let mut body = RowBinary::default();
+        for block in blocks {
+            body.u64(block.slot as u64);
+            body.bytes(block.blockhash.as_bytes());
+            // The materialized views read slot and retrieved_time from the event of the geyser plugin
+            body.bytes(&serde_json::to_vec(&NotifyBlockMetaData::from(block))?);
+            body.datetime64(&block.retrieved_time);
+        }
+
+        self.insert(&self.config.block_table, BLOCK_COLUMNS, body)
+            .await
+    }
+
+    async fn write_slots(&self, slots: &[UpdateSlotStatus]) -> Result<()> {
+        let mut body = RowBinary::default();
+        for slot in slots {
+            body.u64(slot.slot);
+            body.nullable_u64(slot.parent);
+            body.enum8(slot_status(&slot.status));
+            body.datetime64(&slot.retrieved_time);
+        }
+
+        self.insert(&self.config.slot_table, SLOT_COLUMNS, body)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+
+    fn encode(encode: impl FnOnce(&mut RowBinary)) -> Vec<u8> {
+        let mut body = RowBinary::default();
+        encode(&mut body);
+        body.0
+    }
+
+    #[test]
+    fn encodes_lengths_as_leb128() {
+        assert_eq!(encode(|b| b.leb128(0)), vec![0x00]);
+        assert_eq!(encode(|b| b.leb128(127)), vec![0x7f]);
+        assert_eq!(encode(|b| b.leb128(128)), vec![0x80, 0x01]);
+        assert_eq!(encode(|b| b.leb128(300)), vec![0xac, 0x02]);
+        assert_eq!(
+            encode(|b| b.leb128(u64::MAX)),
+            [vec![0xff; 9], vec![0x01]].concat()
+        );
+    }
+
+    #[test]
+    fn encodes_fixed_size_values_little_endian() {
+        assert_eq!(
+            encode(|b| b.u64(0x0102030405060708)),
+            vec![8, 7, 6, 5, 4, 3, 2, 1]
+        );
+        assert_eq!(encode(|b| b.bool(true)), vec![1]);
+        assert_eq!(
+            encode(|b| b.enum8(slot_status(&KafkaSlotStatus::Rooted))),
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn prefixes_strings_and_arrays_with_their_length() {
+        assert_eq!(encode(|b| b.bytes(b"abc")), vec![3, b'a', b'b', b'c']);
+        assert_eq!(encode(|b| b.array_u8(&[])), vec![0]);
+        assert_eq!(
+            encode(|b| b.array_nullable_u8(&[7, 9])),
+            vec![2, 0, 7, 0, 9]
+        );
+    }
+
+    #[test]
+    fn marks_nulls_with_a_leading_byte() {
+        assert_eq!(encode(|b| b.nullable_u64(None)), vec![1]);
+        assert_eq!(
+            encode(|b| b.nullable_u64(Some(1))),
+            vec![0, 1, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn encodes_datetimes_as_milliseconds() {
+        let time = DateTime::from_timestamp_millis(1_700_000_000_123)
+            .unwrap()
+            .naive_utc();
+        assert_eq!(
+            encode(|b| b.datetime64(&time)),
+            1_700_000_000_123i64.to_le_bytes().to_vec()
+        );
+    }
+}
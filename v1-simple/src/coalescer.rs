@@ -0,0 +1,182 @@
+use std::collections::hash_map::Entry;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ahash::AHashMap;
+use crossbeam_queue::SegQueue;
+use log::trace;
+
+use crate::db::DbAccountInfo;
+use crate::sink::CoalescerInput;
+use crate::sink::Sinks;
+
+#[inline(always)]
+fn is_newer(account: &DbAccountInfo, than: &DbAccountInfo) -> bool {
+    (account.slot, account.write_version) > (than.slot, than.write_version)
+}
+
+// Keeps only the latest version of every pubkey seen during the window and passes it on to the sinks.
+// Superseded versions are published as account history if `keep_history` is set.
+pub async fn account_coalescer(
+    window: Duration,
+    input_queue: Arc<SegQueue<CoalescerInput>>,
+    sinks: Arc<Sinks>,
+    keep_history: bool,
+) {
+    // Every version keeps the span and the message of the update it came from,
+    // a dropped version releases its message
+    let mut latest: AHashMap<Vec<u8>, CoalescerInput> = AHashMap::new();
+    let mut interval = tokio::time::interval(window);
+
+    loop {
+        interval.tick().await;
+
+        let mut received = 0;
+        while let Some(account) = input_queue.pop() {
+            received += 1;
+
+            let superseded = match latest.entry(account.0.pubkey.clone()) {
+                Entry::Occupied(mut entry) => {
+                    if is_newer(&account.0, &entry.get().0) {
+                        Some(entry.insert(account))
+                    } else {
+                        Some(account)
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(account);
+                    None
+                }
+            };
+
+            if let Some((superseded, span, message)) = superseded.filter(|_| keep_history) {
+                sinks.publish_account_history(superseded, span, message);
+            }
+        }
+
+        if received > 0 {
+            trace!(
+                "Coalesced {received} account updates into {} rows",
+                latest.len()
+            );
+        }
+
+        latest.drain().for_each(|(_, (account, span, message))| {
+            sinks.publish_account(account, span, message)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use opentelemetry::trace::SpanContext;
+
+    use super::*;
+    use crate::consumer::OffsetTracker;
+    use crate::consumer_stats::Stats;
+    use crate::sink::SinkQueues;
+
+    fn account(pubkey: u8, slot: i64, write_version: i64) -> DbAccountInfo {
+        DbAccountInfo {
+            pubkey: vec![pubkey],
+            lamports: slot * 10 + write_version,
+            owner: vec![0],
+            executable: false,
+            rent_epoch: 0,
+            data: Vec::new(),
+            slot,
+            write_version,
+            txn_signature: None,
+            is_startup: false,
+            retrieved_time: Utc::now().naive_utc(),
+            source: None,
+        }
+    }
+
+    #[test]
+    fn orders_versions_by_slot_then_write_version() {
+        assert!(is_newer(&account(1, 2, 0), &account(1, 1, 5)));
+        assert!(is_newer(&account(1, 2, 6), &account(1, 2, 5)));
+        assert!(!is_newer(&account(1, 2, 5), &account(1, 2, 5)));
+        assert!(!is_newer(&account(1, 1, 9), &account(1, 2, 0)));
+    }
+
+    // Runs the coalescer over a single window
+    async fn coalesce(
+        accounts: Vec<DbAccountInfo>,
+        keep_history: bool,
+    ) -> (Arc<SinkQueues>, Arc<OffsetTracker>) {
+        let queues = Arc::new(SinkQueues::new(&Stats::default(), "test", false));
+        let sinks = Arc::new(Sinks::new(vec![queues.clone()], None));
+        let tracker = Arc::new(OffsetTracker::default());
+        let input_queue = Arc::new(SegQueue::new());
+        for (offset, account) in accounts.into_iter().enumerate() {
+            input_queue.push((
+                account,
+                SpanContext::empty_context(),
+                tracker.received(0, offset as i64),
+            ));
+        }
+
+        let coalescer = tokio::spawn(account_coalescer(
+            Duration::from_secs(60),
+            input_queue,
+            sinks,
+            keep_history,
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        coalescer.abort();
+        let _ = coalescer.await;
+
+        (queues, tracker)
+    }
+
+    #[tokio::test]
+    async fn keeps_the_latest_version_of_every_pubkey() {
+        let (queues, _tracker) = coalesce(
+            vec![
+                account(1, 5, 1),
+                account(2, 5, 2),
+                account(1, 6, 0),
+                // Arrived late, older than what was seen already
+                account(1, 4, 9),
+            ],
+            false,
+        )
+        .await;
+
+        let mut latest: Vec<(Vec<u8>, i64)> = std::iter::from_fn(|| queues.accounts.pop())
+            .map(|queued| (queued.item.pubkey, queued.item.slot))
+            .collect();
+        latest.sort();
+        assert_eq!(latest, vec![(vec![1], 6), (vec![2], 5)]);
+        assert!(queues.account_history.is_empty());
+    }
+
+    #[tokio::test]
+    async fn publishes_the_superseded_versions_as_history() {
+        let (queues, _tracker) = coalesce(
+            vec![account(1, 5, 1), account(1, 6, 0), account(1, 4, 9)],
+            true,
+        )
+        .await;
+
+        assert_eq!(queues.accounts.len(), 1);
+        let mut history: Vec<i64> = std::iter::from_fn(|| queues.account_history.pop())
+            .map(|queued| queued.item.slot)
+            .collect();
+        history.sort();
+        assert_eq!(history, vec![4, 5]);
+    }
+
+    #[tokio::test]
+    async fn releases_the_messages_of_dropped_versions() {
+        let (queues, tracker) = coalesce(vec![account(1, 5, 1), account(1, 6, 0)], false).await;
+
+        // The superseded version's message is done, the latest one waits in the sink queue
+        assert_eq!(tracker.take_committable(), vec![(0, 1)]);
+        drop(queues.accounts.pop());
+        assert_eq!(tracker.take_committable(), vec![(0, 2)]);
+    }
+}
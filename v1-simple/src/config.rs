@@ -2,7 +2,7 @@ use ahash::AHashSet;
 use log::LevelFilter;
 use rdkafka::config::RDKafkaLogLevel;
 use serde::{Deserialize, Serialize};
-use std::{env, str::FromStr};
+use std::{env, fmt::Display, str::FromStr};
 use strum_macros::EnumString;
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, EnumString)]
@@ -73,6 +73,425 @@ impl From<&GlobalLogLevel> for LevelFilter {
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize, EnumString)]
+pub enum PersistenceMode {
+    /// Only the latest version of every account is kept in the `account` table.
+    LatestState,
+    /// Every version is appended to `account_audit`, the `account` table is not written.
+    History,
+    /// The `account` table is upserted and every version is appended to `account_audit`.
+    #[default]
+    Both,
+}
+
+impl PersistenceMode {
+    pub fn writes_history(&self) -> bool {
+        matches!(self, PersistenceMode::History | PersistenceMode::Both)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize, EnumString)]
+pub enum TracingExporter {
+    /// Spans are not recorded.
+    #[default]
+    None,
+    /// Spans are exported in batches to `otlp_endpoint` over gRPC.
+    Otlp,
+    /// Spans are printed to stdout as they end, for local use.
+    Stdout,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize, EnumString)]
+pub enum RetentionAction {
+    /// Expired partitions are dropped.
+    #[default]
+    Drop,
+    /// Expired partitions are detached and kept as standalone tables for archiving.
+    Detach,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClickHouseSinkConfig {
+    // HTTP interface of the server, i.e. http://localhost:8123
+    pub url: String,
+    #[serde(default = "default_clickhouse_database")]
+    pub database: String,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    // The tables must have the columns of v2-advanced/clickhouse/create_*.sql
+    #[serde(default = "default_clickhouse_account_table")]
+    pub account_table: String,
+    #[serde(default = "default_clickhouse_block_table")]
+    pub block_table: String,
+    #[serde(default = "default_clickhouse_slot_table")]
+    pub slot_table: String,
+    // Rows per INSERT
+    #[serde(default = "default_clickhouse_batch_size")]
+    pub batch_size: usize,
+}
+
+impl ClickHouseSinkConfig {
+    fn from_env() -> Self {
+        Self {
+            url: env::var("CLICKHOUSE_URL").expect("CLICKHOUSE_URL is not set"),
+            database: env::var("CLICKHOUSE_DATABASE")
+                .unwrap_or_else(|_| default_clickhouse_database()),
+            user: env::var("CLICKHOUSE_USER").ok(),
+            password: env::var("CLICKHOUSE_PASSWORD").ok(),
+            account_table: env::var("CLICKHOUSE_ACCOUNT_TABLE")
+                .unwrap_or_else(|_| default_clickhouse_account_table()),
+            block_table: env::var("CLICKHOUSE_BLOCK_TABLE")
+                .unwrap_or_else(|_| default_clickhouse_block_table()),
+            slot_table: env::var("CLICKHOUSE_SLOT_TABLE")
+                .unwrap_or_else(|_| default_clickhouse_slot_table()),
+            batch_size: env_parse_or("CLICKHOUSE_BATCH_SIZE", default_clickhouse_batch_size()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KafkaSinkConfig {
+    // The input cluster and its security settings are used if not set
+    pub bootstrap_servers: Option<String>,
+    // Events of a kind are not published if its topic is not set
+    pub update_account_topic: Option<String>,
+    pub update_slot_topic: Option<String>,
+    pub notify_block_topic: Option<String>,
+    #[serde(default = "default_kafka_sink_message_timeout_ms")]
+    pub message_timeout_ms: String,
+    #[serde(default = "default_kafka_sink_compression_codec")]
+    pub compression_codec: String,
+    // Events per write, they are produced concurrently
+    #[serde(default = "default_kafka_sink_batch_size")]
+    pub batch_size: usize,
+}
+
+impl KafkaSinkConfig {
+    fn from_env() -> Self {
+        Self {
+            bootstrap_servers: env::var("KAFKA_SINK_BOOTSTRAP_SERVERS").ok(),
+            update_account_topic: env::var("KAFKA_SINK_UPDATE_ACCOUNT_TOPIC").ok(),
+            update_slot_topic: env::var("KAFKA_SINK_UPDATE_SLOT_TOPIC").ok(),
+            notify_block_topic: env::var("KAFKA_SINK_NOTIFY_BLOCK_TOPIC").ok(),
+            message_timeout_ms: env::var("KAFKA_SINK_MESSAGE_TIMEOUT_MS")
+                .unwrap_or_else(|_| default_kafka_sink_message_timeout_ms()),
+            compression_codec: env::var("KAFKA_SINK_COMPRESSION_CODEC")
+                .unwrap_or_else(|_| default_kafka_sink_compression_codec()),
+            batch_size: env_parse_or("KAFKA_SINK_BATCH_SIZE", default_kafka_sink_batch_size()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize, EnumString)]
+pub enum ParquetPartitioning {
+    /// A directory per `partition_size_slots` slots, i.e. `slots=432000`.
+    #[default]
+    SlotRange,
+    /// A directory per day of `retrieved_time`, i.e. `date=2023-01-31`.
+    Date,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize, EnumString)]
+pub enum ParquetCompression {
+    Uncompressed,
+    Snappy,
+    #[default]
+    Zstd,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ParquetSinkConfig {
+    // Root directory of the archive, every table gets a subdirectory
+    pub path: String,
+    #[serde(default)]
+    pub partitioning: ParquetPartitioning,
+    #[serde(default = "default_partition_size_slots")]
+    pub partition_size_slots: u64,
+    #[serde(default = "default_parquet_row_group_size")]
+    pub row_group_size: usize,
+    #[serde(default)]
+    pub compression: ParquetCompression,
+    // A file is closed and a new one is started after this many rows or seconds
+    #[serde(default = "default_parquet_max_file_rows")]
+    pub max_file_rows: usize,
+    #[serde(default = "default_parquet_max_file_age_secs")]
+    pub max_file_age_secs: u64,
+}
+
+impl ParquetSinkConfig {
+    fn from_env() -> Self {
+        Self {
+            path: env::var("PARQUET_PATH").expect("PARQUET_PATH is not set"),
+            partitioning: env_parse_or("PARQUET_PARTITIONING", ParquetPartitioning::default()),
+            partition_size_slots: env_parse_or(
+                "PARQUET_PARTITION_SIZE_SLOTS",
+                default_partition_size_slots(),
+            ),
+            row_group_size: env_parse_or(
+                "PARQUET_ROW_GROUP_SIZE",
+                default_parquet_row_group_size(),
+            ),
+            compression: env_parse_or("PARQUET_COMPRESSION", ParquetCompression::default()),
+            max_file_rows: env_parse_or("PARQUET_MAX_FILE_ROWS", default_parquet_max_file_rows()),
+            max_file_age_secs: env_parse_or(
+                "PARQUET_MAX_FILE_AGE_SECS",
+                default_parquet_max_file_age_secs(),
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WebhookSinkConfig {
+    // Must be unique when there are several webhooks, it names the spill directories and metrics
+    #[serde(default = "default_webhook_name")]
+    pub name: String,
+    pub url: String,
+    // Key of the HMAC-SHA256 signature sent in the X-Signature header, requests are not signed if not set
+    pub secret: Option<String>,
+    // Only the accounts matching these rules are sent, all filtered accounts if both are empty
+    #[serde(default)]
+    pub include_owners: AHashSet<String>,
+    #[serde(default)]
+    pub include_pubkeys: AHashSet<String>,
+    // Accounts per request
+    #[serde(default = "default_webhook_batch_size")]
+    pub batch_size: usize,
+    #[serde(default = "default_webhook_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn env_set(name: &str) -> AHashSet<String> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+impl WebhookSinkConfig {
+    fn from_env() -> Self {
+        Self {
+            name: default_webhook_name(),
+            url: env::var("WEBHOOK_URL").expect("WEBHOOK_URL is not set"),
+            secret: env::var("WEBHOOK_SECRET").ok(),
+            include_owners: env_set("WEBHOOK_INCLUDE_OWNERS"),
+            include_pubkeys: env_set("WEBHOOK_INCLUDE_PUBKEYS"),
+            batch_size: env_parse_or("WEBHOOK_BATCH_SIZE", default_webhook_batch_size()),
+            timeout_ms: env_parse_or("WEBHOOK_TIMEOUT_MS", default_webhook_timeout_ms()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SinkConfig {
+    /// The tables of `db/create_schema.sql` at `postgres_connection_str`.
+    Postgres,
+    /// The tables of `v2-advanced/clickhouse`, written over the HTTP interface.
+    ClickHouse(ClickHouseSinkConfig),
+    /// The events the rows were made of, in the format of the geyser plugin.
+    Kafka(KafkaSinkConfig),
+    /// Rolling Parquet files in a local directory.
+    Parquet(ParquetSinkConfig),
+    /// JSON POST requests with the changed accounts.
+    Webhook(WebhookSinkConfig),
+}
+
+impl SinkConfig {
+    // Every sink reads its own settings from the environment
+    fn from_env(name: &str) -> Self {
+        match name {
+            "Postgres" => SinkConfig::Postgres,
+            "ClickHouse" => SinkConfig::ClickHouse(ClickHouseSinkConfig::from_env()),
+            "Kafka" => SinkConfig::Kafka(KafkaSinkConfig::from_env()),
+            "Parquet" => SinkConfig::Parquet(ParquetSinkConfig::from_env()),
+            "Webhook" => SinkConfig::Webhook(WebhookSinkConfig::from_env()),
+            name => panic!("SINKS contains an unknown sink {name}"),
+        }
+    }
+}
+
+fn env_parse_or<T>(name: &str, default: T) -> T
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|e| panic!("{name} has a wrong value {value}, error: {e}")),
+        Err(_) => default,
+    }
+}
+
+fn default_sinks() -> Vec<SinkConfig> {
+    vec![SinkConfig::Postgres]
+}
+
+fn default_clickhouse_database() -> String {
+    "events".to_string()
+}
+
+fn default_clickhouse_account_table() -> String {
+    "update_account_local".to_string()
+}
+
+fn default_clickhouse_block_table() -> String {
+    "notify_block_local".to_string()
+}
+
+fn default_clickhouse_slot_table() -> String {
+    "update_slot_local".to_string()
+}
+
+fn default_clickhouse_batch_size() -> usize {
+    10_000
+}
+
+fn default_kafka_sink_message_timeout_ms() -> String {
+    "30000".to_string()
+}
+
+fn default_kafka_sink_compression_codec() -> String {
+    "zstd".to_string()
+}
+
+fn default_kafka_sink_batch_size() -> usize {
+    1000
+}
+
+fn default_parquet_row_group_size() -> usize {
+    100_000
+}
+
+fn default_parquet_max_file_rows() -> usize {
+    1_000_000
+}
+
+fn default_parquet_max_file_age_secs() -> u64 {
+    3600
+}
+
+fn default_webhook_name() -> String {
+    "webhook".to_string()
+}
+
+fn default_webhook_batch_size() -> usize {
+    100
+}
+
+fn default_webhook_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_spill_queue_segment_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_spill_queue_max_size() -> u64 {
+    10 * 1024 * 1024 * 1024
+}
+
+fn default_spill_queue_memory_limit() -> usize {
+    100_000
+}
+
+fn default_postgres_batch_size() -> usize {
+    100
+}
+
+fn default_db_max_retries() -> u32 {
+    10
+}
+
+fn default_db_retry_backoff_ms() -> u64 {
+    500
+}
+
+fn default_db_retry_max_backoff_ms() -> u64 {
+    60_000
+}
+
+fn default_db_stall_timeout_secs() -> u64 {
+    3600
+}
+
+fn default_account_coalesce_keep_history() -> bool {
+    true
+}
+
+fn default_slot_atomic_grace_ms() -> u64 {
+    400
+}
+
+fn default_slot_atomic_max_pending_slots() -> u64 {
+    32
+}
+
+fn default_slot_atomic_max_pending_rows() -> usize {
+    100_000
+}
+
+fn default_pubsub_connection_buffer() -> usize {
+    10_000
+}
+
+// The limit of Solana validators
+fn default_rpc_max_body_size() -> usize {
+    50 * 1024
+}
+
+fn default_rpc_max_program_accounts() -> usize {
+    10_000
+}
+
+fn default_grpc_stream_buffer() -> usize {
+    10_000
+}
+
+fn default_ready_max_queue_depth() -> usize {
+    100_000
+}
+
+fn default_ready_max_kafka_lag() -> u64 {
+    100_000
+}
+
+fn default_filter_hot_pubkeys_capacity() -> usize {
+    1024
+}
+
+fn default_otlp_endpoint() -> String {
+    "http://localhost:4317".to_string()
+}
+
+fn default_tracing_sample_ratio() -> f64 {
+    1.0
+}
+
+fn default_exactly_once_batch_size() -> usize {
+    1000
+}
+
+fn default_exactly_once_batch_ms() -> u64 {
+    100
+}
+
+fn default_partition_size_slots() -> u64 {
+    // One epoch
+    432_000
+}
+
+fn default_partitions_ahead() -> u64 {
+    2
+}
+
+fn default_maintenance_interval_secs() -> u64 {
+    600
+}
+
 pub fn env_build_config() -> FilterConfig {
     let filter_log_path = env::var("FILTER_LOG_PATH").expect("FILTER_LOG_PATH is not set");
     let bootstrap_servers = env::var("BOOTSTRAP_SERVERS").expect("BOOTSTRAP_SERVERS is not set");
@@ -117,6 +536,106 @@ pub fn env_build_config() -> FilterConfig {
     )
     .unwrap_or(GlobalLogLevel::Info);
 
+    let sinks = match env::var("SINKS") {
+        Ok(sinks) => sinks
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(SinkConfig::from_env)
+            .collect(),
+        Err(_) => default_sinks(),
+    };
+
+    let spill_queue_path = env::var("SPILL_QUEUE_PATH").ok();
+    let spill_queue_segment_size = env_parse_or(
+        "SPILL_QUEUE_SEGMENT_SIZE",
+        default_spill_queue_segment_size(),
+    );
+    let spill_queue_max_size = env_parse_or("SPILL_QUEUE_MAX_SIZE", default_spill_queue_max_size());
+    let spill_queue_memory_limit = env_parse_or(
+        "SPILL_QUEUE_MEMORY_LIMIT",
+        default_spill_queue_memory_limit(),
+    );
+
+    let db_max_retries = env_parse_or("DB_MAX_RETRIES", default_db_max_retries());
+    let db_retry_backoff_ms = env_parse_or("DB_RETRY_BACKOFF_MS", default_db_retry_backoff_ms());
+    let db_retry_max_backoff_ms =
+        env_parse_or("DB_RETRY_MAX_BACKOFF_MS", default_db_retry_max_backoff_ms());
+    let db_stall_timeout_secs =
+        env_parse_or("DB_STALL_TIMEOUT_SECS", default_db_stall_timeout_secs());
+    let quarantine_path = env::var("QUARANTINE_PATH").ok();
+    let postgres_batch_size = env_parse_or("POSTGRES_BATCH_SIZE", default_postgres_batch_size());
+
+    let persistence_mode = env_parse_or("PERSISTENCE_MODE", PersistenceMode::default());
+
+    let partition_size_slots = env_parse_or("PARTITION_SIZE_SLOTS", default_partition_size_slots());
+    let partitions_ahead = env_parse_or("PARTITIONS_AHEAD", default_partitions_ahead());
+    let retention_slots = env::var("RETENTION_SLOTS")
+        .ok()
+        .map(|v| v.parse().expect("RETENTION_SLOTS has a wrong value"));
+    let retention_hours = env::var("RETENTION_HOURS")
+        .ok()
+        .map(|v| v.parse().expect("RETENTION_HOURS has a wrong value"));
+    let retention_action = env_parse_or("RETENTION_ACTION", RetentionAction::default());
+    let maintenance_interval_secs = env_parse_or(
+        "MAINTENANCE_INTERVAL_SECS",
+        default_maintenance_interval_secs(),
+    );
+
+    let account_coalesce_window_ms = env_parse_or("ACCOUNT_COALESCE_WINDOW_MS", 0);
+    let account_coalesce_keep_history = env_parse_or(
+        "ACCOUNT_COALESCE_KEEP_HISTORY",
+        default_account_coalesce_keep_history(),
+    );
+    let account_notify_channel = env::var("ACCOUNT_NOTIFY_CHANNEL").ok();
+
+    let slot_atomic = env_parse_or("SLOT_ATOMIC", false);
+    let slot_atomic_grace_ms = env_parse_or("SLOT_ATOMIC_GRACE_MS", default_slot_atomic_grace_ms());
+    let slot_atomic_max_pending_slots = env_parse_or(
+        "SLOT_ATOMIC_MAX_PENDING_SLOTS",
+        default_slot_atomic_max_pending_slots(),
+    );
+    let slot_atomic_max_pending_rows = env_parse_or(
+        "SLOT_ATOMIC_MAX_PENDING_ROWS",
+        default_slot_atomic_max_pending_rows(),
+    );
+
+    let rpc_port = env::var("RPC_PORT")
+        .ok()
+        .map(|v| v.parse().expect("RPC_PORT has a wrong value"));
+    let rpc_max_body_size = env_parse_or("RPC_MAX_BODY_SIZE", default_rpc_max_body_size());
+    let rpc_max_program_accounts = env_parse_or(
+        "RPC_MAX_PROGRAM_ACCOUNTS",
+        default_rpc_max_program_accounts(),
+    );
+    let pubsub_port = env::var("PUBSUB_PORT")
+        .ok()
+        .map(|v| v.parse().expect("PUBSUB_PORT has a wrong value"));
+    let pubsub_connection_buffer = env_parse_or(
+        "PUBSUB_CONNECTION_BUFFER",
+        default_pubsub_connection_buffer(),
+    );
+    let grpc_port = env::var("GRPC_PORT")
+        .ok()
+        .map(|v| v.parse().expect("GRPC_PORT has a wrong value"));
+    let grpc_stream_buffer = env_parse_or("GRPC_STREAM_BUFFER", default_grpc_stream_buffer());
+    let ready_max_queue_depth =
+        env_parse_or("READY_MAX_QUEUE_DEPTH", default_ready_max_queue_depth());
+    let ready_max_kafka_lag = env_parse_or("READY_MAX_KAFKA_LAG", default_ready_max_kafka_lag());
+    let filter_hot_pubkeys_capacity = env_parse_or(
+        "FILTER_HOT_PUBKEYS_CAPACITY",
+        default_filter_hot_pubkeys_capacity(),
+    );
+    let tracing_exporter = env_parse_or("TRACING_EXPORTER", TracingExporter::default());
+    let otlp_endpoint = env_parse_or("OTLP_ENDPOINT", default_otlp_endpoint());
+    let tracing_sample_ratio = env_parse_or("TRACING_SAMPLE_RATIO", default_tracing_sample_ratio());
+
+    let exactly_once = env_parse_or("EXACTLY_ONCE", false);
+    let exactly_once_batch_size =
+        env_parse_or("EXACTLY_ONCE_BATCH_SIZE", default_exactly_once_batch_size());
+    let exactly_once_batch_ms =
+        env_parse_or("EXACTLY_ONCE_BATCH_MS", default_exactly_once_batch_ms());
+
     FilterConfig {
         filter_log_path,
         bootstrap_servers,
@@ -137,6 +656,47 @@ pub fn env_build_config() -> FilterConfig {
         prometheus_port,
         kafka_log_level,
         global_log_level,
+        sinks,
+        spill_queue_path,
+        spill_queue_segment_size,
+        spill_queue_max_size,
+        spill_queue_memory_limit,
+        db_max_retries,
+        db_retry_backoff_ms,
+        db_retry_max_backoff_ms,
+        db_stall_timeout_secs,
+        quarantine_path,
+        postgres_batch_size,
+        persistence_mode,
+        account_coalesce_window_ms,
+        account_coalesce_keep_history,
+        account_notify_channel,
+        slot_atomic,
+        slot_atomic_grace_ms,
+        slot_atomic_max_pending_slots,
+        slot_atomic_max_pending_rows,
+        rpc_port,
+        rpc_max_body_size,
+        rpc_max_program_accounts,
+        pubsub_port,
+        pubsub_connection_buffer,
+        grpc_port,
+        grpc_stream_buffer,
+        ready_max_queue_depth,
+        ready_max_kafka_lag,
+        filter_hot_pubkeys_capacity,
+        tracing_exporter,
+        otlp_endpoint,
+        tracing_sample_ratio,
+        exactly_once,
+        exactly_once_batch_size,
+        exactly_once_batch_ms,
+        partition_size_slots,
+        partitions_ahead,
+        retention_slots,
+        retention_hours,
+        retention_action,
+        maintenance_interval_secs,
     }
 }
 
@@ -163,4 +723,125 @@ pub struct FilterConfig {
     pub prometheus_port: String,
     pub kafka_log_level: LogLevel,
     pub global_log_level: GlobalLogLevel,
+    // Outputs the filtered rows are written to in parallel, each of them has its own queues
+    #[serde(default = "default_sinks")]
+    pub sinks: Vec<SinkConfig>,
+    // Directory of the on-disk queue used when rows pile up in memory, disabled if not set
+    pub spill_queue_path: Option<String>,
+    // Size of a single segment file in bytes
+    #[serde(default = "default_spill_queue_segment_size")]
+    pub spill_queue_segment_size: u64,
+    // Total size of all segment files per table in bytes
+    #[serde(default = "default_spill_queue_max_size")]
+    pub spill_queue_max_size: u64,
+    // How many rows per table are kept in memory before spilling to disk
+    #[serde(default = "default_spill_queue_memory_limit")]
+    pub spill_queue_memory_limit: usize,
+    // How many times a failed insert is retried before the row is quarantined
+    #[serde(default = "default_db_max_retries")]
+    pub db_max_retries: u32,
+    // Backoff before the first retry, doubled on every next attempt
+    #[serde(default = "default_db_retry_backoff_ms")]
+    pub db_retry_backoff_ms: u64,
+    #[serde(default = "default_db_retry_max_backoff_ms")]
+    pub db_retry_max_backoff_ms: u64,
+    // How long rows failing with a missing partition or a misconfigured destination are retried before they are quarantined
+    #[serde(default = "default_db_stall_timeout_secs")]
+    pub db_stall_timeout_secs: u64,
+    // JSON lines file for rows that could not be inserted, such rows are only logged if not set
+    pub quarantine_path: Option<String>,
+    // Rows per write of the Postgres sink, the accounts of a write share one statement and one notification
+    #[serde(default = "default_postgres_batch_size")]
+    pub postgres_batch_size: usize,
+    // Which of the account and account_audit tables are written
+    #[serde(default)]
+    pub persistence_mode: PersistenceMode,
+    // Only the latest version of each account seen during this window is written to the account table, 0 disables coalescing
+    #[serde(default)]
+    pub account_coalesce_window_ms: u64,
+    // Write the superseded versions to account_audit, ignored in the LatestState mode
+    #[serde(default = "default_account_coalesce_keep_history")]
+    pub account_coalesce_keep_history: bool,
+    // Postgres channel notified with the pubkey, slot and write_version of the changed accounts, disabled if not set
+    pub account_notify_channel: Option<String>,
+    // Write all rows of a slot to Postgres in one transaction once its block has arrived
+    #[serde(default)]
+    pub slot_atomic: bool,
+    // How long the rows of a slot are still collected after its block
+    #[serde(default = "default_slot_atomic_grace_ms")]
+    pub slot_atomic_grace_ms: u64,
+    // Slots this far behind the newest one are written without waiting for their blocks
+    #[serde(default = "default_slot_atomic_max_pending_slots")]
+    pub slot_atomic_max_pending_slots: u64,
+    // The oldest slots are written early once this many rows are pending, new rows wait meanwhile
+    #[serde(default = "default_slot_atomic_max_pending_rows")]
+    pub slot_atomic_max_pending_rows: usize,
+    // Port of the JSON-RPC server answering account, slot and block reads from Postgres, disabled if not set
+    pub rpc_port: Option<u16>,
+    // Requests with a larger body are rejected with 413
+    #[serde(default = "default_rpc_max_body_size")]
+    pub rpc_max_body_size: usize,
+    // getProgramAccounts requests matching more accounts than this fail
+    #[serde(default = "default_rpc_max_program_accounts")]
+    pub rpc_max_program_accounts: usize,
+    // Port of the WebSocket server notifying account, program and slot subscriptions straight from the filters, disabled if not set
+    pub pubsub_port: Option<u16>,
+    // Notifications queued per WebSocket connection, connections falling further behind are closed
+    #[serde(default = "default_pubsub_connection_buffer")]
+    pub pubsub_connection_buffer: usize,
+    // Port of the gRPC server streaming the filtered accounts, slots and blocks, disabled if not set
+    pub grpc_port: Option<u16>,
+    // Updates a gRPC client may fall behind the stream before it is disconnected
+    #[serde(default = "default_grpc_stream_buffer")]
+    pub grpc_stream_buffer: usize,
+    // /readyz fails while a sink has more rows queued than this
+    #[serde(default = "default_ready_max_queue_depth")]
+    pub ready_max_queue_depth: usize,
+    // /readyz fails while the consumer lag of a topic is above this many messages
+    #[serde(default = "default_ready_max_kafka_lag")]
+    pub ready_max_kafka_lag: u64,
+    // How many matched pubkeys are counted for /filter/hot-pubkeys, 0 disables the counting
+    #[serde(default = "default_filter_hot_pubkeys_capacity")]
+    pub filter_hot_pubkeys_capacity: usize,
+    // Where the spans of the pipeline go, from the Kafka consumers to the sink writes
+    #[serde(default)]
+    pub tracing_exporter: TracingExporter,
+    #[serde(default = "default_otlp_endpoint")]
+    pub otlp_endpoint: String,
+    // The share of the messages traced, their sink writes are traced with them
+    #[serde(default = "default_tracing_sample_ratio")]
+    pub tracing_sample_ratio: f64,
+    // Write the rows together with the Kafka offsets they were read at in one Postgres transaction
+    // and resume from the stored offsets on startup, only the Postgres sink is supported
+    #[serde(default)]
+    pub exactly_once: bool,
+    // The maximum number of messages of a topic written in one transaction
+    #[serde(default = "default_exactly_once_batch_size")]
+    pub exactly_once_batch_size: usize,
+    // How long a batch is collected after its first message
+    #[serde(default = "default_exactly_once_batch_ms")]
+    pub exactly_once_batch_ms: u64,
+    // Slots per account_audit and slot partition
+    #[serde(default = "default_partition_size_slots")]
+    pub partition_size_slots: u64,
+    // How many partitions are created in advance after the one holding the latest slot
+    #[serde(default = "default_partitions_ahead")]
+    pub partitions_ahead: u64,
+    // Partitions entirely older than this many slots behind the latest one are removed
+    pub retention_slots: Option<u64>,
+    // Partitions entirely older than this many hours are removed
+    pub retention_hours: Option<u64>,
+    #[serde(default)]
+    pub retention_action: RetentionAction,
+    #[serde(default = "default_maintenance_interval_secs")]
+    pub maintenance_interval_secs: u64,
+}
+
+impl FilterConfig {
+    // The Kafka sink produces the account updates as they were consumed
+    pub fn republishes_accounts(&self) -> bool {
+        self.sinks.iter().any(
+            |sink| matches!(sink, SinkConfig::Kafka(kafka) if kafka.update_account_topic.is_some()),
+        )
+    }
 }
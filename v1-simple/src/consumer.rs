@@ -1,22 +1,33 @@
-use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc,
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
+use ahash::AHashMap;
+use chrono::Utc;
 use flume::Sender;
 use kafka_common::message_type::{GetMessageType, MessageType};
-use log::{error, info};
+use log::{error, info, warn};
+use opentelemetry::{
+    trace::{SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
 use prometheus_client::metrics::counter::Counter;
 use rdkafka::{
     consumer::{Consumer, StreamConsumer},
     message::BorrowedMessage,
-    ClientConfig, Message,
+    ClientConfig, Message, Offset, TopicPartitionList,
 };
 use serde::Deserialize;
 
 use crate::{
     config::FilterConfig,
     consumer_stats::{ContextWithStats, Stats},
+    telemetry::tracer,
 };
 
 pub fn extract_from_message<'a>(message: &'a BorrowedMessage<'a>) -> Option<&'a str> {
@@ -31,6 +42,116 @@ pub fn extract_from_message<'a>(message: &'a BorrowedMessage<'a>) -> Option<&'a
     payload
 }
 
+// How often the offsets of the messages whose rows are durable are handed over for the auto commit
+const OFFSET_STORE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct PartitionOffsets {
+    // Received messages whose rows are not durable yet
+    pending: BTreeSet<i64>,
+    // The offset following the last received message
+    next: i64,
+    // The last offset handed over for the commit
+    stored: i64,
+}
+
+// The offsets of a topic that may be committed, a message is held until its rows are durable:
+// written or quarantined by every sink, spilled to disk, or filtered out
+#[derive(Default)]
+pub struct OffsetTracker {
+    partitions: Mutex<AHashMap<i32, PartitionOffsets>>,
+}
+
+impl OffsetTracker {
+    fn lock(&self) -> std::sync::MutexGuard<'_, AHashMap<i32, PartitionOffsets>> {
+        self.partitions
+            .lock()
+            .expect("Offset tracker lock is poisoned")
+    }
+
+    pub fn received(self: &Arc<Self>, partition: i32, offset: i64) -> Arc<MessageAck> {
+        let mut partitions = self.lock();
+        let offsets = partitions.entry(partition).or_default();
+        offsets.pending.insert(offset);
+        offsets.next = offsets.next.max(offset + 1);
+
+        Arc::new(MessageAck {
+            tracker: self.clone(),
+            partition,
+            offset,
+        })
+    }
+
+    // The offsets that moved forward since the last call, every one is the next offset to read
+    pub fn take_committable(&self) -> Vec<(i32, i64)> {
+        let mut committable = Vec::new();
+        for (partition, offsets) in self.lock().iter_mut() {
+            let next = offsets.pending.first().copied().unwrap_or(offsets.next);
+            // A partition read again after a rebalance doesn't move the offset back
+            if next > offsets.stored {
+                offsets.stored = next;
+                committable.push((*partition, next));
+            }
+        }
+        committable
+    }
+}
+
+// A received message, its offset may be committed once every copy is dropped.
+// The copies travel with the rows of the message through the filters, the coalescer and the sink queues.
+// Dropping them on shutdown commits nothing, the offsets are only handed over by the consumer loop.
+pub struct MessageAck {
+    tracker: Arc<OffsetTracker>,
+    partition: i32,
+    offset: i64,
+}
+
+impl Drop for MessageAck {
+    fn drop(&mut self) {
+        if let Some(offsets) = self.tracker.lock().get_mut(&self.partition) {
+            offsets.pending.remove(&self.offset);
+        }
+    }
+}
+
+// A deserialized message and the moment it was appended to Kafka, on the local monotonic clock.
+// The context holds the span of the message, the spans of its rows are its children.
+pub struct Consumed<T> {
+    pub event: T,
+    pub appended: Option<Instant>,
+    pub context: Context,
+    // The payload of the account updates, kept only when a sink republishes them
+    pub source: Option<Arc<str>>,
+    pub ack: Arc<MessageAck>,
+}
+
+// None for the messages without a timestamp or with a timestamp in the future
+fn appended_at(message: &BorrowedMessage) -> Option<Instant> {
+    let age = Utc::now().timestamp_millis() - message.timestamp().to_millis()?;
+    Instant::now().checked_sub(Duration::from_millis(u64::try_from(age).ok()?))
+}
+
+fn store_offsets(
+    consumer: &StreamConsumer<ContextWithStats>,
+    topic: &str,
+    tracker: &OffsetTracker,
+) {
+    let mut offsets = TopicPartitionList::new();
+    for (partition, next_offset) in tracker.take_committable() {
+        if let Err(e) = offsets.add_partition_offset(topic, partition, Offset::Offset(next_offset))
+        {
+            warn!("Wrong offset for {topic}, error: {e}");
+        }
+    }
+
+    // A partition that was revoked meanwhile is committed by its new owner
+    if offsets.count() > 0 {
+        if let Err(e) = consumer.store_offsets(&offsets) {
+            warn!("Failed to store the offsets of {topic}, error: {e}");
+        }
+    }
+}
+
 pub fn get_counter(stats: &Arc<Stats>, message_type: MessageType) -> &Counter<u64, AtomicU64> {
     match message_type {
         MessageType::UpdateAccount => &stats.kafka_update_account,
@@ -43,7 +164,7 @@ pub fn get_counter(stats: &Arc<Stats>, message_type: MessageType) -> &Counter<u6
 pub async fn consumer<T>(
     config: Arc<FilterConfig>,
     topic: String,
-    filter_tx: Sender<T>,
+    filter_tx: Sender<Consumed<T>>,
     ctx_stats: ContextWithStats,
 ) where
     T: for<'a> Deserialize<'a> + std::marker::Send + 'static + GetMessageType,
@@ -57,24 +178,53 @@ pub async fn consumer<T>(
         .set("session.timeout.ms", &config.session_timeout_ms)
         .set("fetch.message.max.bytes", &config.fetch_message_max_bytes)
         .set("enable.auto.commit", "true")
+        // Only the offsets of the messages whose rows are durable are committed
+        .set("enable.auto.offset.store", "false")
         .set("security.protocol", &config.security_protocol)
         .set("sasl.mechanism", &config.sasl_mechanism)
         .set("sasl.username", &config.sasl_username)
         .set("sasl.password", &config.sasl_password)
         .set("statistics.interval.ms", &config.statistics_interval_ms)
         .set_log_level((&config.kafka_log_level).into())
-        .create_with_context(ctx_stats)
+        .create_with_context(ctx_stats.clone())
         .expect("Consumer creation failed");
 
     consumer.subscribe(&[&topic]).unwrap_or_else(|e| {
         panic!("Couldn't subscribe to specified topic with {type_name}, error: {e}")
     });
 
+    ctx_stats.health.add_topic(&topic);
+
     info!("The consumer loop for {type_name} is about to start!");
 
+    let keep_source = config.republishes_accounts();
+    let tracer = tracer();
+    let offsets = Arc::new(OffsetTracker::default());
+    let mut store_interval = tokio::time::interval(OFFSET_STORE_INTERVAL);
     loop {
-        match consumer.recv().await {
+        let result = tokio::select! {
+            result = consumer.recv() => result,
+            _ = store_interval.tick() => {
+                store_offsets(&consumer, &topic, &offsets);
+                continue;
+            }
+        };
+
+        match result {
             Ok(message) => {
+                let ack = offsets.received(message.partition(), message.offset());
+                let appended = appended_at(&message);
+                let span = tracer
+                    .span_builder(format!("{topic} receive"))
+                    .with_kind(SpanKind::Consumer)
+                    .with_attributes(vec![
+                        KeyValue::new("messaging.system", "kafka"),
+                        KeyValue::new("messaging.destination", topic.clone()),
+                        KeyValue::new("messaging.kafka.partition", message.partition() as i64),
+                        KeyValue::new("messaging.kafka.message.offset", message.offset()),
+                    ])
+                    .start_with_context(&tracer, &Context::new());
+                let context = Context::new().with_span(span);
                 if let Some(payload) = extract_from_message(&message) {
                     stats
                         .kafka_bytes_rx
@@ -82,6 +232,15 @@ pub async fn consumer<T>(
                         .fetch_add(payload.len() as u64, Ordering::Relaxed);
 
                     let result: serde_json::Result<T> = serde_json::from_str(payload);
+                    let source = match &result {
+                        Ok(event)
+                            if keep_source
+                                && matches!(event.get_type(), MessageType::UpdateAccount) =>
+                        {
+                            Some(Arc::from(payload))
+                        }
+                        _ => None,
+                    };
                     let filter_tx = filter_tx.clone();
                     let stats = stats.clone();
 
@@ -89,16 +248,26 @@ pub async fn consumer<T>(
                         match result {
                             Ok(event) => {
                                 let received = get_counter(&stats, event.get_type());
-                                if let Err(e) = filter_tx.send_async(event).await {
+                                let consumed = Consumed {
+                                    event,
+                                    appended,
+                                    context: context.clone(),
+                                    source,
+                                    ack,
+                                };
+                                if let Err(e) = filter_tx.send_async(consumed).await {
                                     error!("Failed to send the data {type_name}, error {e}");
+                                    context.span().set_status(Status::error(e.to_string()));
                                 }
                                 received.inc();
                             }
                             Err(e) => {
                                 error!("Failed to deserialize {type_name} {e}");
                                 stats.kafka_errors_deserialize.inc();
+                                context.span().set_status(Status::error(e.to_string()));
                             }
                         }
+                        context.span().end();
                     });
                 }
             }
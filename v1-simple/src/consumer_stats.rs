@@ -1,8 +1,21 @@
-use std::sync::{atomic::AtomicU64, Arc};
+use std::{
+    sync::{atomic::AtomicU64, Arc},
+    time::Duration,
+};
 
 use log::info;
-use prometheus_client::metrics::counter::Counter;
-use rdkafka::{consumer::ConsumerContext, ClientContext, Statistics};
+use prometheus_client::metrics::{
+    counter::Counter,
+    family::Family,
+    gauge::Gauge,
+    histogram::{exponential_buckets, Histogram},
+};
+use rdkafka::{
+    consumer::{ConsumerContext, Rebalance},
+    ClientContext, Statistics,
+};
+
+use crate::{config::FilterConfig, filter_metrics::FilterMetrics, health::Health};
 
 #[derive(Default)]
 pub struct Stats {
@@ -13,21 +26,196 @@ pub struct Stats {
     pub kafka_errors_consumer: Counter<u64, AtomicU64>,
     pub kafka_errors_deserialize: Counter<u64, AtomicU64>,
     pub kafka_bytes_rx: Counter<u64, AtomicU64>,
+    pub db_partitions_created: Counter<u64, AtomicU64>,
+    pub db_partitions_removed: Counter<u64, AtomicU64>,
+    pub db_maintenance_errors: Counter<u64, AtomicU64>,
+    pub db_retention_cutoff_slot: Gauge<u64, AtomicU64>,
+    pub queue_length: Family<Labels, Gauge<u64, AtomicU64>>,
+    pub queue_pushed: Family<Labels, Counter<u64, AtomicU64>>,
+    pub queue_popped: Family<Labels, Counter<u64, AtomicU64>>,
+    pub queue_requeued: Family<Labels, Counter<u64, AtomicU64>>,
+    pub sink_rows_written: Family<Labels, Counter<u64, AtomicU64>>,
+    pub sink_rows_failed: Family<Labels, Counter<u64, AtomicU64>>,
+    pub latency: LatencyStats,
+    pub slot_seen: Family<Labels, Gauge<u64, AtomicU64>>,
+    pub slot_written: Family<Labels, Gauge<u64, AtomicU64>>,
+    pub slot_lag: Family<Labels, Gauge<u64, AtomicU64>>,
+    pub slot_rooted_gaps: Counter<u64, AtomicU64>,
+    pub filter_evaluated: Counter<u64, AtomicU64>,
+    pub filter_rule_matched: Family<Labels, Counter<u64, AtomicU64>>,
+}
+
+// Seconds spent in every stage of the pipeline
+pub struct LatencyStats {
+    pub kafka_to_filter: Family<Labels, Histogram>,
+    pub filter_to_queue: Family<Labels, Histogram>,
+    pub queue_wait: Family<Labels, Histogram>,
+    pub db_execute: Family<Labels, Histogram>,
+    pub validator_to_commit: Family<Labels, Histogram>,
+}
+
+// From 1ms to about 65s
+fn latency_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(0.001, 2.0, 17))
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        Self {
+            kafka_to_filter: Family::new_with_constructor(latency_histogram),
+            filter_to_queue: Family::new_with_constructor(latency_histogram),
+            queue_wait: Family::new_with_constructor(latency_histogram),
+            db_execute: Family::new_with_constructor(latency_histogram),
+            validator_to_commit: Family::new_with_constructor(latency_histogram),
+        }
+    }
+}
+
+// The histograms of the filter of one message type
+#[derive(Clone)]
+pub struct FilterLatency {
+    pub kafka_to_filter: Histogram,
+    pub filter_to_queue: Histogram,
 }
 
-pub trait GetCounters {
-    fn get_counters(&self) -> (&AtomicU64, &AtomicU64);
+impl FilterLatency {
+    pub fn new(stats: &Stats, message_type: &str) -> Self {
+        let labels = labels(&[("type", message_type)]);
+        Self {
+            kafka_to_filter: stats.latency.kafka_to_filter.get_or_create(&labels).clone(),
+            filter_to_queue: stats.latency.filter_to_queue.get_or_create(&labels).clone(),
+        }
+    }
 }
 
-#[derive(Default, Clone)]
+pub type Labels = Vec<(String, String)>;
+
+// The order of the slot_lag gauges of a table
+pub const SLOT_STATUSES: [&str; 3] = ["processed", "confirmed", "rooted"];
+
+pub fn labels(labels: &[(&str, &str)]) -> Labels {
+    labels
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+// The metrics of one table of a sink, resolved once so that updating them doesn't look up the families
+#[derive(Clone)]
+pub struct TableMetrics {
+    pub pushed: Counter<u64, AtomicU64>,
+    pub popped: Counter<u64, AtomicU64>,
+    pub requeued: Counter<u64, AtomicU64>,
+    pub written: Counter<u64, AtomicU64>,
+    pub failed: Counter<u64, AtomicU64>,
+    pub queue_wait: Histogram,
+    pub db_execute: Histogram,
+    pub validator_to_commit: Histogram,
+    pub slot_written: Gauge<u64, AtomicU64>,
+    // How far the table is behind the highest processed, confirmed and rooted slot
+    pub slot_lag: [Gauge<u64, AtomicU64>; 3],
+    // Rows held outside of the in-memory queue, on disk in the spill queue or waiting for a retry.
+    // They are not exported on their own, they count towards queue_length.
+    pub spilled: Gauge<u64, AtomicU64>,
+    pub retrying: Gauge<u64, AtomicU64>,
+}
+
+impl TableMetrics {
+    pub fn new(stats: &Stats, sink: &str, table: &str) -> Self {
+        let labels = labels(&[("sink", sink), ("table", table)]);
+        Self {
+            pushed: stats.queue_pushed.get_or_create(&labels).clone(),
+            popped: stats.queue_popped.get_or_create(&labels).clone(),
+            requeued: stats.queue_requeued.get_or_create(&labels).clone(),
+            written: stats.sink_rows_written.get_or_create(&labels).clone(),
+            failed: stats.sink_rows_failed.get_or_create(&labels).clone(),
+            queue_wait: stats.latency.queue_wait.get_or_create(&labels).clone(),
+            db_execute: stats.latency.db_execute.get_or_create(&labels).clone(),
+            validator_to_commit: stats
+                .latency
+                .validator_to_commit
+                .get_or_create(&labels)
+                .clone(),
+            slot_written: stats.slot_written.get_or_create(&labels).clone(),
+            slot_lag: SLOT_STATUSES.map(|status| {
+                stats
+                    .slot_lag
+                    .get_or_create(&self::labels(&[
+                        ("sink", sink),
+                        ("table", table),
+                        ("status", status),
+                    ]))
+                    .clone()
+            }),
+            spilled: Gauge::default(),
+            retrying: Gauge::default(),
+        }
+    }
+
+    pub fn off_queue(&self) -> usize {
+        (self.spilled.get() + self.retrying.get()) as usize
+    }
+}
+
+// A queue or channel whose length is exported as queue_length
+pub struct QueueGauge {
+    labels: Labels,
+    length: Box<dyn Fn() -> usize + Send + Sync>,
+}
+
+impl QueueGauge {
+    pub fn new(
+        queue_labels: &[(&str, &str)],
+        length: impl Fn() -> usize + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            labels: labels(queue_labels),
+            length: Box::new(length),
+        }
+    }
+}
+
+// The lengths are sampled, the queues themselves are not slowed down by the metrics
+pub async fn sample_queue_lengths(stats: Arc<Stats>, gauges: Vec<QueueGauge>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        for gauge in &gauges {
+            stats
+                .queue_length
+                .get_or_create(&gauge.labels)
+                .set((gauge.length)() as u64);
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct ContextWithStats {
     pub stats: Arc<Stats>,
+    pub health: Arc<Health>,
+    pub filter_metrics: Arc<FilterMetrics>,
+}
+
+impl ContextWithStats {
+    pub fn new(config: &FilterConfig, health: Arc<Health>) -> Self {
+        let stats = Arc::<Stats>::default();
+        Self {
+            filter_metrics: Arc::new(FilterMetrics::new(config, &stats)),
+            stats,
+            health,
+        }
+    }
 }
 
 impl ClientContext for ContextWithStats {
     fn stats(&self, stats: Statistics) {
+        self.health.statistics(&stats);
         info!("{:?}", stats);
     }
 }
 
-impl ConsumerContext for ContextWithStats {}
+impl ConsumerContext for ContextWithStats {
+    fn post_rebalance<'a>(&self, rebalance: &Rebalance<'a>) {
+        self.health.rebalance(rebalance);
+    }
+}
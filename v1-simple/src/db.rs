@@ -4,14 +4,17 @@ use std::time::Duration;
 
 use anyhow::anyhow;
 use anyhow::Result;
-use crossbeam_queue::SegQueue;
+use chrono::NaiveDateTime;
+use kafka_common::kafka_structs::KafkaReplicaAccountInfoVersions;
 use kafka_common::kafka_structs::KafkaReplicaBlockInfo;
+use kafka_common::kafka_structs::KafkaReplicaBlockInfoVersions;
+use kafka_common::kafka_structs::NotifyBlockMetaData;
 use kafka_common::kafka_structs::UpdateAccount;
-use kafka_common::kafka_structs::UpdateSlotStatus;
 use log::error;
 use log::info;
-use log::warn;
 use postgres_types::FromSql;
+use serde::Deserialize;
+use serde::Serialize;
 use solana_runtime::bank::RewardType;
 use solana_transaction_status::Reward;
 use tokio_postgres::types::ToSql;
@@ -19,15 +22,8 @@ use tokio_postgres::Client;
 use tokio_postgres::NoTls;
 
 use crate::config::FilterConfig;
-use crate::db_inserts::insert_into_account_audit;
-use crate::db_inserts::insert_into_block_metadata;
-use crate::db_inserts::insert_slot_status_internal;
-use crate::db_statements::create_account_insert_statement;
-use crate::db_statements::create_block_metadata_insert_statement;
-use crate::db_statements::create_slot_insert_statement_with_parent;
-use crate::db_statements::create_slot_insert_statement_without_parent;
 
-#[derive(Clone, Eq, PartialEq, Debug)]
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
 pub struct DbAccountInfo {
     pub pubkey: Vec<u8>,
     pub lamports: i64,
@@ -38,18 +34,23 @@ pub struct DbAccountInfo {
     pub slot: i64,
     pub write_version: i64,
     pub txn_signature: Option<Vec<u8>>,
+    pub is_startup: bool,
+    pub retrieved_time: NaiveDateTime,
+    // The Kafka message the row was made of, only kept in the queues of the sinks that republish it
+    pub source: Option<Arc<str>>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DbBlockInfo {
     pub slot: i64,
     pub blockhash: String,
     pub rewards: Vec<DbReward>,
     pub block_time: Option<i64>,
     pub block_height: Option<i64>,
+    pub retrieved_time: NaiveDateTime,
 }
 
-#[derive(Clone, Debug, FromSql, ToSql, Eq, PartialEq)]
+#[derive(Clone, Debug, FromSql, ToSql, Eq, PartialEq, Serialize, Deserialize)]
 #[postgres(name = "RewardType")]
 pub enum DbRewardType {
     Fee,
@@ -58,7 +59,7 @@ pub enum DbRewardType {
     Voting,
 }
 
-#[derive(Clone, Debug, FromSql, ToSql)]
+#[derive(Clone, Debug, FromSql, ToSql, Serialize, Deserialize)]
 #[postgres(name = "Reward")]
 pub struct DbReward {
     pub pubkey: String,
@@ -75,18 +76,14 @@ impl fmt::Display for DbAccountInfo {
 }
 
 fn range_check(lamports: u64, rent_epoch: u64, write_version: u64) -> Result<()> {
-    if lamports > std::i64::MAX as u64 {
-        return Err(anyhow!("account_info.lamports greater than std::i64::MAX!"));
+    if lamports > i64::MAX as u64 {
+        return Err(anyhow!("account_info.lamports greater than i64::MAX!"));
     }
-    if rent_epoch > std::i64::MAX as u64 {
-        return Err(anyhow!(
-            "account_info.rent_epoch greater than std::i64::MAX!"
-        ));
+    if rent_epoch > i64::MAX as u64 {
+        return Err(anyhow!("account_info.rent_epoch greater than i64::MAX!"));
     }
-    if write_version > std::i64::MAX as u64 {
-        return Err(anyhow!(
-            "account_info.write_version greater than std::i64::MAX!"
-        ));
+    if write_version > i64::MAX as u64 {
+        return Err(anyhow!("account_info.write_version greater than i64::MAX!"));
     }
     Ok(())
 }
@@ -96,7 +93,7 @@ impl TryFrom<&UpdateAccount> for DbAccountInfo {
 
     fn try_from(update_account: &UpdateAccount) -> Result<Self> {
         match &update_account.account {
-            kafka_common::kafka_structs::KafkaReplicaAccountInfoVersions::V0_0_1(account_info) => {
+            KafkaReplicaAccountInfoVersions::V0_0_1(account_info) => {
                 range_check(
                     account_info.lamports,
                     account_info.rent_epoch,
@@ -113,9 +110,12 @@ impl TryFrom<&UpdateAccount> for DbAccountInfo {
                     slot: update_account.slot as i64,
                     write_version: account_info.write_version as i64,
                     txn_signature: None,
+                    is_startup: update_account.is_startup,
+                    retrieved_time: update_account.retrieved_time,
+                    source: None,
                 })
             }
-            kafka_common::kafka_structs::KafkaReplicaAccountInfoVersions::V0_0_2(account_info) => {
+            KafkaReplicaAccountInfoVersions::V0_0_2(account_info) => {
                 range_check(
                     account_info.lamports,
                     account_info.rent_epoch,
@@ -132,6 +132,9 @@ impl TryFrom<&UpdateAccount> for DbAccountInfo {
                     slot: update_account.slot as i64,
                     write_version: account_info.write_version as i64,
                     txn_signature: account_info.txn_signature.map(|v| v.as_ref().to_vec()),
+                    is_startup: update_account.is_startup,
+                    retrieved_time: update_account.retrieved_time,
+                    source: None,
                 })
             }
         }
@@ -161,16 +164,57 @@ impl From<&Reward> for DbReward {
     }
 }
 
-impl From<KafkaReplicaBlockInfo> for DbBlockInfo {
-    fn from(block_info: KafkaReplicaBlockInfo) -> Self {
-        Self {
-            slot: block_info.slot as i64,
-            blockhash: block_info.blockhash.to_string(),
-            rewards: block_info.rewards.iter().map(DbReward::from).collect(),
-            block_time: block_info.block_time,
-            block_height: block_info
-                .block_height
-                .map(|block_height| block_height as i64),
+impl From<NotifyBlockMetaData> for DbBlockInfo {
+    fn from(notify_block: NotifyBlockMetaData) -> Self {
+        match notify_block.block_info {
+            KafkaReplicaBlockInfoVersions::V0_0_1(block_info) => Self {
+                slot: block_info.slot as i64,
+                blockhash: block_info.blockhash.to_string(),
+                rewards: block_info.rewards.iter().map(DbReward::from).collect(),
+                block_time: block_info.block_time,
+                block_height: block_info
+                    .block_height
+                    .map(|block_height| block_height as i64),
+                retrieved_time: notify_block.retrieved_time,
+            },
+        }
+    }
+}
+
+impl From<DbRewardType> for RewardType {
+    fn from(reward_type: DbRewardType) -> Self {
+        match reward_type {
+            DbRewardType::Fee => Self::Fee,
+            DbRewardType::Rent => Self::Rent,
+            DbRewardType::Staking => Self::Staking,
+            DbRewardType::Voting => Self::Voting,
+        }
+    }
+}
+
+impl From<&DbReward> for Reward {
+    fn from(reward: &DbReward) -> Self {
+        Reward {
+            pubkey: reward.pubkey.clone(),
+            lamports: reward.lamports,
+            post_balance: reward.post_balance as u64,
+            reward_type: reward.reward_type.clone().map(|v| v.into()),
+            commission: reward.commission.map(|v| v as u8),
+        }
+    }
+}
+
+impl From<&DbBlockInfo> for NotifyBlockMetaData {
+    fn from(block: &DbBlockInfo) -> Self {
+        NotifyBlockMetaData {
+            block_info: KafkaReplicaBlockInfoVersions::V0_0_1(KafkaReplicaBlockInfo {
+                slot: block.slot as u64,
+                blockhash: block.blockhash.clone(),
+                rewards: block.rewards.iter().map(Reward::from).collect(),
+                block_time: block.block_time,
+                block_height: block.block_height.map(|v| v as u64),
+            }),
+            retrieved_time: block.retrieved_time,
         }
     }
 }
@@ -194,7 +238,7 @@ pub async fn initialize_db_client(config: Arc<FilterConfig>) -> Arc<Client> {
     client
 }
 
-async fn connect_to_db(config: Arc<FilterConfig>) -> Result<Arc<Client>> {
+pub async fn connect_to_db(config: Arc<FilterConfig>) -> Result<Arc<Client>> {
     let (client, connection) =
         tokio_postgres::connect(&config.postgres_connection_str, NoTls).await?;
 
@@ -206,99 +250,3 @@ async fn connect_to_db(config: Arc<FilterConfig>) -> Result<Arc<Client>> {
 
     Ok(Arc::new(client))
 }
-
-async fn account_stmt_executor(client: Arc<Client>, account_queue: Arc<SegQueue<DbAccountInfo>>) {
-    if let Some(db_account_info) = account_queue.pop() {
-        tokio::spawn(async move {
-            let statement = match create_account_insert_statement(client.clone()).await {
-                Ok(s) => s,
-                Err(e) => {
-                    error!("Failed to execute create_account_insert_statement, error {e}");
-                    account_queue.push(db_account_info);
-                    return;
-                }
-            };
-
-            if let Err(error) =
-                insert_into_account_audit(&db_account_info, &statement, client).await
-            {
-                error!("Failed to insert the data to account_audit, error: {error}");
-                // Push account_info back to the database queue
-                account_queue.push(db_account_info);
-            }
-        });
-    }
-}
-
-async fn block_stmt_executor(client: Arc<Client>, block_queue: Arc<SegQueue<DbBlockInfo>>) {
-    if let Some(db_block_info) = block_queue.pop() {
-        tokio::spawn(async move {
-            let statement = match create_block_metadata_insert_statement(client.clone()).await {
-                Ok(s) => s,
-                Err(e) => {
-                    error!("Failed to prepare create_block_metadata_insert_statement, error {e}");
-                    block_queue.push(db_block_info);
-                    return;
-                }
-            };
-
-            if let Err(error) = insert_into_block_metadata(&db_block_info, &statement, client).await
-            {
-                error!("Failed to insert the data to block_metadata, error: {error}");
-                // Push block_info back to the database queue
-                block_queue.push(db_block_info);
-            }
-        });
-    }
-}
-
-async fn slot_stmt_executor(client: Arc<Client>, slot_queue: Arc<SegQueue<UpdateSlotStatus>>) {
-    if let Some(db_slot_info) = slot_queue.pop() {
-        tokio::spawn(async move {
-            let statement = match db_slot_info.parent {
-                Some(_) => create_slot_insert_statement_with_parent(client.clone()).await,
-                None => create_slot_insert_statement_without_parent(client.clone()).await,
-            };
-
-            match statement {
-                Ok(statement) => {
-                    if let Err(e) =
-                        insert_slot_status_internal(&db_slot_info, &statement, client).await
-                    {
-                        error!("Failed to execute insert_slot_status_internal, error {e}");
-                        slot_queue.push(db_slot_info);
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to prepare create_slot_insert_statement, error {e}");
-                    slot_queue.push(db_slot_info);
-                }
-            }
-        });
-    }
-}
-
-pub async fn db_stmt_executor(
-    config: Arc<FilterConfig>,
-    mut client: Arc<Client>,
-    account_queue: Arc<SegQueue<DbAccountInfo>>,
-    block_queue: Arc<SegQueue<DbBlockInfo>>,
-    slot_queue: Arc<SegQueue<UpdateSlotStatus>>,
-) {
-    let mut idle_interval = tokio::time::interval(Duration::from_millis(500));
-
-    loop {
-        if client.is_closed() {
-            warn!("Postgres client was unexpectedly closed");
-            client = initialize_db_client(config.clone()).await;
-        }
-
-        if account_queue.is_empty() && block_queue.is_empty() && slot_queue.is_empty() {
-            idle_interval.tick().await;
-        }
-
-        account_stmt_executor(client.clone(), account_queue.clone()).await;
-        block_stmt_executor(client.clone(), block_queue.clone()).await;
-        slot_stmt_executor(client.clone(), slot_queue.clone()).await;
-    }
-}
@@ -3,18 +3,19 @@ use anyhow::Result;
 use chrono::Utc;
 use kafka_common::kafka_structs::UpdateSlotStatus;
 use std::sync::Arc;
-use tokio_postgres::{Client, Statement};
+use tokio_postgres::{types::ToSql, Client, Row, Statement};
 
 use crate::db::DbAccountInfo;
 use crate::db::DbBlockInfo;
 
-pub async fn insert_into_account_audit(
+// Returns the number of account rows written, 0 if the upsert kept a newer version
+pub async fn insert_account_info(
     account: &DbAccountInfo,
     statement: &Statement,
     client: Arc<Client>,
-) -> Result<()> {
+) -> Result<u64> {
     let updated_on = Utc::now().naive_utc();
-    if let Err(error) = client
+    client
         .execute(
             statement,
             &[
@@ -31,13 +32,45 @@ pub async fn insert_into_account_audit(
             ],
         )
         .await
-    {
-        return Err(anyhow!(
-            "DbAccountInfo statement execution failed, error {error}"
-        ));
-    }
+        .map_err(|error| anyhow!(error).context("DbAccountInfo statement execution failed"))
+}
 
-    Ok(())
+// Runs a batch statement of db_statements for the accounts, returns the rows it returned
+pub async fn insert_account_batch(
+    accounts: &[DbAccountInfo],
+    statement: &Statement,
+    client: Arc<Client>,
+) -> Result<Vec<Row>> {
+    let updated_on = vec![Utc::now().naive_utc(); accounts.len()];
+    let pubkeys: Vec<&[u8]> = accounts.iter().map(|a| a.pubkey.as_slice()).collect();
+    let slots: Vec<i64> = accounts.iter().map(|a| a.slot).collect();
+    let owners: Vec<&[u8]> = accounts.iter().map(|a| a.owner.as_slice()).collect();
+    let lamports: Vec<i64> = accounts.iter().map(|a| a.lamports).collect();
+    let executable: Vec<bool> = accounts.iter().map(|a| a.executable).collect();
+    let rent_epochs: Vec<i64> = accounts.iter().map(|a| a.rent_epoch).collect();
+    let data: Vec<&[u8]> = accounts.iter().map(|a| a.data.as_slice()).collect();
+    let write_versions: Vec<i64> = accounts.iter().map(|a| a.write_version).collect();
+    let signatures: Vec<Option<&[u8]>> = accounts
+        .iter()
+        .map(|a| a.txn_signature.as_deref())
+        .collect();
+
+    let params: [&(dyn ToSql + Sync); 10] = [
+        &pubkeys,
+        &slots,
+        &owners,
+        &lamports,
+        &executable,
+        &rent_epochs,
+        &data,
+        &write_versions,
+        &updated_on,
+        &signatures,
+    ];
+    client
+        .query(statement, &params)
+        .await
+        .map_err(|error| anyhow!(error).context("DbAccountInfo batch statement execution failed"))
 }
 
 pub async fn insert_into_block_metadata(
@@ -61,9 +94,7 @@ pub async fn insert_into_block_metadata(
         )
         .await
     {
-        return Err(anyhow!(
-            "DbBlockInfo statement execution failed, error {error}"
-        ));
+        return Err(anyhow!(error).context("DbBlockInfo statement execution failed"));
     }
 
     Ok(())
@@ -102,9 +133,7 @@ pub async fn insert_slot_status_internal(
     };
 
     if let Err(error) = result {
-        return Err(anyhow!(
-            "UpdateSlotStatus statement execution failed, error {error}"
-        ));
+        return Err(anyhow!(error).context("UpdateSlotStatus statement execution failed"));
     }
 
     Ok(())
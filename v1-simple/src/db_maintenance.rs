@@ -0,0 +1,378 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use ahash::AHashSet;
+use anyhow::Result;
+use crossbeam_queue::SegQueue;
+use log::error;
+use log::info;
+use log::warn;
+use tokio_postgres::Client;
+
+use crate::config::FilterConfig;
+use crate::config::RetentionAction;
+use crate::consumer_stats::Stats;
+use crate::db::initialize_db_client;
+use crate::db_retry::missing_partition_slot;
+
+// How often the chain tip is compared with the created partitions
+const MAINTENANCE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Tables partitioned by slot range
+const PARTITIONED_TABLES: [&str; 2] = ["account_audit", "slot"];
+
+// Slots of the rows rejected for a lack of a partition, the maintenance task creates their partitions,
+// i.e. for the rows of a lagging topic or of old data replayed into a fresh schema
+#[derive(Default)]
+pub struct MissingPartitions {
+    slots: SegQueue<i64>,
+}
+
+impl MissingPartitions {
+    pub fn report(&self, error: &anyhow::Error) {
+        if let Some(slot) = missing_partition_slot(error) {
+            self.slots.push(slot);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    // The first slot of every missing partition
+    fn take(&self, size: i64) -> Vec<i64> {
+        let mut starts = AHashSet::new();
+        while let Some(slot) = self.slots.pop() {
+            starts.insert(slot.div_euclid(size) * size);
+        }
+        starts.into_iter().collect()
+    }
+}
+
+#[derive(Debug)]
+struct Partition {
+    name: String,
+    from: i64,
+    to: i64,
+}
+
+// Parses the bound of a range partition on a single column, i.e. "FOR VALUES FROM ('0') TO ('432000')"
+fn parse_partition_bound(bound: &str) -> Option<(i64, i64)> {
+    let parse = |value: &str| -> Option<i64> {
+        let value = value
+            .trim()
+            .trim_matches(|c| c == '(' || c == ')' || c == '\'');
+        match value {
+            "MINVALUE" => Some(i64::MIN),
+            "MAXVALUE" => Some(i64::MAX),
+            value => value.parse().ok(),
+        }
+    };
+
+    let bound = bound.strip_prefix("FOR VALUES FROM ")?;
+    let (from, to) = bound.split_once(" TO ")?;
+    Some((parse(from)?, parse(to)?))
+}
+
+async fn is_partitioned(client: &Client, table: &str) -> Result<bool> {
+    let row = client
+        .query_one(
+            "SELECT EXISTS (SELECT 1 FROM pg_partitioned_table WHERE partrelid = to_regclass($1))",
+            &[&table],
+        )
+        .await?;
+    Ok(row.get(0))
+}
+
+async fn list_partitions(client: &Client, table: &str) -> Result<Vec<Partition>> {
+    let rows = client
+        .query(
+            "SELECT c.relname::TEXT, pg_get_expr(c.relpartbound, c.oid) \
+            FROM pg_inherits i JOIN pg_class c ON c.oid = i.inhrelid \
+            WHERE i.inhparent = to_regclass($1)",
+            &[&table],
+        )
+        .await?;
+
+    let mut partitions: Vec<Partition> = rows
+        .iter()
+        .filter_map(|row| {
+            let name: String = row.get(0);
+            let bound: String = row.get(1);
+            // The default partition has no range
+            parse_partition_bound(&bound).map(|(from, to)| Partition { name, from, to })
+        })
+        .collect();
+
+    partitions.sort_by_key(|p| p.from);
+    Ok(partitions)
+}
+
+// Parts of [from, to) that are not covered by the existing partitions
+fn uncovered_ranges(partitions: &[Partition], from: i64, to: i64) -> Vec<(i64, i64)> {
+    let mut ranges = Vec::new();
+    let mut cursor = from;
+
+    for partition in partitions {
+        if partition.to <= cursor || partition.from >= to {
+            continue;
+        }
+        if partition.from > cursor {
+            ranges.push((cursor, partition.from));
+        }
+        cursor = cursor.max(partition.to);
+    }
+
+    if cursor < to {
+        ranges.push((cursor, to));
+    }
+    ranges
+}
+
+// The highest slot older than the retention window
+async fn retention_cutoff(client: &Client, config: &FilterConfig, tip: i64) -> Result<Option<i64>> {
+    let by_slots = config
+        .retention_slots
+        .map(|slots| tip.saturating_sub(slots as i64));
+
+    let by_time = match config.retention_hours {
+        Some(hours) => {
+            let row = client
+                .query_one(
+                    "SELECT max(slot) FROM slot WHERE updated_on < now() - make_interval(hours => $1)",
+                    &[&(hours as i32)],
+                )
+                .await?;
+            row.get::<_, Option<i64>>(0)
+        }
+        None => None,
+    };
+
+    // A partition is removed only when it is outside of both windows
+    Ok(match (by_slots, by_time) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    })
+}
+
+async fn maintain_table(
+    client: &Client,
+    config: &FilterConfig,
+    stats: &Stats,
+    table: &str,
+    tip: i64,
+    missing: &[i64],
+) -> Result<()> {
+    let mut partitions = list_partitions(client, table).await?;
+    let size = config.partition_size_slots as i64;
+    let cutoff = retention_cutoff(client, config, tip).await?;
+
+    let mut wanted = Vec::new();
+    if tip > 0 {
+        let from = tip / size * size;
+        wanted.push((from, from + size * (config.partitions_ahead as i64 + 1)));
+    }
+    for from in missing {
+        let to = from + size;
+        match cutoff {
+            // It would be removed right away
+            Some(cutoff) if to <= cutoff => warn!(
+                "No partition of {table} is created for slots [{from}, {to}), they are older than the retention cutoff {cutoff}"
+            ),
+            _ => wanted.push((*from, to)),
+        }
+    }
+
+    for (from, to) in wanted {
+        for (from, to) in uncovered_ranges(&partitions, from, to) {
+            let name = format!("{table}_p{from}");
+            client
+                .batch_execute(&format!(
+                    "CREATE TABLE IF NOT EXISTS {name} PARTITION OF {table} FOR VALUES FROM ({from}) TO ({to})"
+                ))
+                .await?;
+
+            info!("Created partition {name} for slots [{from}, {to})");
+            stats.db_partitions_created.inc();
+            partitions.push(Partition { name, from, to });
+        }
+        partitions.sort_by_key(|p| p.from);
+    }
+
+    let cutoff = match cutoff {
+        Some(cutoff) => cutoff,
+        None => return Ok(()),
+    };
+    stats.db_retention_cutoff_slot.set(cutoff.max(0) as u64);
+
+    for partition in partitions.iter().filter(|p| p.to <= cutoff) {
+        let name = &partition.name;
+        let statement = match config.retention_action {
+            RetentionAction::Drop => format!("DROP TABLE {name}"),
+            RetentionAction::Detach => format!("ALTER TABLE {table} DETACH PARTITION {name}"),
+        };
+
+        client.batch_execute(&statement).await?;
+
+        info!(
+            "{:?} partition {name} with slots [{}, {}), retention cutoff is {cutoff}",
+            config.retention_action, partition.from, partition.to
+        );
+        stats.db_partitions_removed.inc();
+    }
+
+    Ok(())
+}
+
+// Creates slot range partitions of account_audit and slot ahead of the chain and for the rows that missed one,
+// and removes the ones that fell out of the retention window
+pub async fn db_maintenance(
+    config: Arc<FilterConfig>,
+    stats: Arc<Stats>,
+    chain_tip: Arc<AtomicU64>,
+    missing_partitions: Arc<MissingPartitions>,
+) {
+    if config.partition_size_slots == 0 {
+        error!("partition_size_slots must be greater than 0, partition maintenance is disabled");
+        return;
+    }
+
+    let mut client = initialize_db_client(config.clone()).await;
+    let mut check = tokio::time::interval(MAINTENANCE_CHECK_INTERVAL);
+    let maintenance_interval = Duration::from_secs(config.maintenance_interval_secs);
+    let size = config.partition_size_slots as i64;
+    let mut last_run: Option<Instant> = None;
+    // The created partitions cover the slots below this one
+    let mut covered_to = 0;
+    let mut not_partitioned = AHashSet::new();
+
+    loop {
+        check.tick().await;
+
+        // Runs on the maintenance interval and as soon as the chain reaches the last partition,
+        // on a fresh schema that is when the first slot is received
+        let received_tip = chain_tip.load(Ordering::Relaxed) as i64;
+        let interval_elapsed = match last_run {
+            Some(at) => at.elapsed() >= maintenance_interval,
+            None => true,
+        };
+        let due = interval_elapsed
+            || (received_tip > 0 && received_tip >= covered_to - size)
+            || !missing_partitions.is_empty();
+        if !due {
+            continue;
+        }
+        last_run = Some(Instant::now());
+
+        if client.is_closed() {
+            warn!("Postgres maintenance client was unexpectedly closed");
+            client = initialize_db_client(config.clone()).await;
+        }
+
+        let mut tip = received_tip;
+        if tip == 0 {
+            // Nothing was received yet, continue from what is in the database
+            match client.query_one("SELECT max(slot) FROM slot", &[]).await {
+                Ok(row) => tip = row.get::<_, Option<i64>>(0).unwrap_or(0),
+                Err(e) => {
+                    error!("Failed to get the latest slot from the database, error: {e}");
+                    stats.db_maintenance_errors.inc();
+                    continue;
+                }
+            }
+        }
+
+        // Rows that are still missing a partition after a failed run report it again on their retry
+        let missing = missing_partitions.take(size);
+        let mut failed = false;
+        for table in PARTITIONED_TABLES {
+            let result = match is_partitioned(&client, table).await {
+                Ok(true) => maintain_table(&client, &config, &stats, table, tip, &missing).await,
+                Ok(false) => {
+                    if not_partitioned.insert(table) {
+                        warn!("Table {table} is not partitioned, see partition_history_tables.sql");
+                    }
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = result {
+                error!("Maintenance of {table} failed, error: {e}");
+                stats.db_maintenance_errors.inc();
+                failed = true;
+            }
+        }
+
+        if !failed && tip > 0 {
+            covered_to = tip / size * size + size * (config.partitions_ahead as i64 + 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partition(from: i64, to: i64) -> Partition {
+        Partition {
+            name: format!("account_audit_{from}"),
+            from,
+            to,
+        }
+    }
+
+    #[test]
+    fn parses_range_partition_bounds() {
+        assert_eq!(
+            parse_partition_bound("FOR VALUES FROM ('0') TO ('432000')"),
+            Some((0, 432000))
+        );
+        assert_eq!(
+            parse_partition_bound("FOR VALUES FROM (MINVALUE) TO ('864000')"),
+            Some((i64::MIN, 864000))
+        );
+        assert_eq!(
+            parse_partition_bound("FOR VALUES FROM ('864000') TO (MAXVALUE)"),
+            Some((864000, i64::MAX))
+        );
+        assert_eq!(parse_partition_bound("DEFAULT"), None);
+        assert_eq!(parse_partition_bound("FOR VALUES IN ('1', '2')"), None);
+    }
+
+    #[test]
+    fn finds_the_ranges_without_a_partition() {
+        let partitions = [partition(0, 100), partition(200, 300)];
+
+        assert_eq!(uncovered_ranges(&partitions, 0, 100), vec![]);
+        assert_eq!(uncovered_ranges(&partitions, 0, 300), vec![(100, 200)]);
+        assert_eq!(
+            uncovered_ranges(&partitions, 50, 400),
+            vec![(100, 200), (300, 400)]
+        );
+        assert_eq!(uncovered_ranges(&partitions, 120, 180), vec![(120, 180)]);
+        assert_eq!(uncovered_ranges(&[], 0, 100), vec![(0, 100)]);
+    }
+
+    #[test]
+    fn overlapping_partitions_cover_their_union() {
+        let partitions = [partition(0, 150), partition(100, 200)];
+        assert_eq!(uncovered_ranges(&partitions, 0, 250), vec![(200, 250)]);
+    }
+
+    #[test]
+    fn reports_the_start_of_every_missing_partition() {
+        let missing = MissingPartitions::default();
+        for slot in [5, 99, 100, 250] {
+            missing.slots.push(slot);
+        }
+
+        let mut starts = missing.take(100);
+        starts.sort();
+        assert_eq!(starts, vec![0, 100, 200]);
+        assert!(missing.is_empty());
+    }
+}
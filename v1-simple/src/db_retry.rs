@@ -0,0 +1,670 @@
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Result;
+use chrono::Utc;
+use crossbeam_queue::SegQueue;
+use log::error;
+use log::warn;
+use opentelemetry::trace::SpanContext;
+use prometheus_client::metrics::gauge::Gauge;
+use serde::Serialize;
+use serde_json::json;
+use tokio_postgres::error::SqlState;
+
+use crate::config::FilterConfig;
+use crate::sink::Queued;
+use crate::sink::RowAck;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorKind {
+    // The connection is gone, the row itself is fine
+    Connection,
+    // The statement may succeed if it is retried later
+    Transient,
+    // The statement will never succeed for this row
+    Permanent,
+    // The slot of the row has no partition yet, it can be inserted once the maintenance task creates it
+    MissingPartition,
+    // The destination is misconfigured, i.e. a missing table or a revoked grant,
+    // every row fails the same way until an operator fixes it
+    Misconfigured,
+}
+
+pub fn classify_postgres_error(error: &anyhow::Error) -> ErrorKind {
+    let pg_error = match error
+        .chain()
+        .find_map(|e| e.downcast_ref::<tokio_postgres::Error>())
+    {
+        Some(e) => e,
+        None => return ErrorKind::Transient,
+    };
+
+    if pg_error.is_closed() {
+        return ErrorKind::Connection;
+    }
+
+    match pg_error.code() {
+        Some(code) => classify_sql_state(code, pg_error.as_db_error().map(|e| e.message())),
+        // Not a database error, i.e. an IO error on the socket
+        None => ErrorKind::Connection,
+    }
+}
+
+fn classify_sql_state(code: &SqlState, message: Option<&str>) -> ErrorKind {
+    // A row without a partition is reported as a check violation
+    if code.code() == "23514"
+        && message.is_some_and(|message| message.starts_with("no partition of relation"))
+    {
+        return ErrorKind::MissingPartition;
+    }
+
+    match &code.code()[..2] {
+        // Connection exception, operator intervention
+        "08" | "57" => ErrorKind::Connection,
+        // Data exception, integrity constraint violation
+        "22" | "23" => ErrorKind::Permanent,
+        // Syntax error or access rule violation
+        "42" => ErrorKind::Misconfigured,
+        _ => ErrorKind::Transient,
+    }
+}
+
+// The slot of a row that was rejected for a lack of a partition
+pub fn missing_partition_slot(error: &anyhow::Error) -> Option<i64> {
+    let db_error = error
+        .chain()
+        .find_map(|e| e.downcast_ref::<tokio_postgres::Error>())?
+        .as_db_error()?;
+    if !db_error.message().starts_with("no partition of relation") {
+        return None;
+    }
+    partition_key_slot(db_error.detail()?)
+}
+
+// i.e. "Partition key of the failing row contains (slot) = (432000)."
+fn partition_key_slot(detail: &str) -> Option<i64> {
+    let (_, value) = detail.rsplit_once("= (")?;
+    value.split_once(')')?.0.parse().ok()
+}
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    // How long a row may keep failing with a missing partition or a misconfiguration
+    pub stall_timeout: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &FilterConfig) -> Self {
+        Self {
+            max_retries: config.db_max_retries,
+            backoff: Duration::from_millis(config.db_retry_backoff_ms),
+            max_backoff: Duration::from_millis(config.db_retry_max_backoff_ms),
+            stall_timeout: Duration::from_secs(config.db_stall_timeout_secs),
+        }
+    }
+
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+
+    // Whether a row that first stalled at `stalled_since` may still be retried
+    pub fn may_stall(&self, stalled_since: Instant) -> bool {
+        stalled_since.elapsed() < self.stall_timeout
+    }
+}
+
+// Rows that failed too many times or can never be written end up in a JSON lines file
+pub struct Quarantine {
+    path: Option<String>,
+    file: Mutex<Option<File>>,
+}
+
+impl Quarantine {
+    pub fn new(path: Option<String>) -> Self {
+        if path.is_none() {
+            warn!("quarantine_path is not set, rows that can't be written are logged and dropped");
+        }
+
+        Self {
+            path,
+            file: Mutex::new(None),
+        }
+    }
+
+    pub fn write<T: Serialize>(
+        &self,
+        sink: &str,
+        table: &str,
+        attempts: u32,
+        error: &str,
+        row: &T,
+    ) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut file = self.file.lock().expect("Quarantine lock is poisoned");
+        if file.is_none() {
+            *file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        }
+
+        let record = json!({
+            "sink": sink,
+            "table": table,
+            "attempts": attempts,
+            "error": error,
+            "quarantined_on": Utc::now().naive_utc(),
+            "row": row,
+        });
+
+        if let Some(file) = file.as_mut() {
+            serde_json::to_writer(&mut *file, &record)?;
+            file.write_all(b"\n")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct RetryState {
+    attempts: u32,
+    // Set once the row was part of a batch that failed permanently, it is retried alone
+    isolated: bool,
+    // The first failure with a missing partition or a misconfiguration
+    stalled_since: Option<Instant>,
+}
+
+struct RetryEntry<T> {
+    item: T,
+    state: RetryState,
+    span: SpanContext,
+    ack: RowAck,
+    not_before: Instant,
+}
+
+// Rows handed to a single write, `states`, `spans` and `acks` are parallel to `items`
+pub struct RetryBatch<T> {
+    pub items: Vec<T>,
+    states: Vec<RetryState>,
+    // The spans the rows were queued with, invalid for the rows that are not traced
+    pub spans: Vec<SpanContext>,
+    // Released once the rows are written or quarantined
+    pub acks: Vec<RowAck>,
+    // How long the rows taken from the queue waited there, empty for a batch of retries
+    pub queue_wait: Vec<Duration>,
+}
+
+// Keeps failed rows of one table of a sink until their backoff expires
+pub struct RetryQueue<T> {
+    sink: String,
+    table: &'static str,
+    policy: RetryPolicy,
+    quarantine: Arc<Quarantine>,
+    pending: Mutex<Vec<RetryEntry<T>>>,
+    // The length of `pending`, readable without the lock
+    retrying: Gauge<u64, AtomicU64>,
+}
+
+impl<T: Serialize> RetryQueue<T> {
+    pub fn new(
+        sink: &str,
+        table: &'static str,
+        policy: RetryPolicy,
+        quarantine: Arc<Quarantine>,
+        retrying: Gauge<u64, AtomicU64>,
+    ) -> Self {
+        Self {
+            sink: sink.to_string(),
+            table,
+            policy,
+            quarantine,
+            pending: Mutex::new(Vec::new()),
+            retrying,
+        }
+    }
+
+    pub fn has_due(&self) -> bool {
+        let now = Instant::now();
+        self.pending
+            .lock()
+            .expect("Retry queue lock is poisoned")
+            .iter()
+            .any(|e| e.not_before <= now)
+    }
+
+    // Rows waiting for a retry take precedence over the new ones.
+    // An isolated row is always returned alone so that it can't fail a batch of valid rows again.
+    pub fn next_batch(
+        &self,
+        queue: &SegQueue<Queued<T>>,
+        max_rows: usize,
+    ) -> Option<RetryBatch<T>> {
+        let now = Instant::now();
+        let max_rows = max_rows.max(1);
+        let mut pending = self.pending.lock().expect("Retry queue lock is poisoned");
+        let mut batch = RetryBatch {
+            items: Vec::new(),
+            states: Vec::new(),
+            spans: Vec::new(),
+            acks: Vec::new(),
+            queue_wait: Vec::new(),
+        };
+
+        let mut index = 0;
+        while index < pending.len() && batch.items.len() < max_rows {
+            let entry = &pending[index];
+            if entry.not_before > now || (entry.state.isolated && !batch.items.is_empty()) {
+                index += 1;
+                continue;
+            }
+
+            let entry = pending.swap_remove(index);
+            self.retrying.dec();
+            let isolated = entry.state.isolated;
+            batch.items.push(entry.item);
+            batch.states.push(entry.state);
+            batch.spans.push(entry.span);
+            batch.acks.push(entry.ack);
+
+            if isolated {
+                return Some(batch);
+            }
+        }
+        drop(pending);
+
+        if batch.items.is_empty() {
+            while batch.items.len() < max_rows {
+                match queue.pop() {
+                    Some(queued) => {
+                        batch.items.push(queued.item);
+                        batch.states.push(RetryState::default());
+                        batch.spans.push(queued.span);
+                        batch.acks.push(queued.ack);
+                        batch.queue_wait.push(queued.queued_at.elapsed());
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        (!batch.items.is_empty()).then_some(batch)
+    }
+
+    fn schedule(&self, entries: Vec<RetryEntry<T>>) {
+        let mut pending = self.pending.lock().expect("Retry queue lock is poisoned");
+        self.retrying.inc_by(entries.len() as u64);
+        pending.extend(entries);
+    }
+
+    fn give_up(&self, item: &T, attempts: u32, kind: ErrorKind, error: &anyhow::Error) {
+        let sink = &self.sink;
+        let table = self.table;
+
+        error!(
+            "Giving up on {sink} {table} row after {attempts} attempt(s), {kind:?} error: {error:#}"
+        );
+        if let Err(e) = self
+            .quarantine
+            .write(sink, table, attempts, &format!("{error:#}"), item)
+        {
+            warn!("Failed to write {sink} {table} row to quarantine, it is dropped, error: {e}");
+        }
+    }
+
+    // Returns how many rows were given up on, the others are retried
+    pub fn failed(
+        &self,
+        batch: RetryBatch<T>,
+        kind: ErrorKind,
+        error: &anyhow::Error,
+        queue: &SegQueue<Queued<T>>,
+    ) -> usize {
+        let now = Instant::now();
+        let batch_len = batch.items.len();
+        let mut retries = Vec::new();
+        let mut given_up = 0;
+
+        let rows = batch
+            .items
+            .into_iter()
+            .zip(batch.states)
+            .zip(batch.spans)
+            .zip(batch.acks);
+        for (((item, mut state), span), ack) in rows {
+            if matches!(kind, ErrorKind::MissingPartition | ErrorKind::Misconfigured) {
+                state.stalled_since.get_or_insert(now);
+            }
+            let may_stall = state
+                .stalled_since
+                .is_none_or(|since| self.policy.may_stall(since));
+
+            match kind {
+                ErrorKind::Connection => {
+                    // Does not count as an attempt, the row waits for the reconnect
+                    if state.attempts == 0 && !state.isolated {
+                        queue.push(Queued {
+                            ack,
+                            ..Queued::new(item, span)
+                        });
+                    } else {
+                        retries.push(RetryEntry {
+                            item,
+                            state,
+                            span,
+                            ack,
+                            not_before: now + self.policy.backoff,
+                        });
+                    }
+                }
+                // Does not count as an attempt, the row waits for the maintenance task
+                ErrorKind::MissingPartition if may_stall => {
+                    retries.push(RetryEntry {
+                        item,
+                        state,
+                        span,
+                        ack,
+                        not_before: now + self.policy.backoff,
+                    });
+                }
+                // Does not count as an attempt either, the row waits for an operator
+                ErrorKind::Misconfigured if may_stall => {
+                    retries.push(RetryEntry {
+                        item,
+                        state,
+                        span,
+                        ack,
+                        not_before: now + self.policy.max_backoff,
+                    });
+                }
+                // Any row of the batch may be the bad one, each of them gets a retry of its own
+                ErrorKind::Permanent if batch_len > 1 => {
+                    state.isolated = true;
+                    retries.push(RetryEntry {
+                        item,
+                        state,
+                        span,
+                        ack,
+                        not_before: now,
+                    });
+                }
+                ErrorKind::Transient if state.attempts < self.policy.max_retries => {
+                    state.attempts += 1;
+                    retries.push(RetryEntry {
+                        item,
+                        state,
+                        span,
+                        ack,
+                        not_before: now + self.policy.backoff(state.attempts),
+                    });
+                }
+                kind => {
+                    self.give_up(&item, state.attempts + 1, kind, error);
+                    ack.done();
+                    given_up += 1;
+                }
+            }
+        }
+
+        if !retries.is_empty() {
+            self.schedule(retries);
+        }
+        given_up
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    fn policy(max_retries: u32, stall_timeout: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+            stall_timeout,
+        }
+    }
+
+    fn retry_queue(policy: RetryPolicy, quarantine: Option<String>) -> RetryQueue<u64> {
+        RetryQueue::new(
+            "postgres",
+            "account",
+            policy,
+            Arc::new(Quarantine::new(quarantine)),
+            Gauge::default(),
+        )
+    }
+
+    fn queued(items: &[u64]) -> SegQueue<Queued<u64>> {
+        let queue = SegQueue::new();
+        for item in items {
+            queue.push(Queued::new(*item, SpanContext::empty_context()));
+        }
+        queue
+    }
+
+    fn sql_state(code: &str) -> SqlState {
+        SqlState::from_code(code)
+    }
+
+    #[test]
+    fn classifies_sql_states() {
+        let missing = Some("no partition of relation \"account_audit\" found for row");
+        assert_eq!(
+            classify_sql_state(&sql_state("23514"), missing),
+            ErrorKind::MissingPartition
+        );
+        assert_eq!(
+            classify_sql_state(
+                &sql_state("23514"),
+                Some("new row violates check constraint")
+            ),
+            ErrorKind::Permanent
+        );
+        assert_eq!(
+            classify_sql_state(&sql_state("23505"), None),
+            ErrorKind::Permanent
+        );
+        assert_eq!(
+            classify_sql_state(&sql_state("22P02"), None),
+            ErrorKind::Permanent
+        );
+        assert_eq!(
+            classify_sql_state(&sql_state("08006"), None),
+            ErrorKind::Connection
+        );
+        assert_eq!(
+            classify_sql_state(&sql_state("57P01"), None),
+            ErrorKind::Connection
+        );
+        assert_eq!(
+            classify_sql_state(&sql_state("42P01"), None),
+            ErrorKind::Misconfigured
+        );
+        assert_eq!(
+            classify_sql_state(&sql_state("40001"), None),
+            ErrorKind::Transient
+        );
+    }
+
+    #[test]
+    fn errors_not_from_postgres_are_transient() {
+        assert_eq!(
+            classify_postgres_error(&anyhow!("timed out")),
+            ErrorKind::Transient
+        );
+        assert_eq!(missing_partition_slot(&anyhow!("timed out")), None);
+    }
+
+    #[test]
+    fn parses_the_slot_of_a_partition_key() {
+        assert_eq!(
+            partition_key_slot("Partition key of the failing row contains (slot) = (432000)."),
+            Some(432000)
+        );
+        assert_eq!(partition_key_slot("Failing row contains (1, 2)."), None);
+    }
+
+    #[test]
+    fn doubles_the_backoff_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            stall_timeout: Duration::ZERO,
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(4), Duration::from_millis(500));
+        assert_eq!(policy.backoff(40), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn takes_batches_from_the_queue() {
+        let retry = retry_queue(policy(3, Duration::ZERO), None);
+        let queue = queued(&[1, 2, 3]);
+
+        let batch = retry.next_batch(&queue, 2).unwrap();
+        assert_eq!(batch.items, vec![1, 2]);
+        assert_eq!(batch.queue_wait.len(), 2);
+        assert_eq!(retry.next_batch(&queue, 2).unwrap().items, vec![3]);
+        assert!(retry.next_batch(&queue, 2).is_none());
+    }
+
+    #[test]
+    fn retries_transient_failures_before_new_rows() {
+        let retry = retry_queue(policy(3, Duration::ZERO), None);
+        let queue = queued(&[1, 2, 3]);
+
+        let batch = retry.next_batch(&queue, 2).unwrap();
+        let given_up = retry.failed(batch, ErrorKind::Transient, &anyhow!("timed out"), &queue);
+        assert_eq!(given_up, 0);
+        assert_eq!(retry.retrying.get(), 2);
+        assert!(retry.has_due());
+
+        let mut items = retry.next_batch(&queue, 10).unwrap().items;
+        items.sort();
+        assert_eq!(items, vec![1, 2]);
+        assert_eq!(retry.retrying.get(), 0);
+    }
+
+    #[test]
+    fn waits_for_the_backoff() {
+        let retry = retry_queue(
+            RetryPolicy {
+                backoff: Duration::from_secs(60),
+                max_backoff: Duration::from_secs(60),
+                ..policy(3, Duration::ZERO)
+            },
+            None,
+        );
+        let queue = queued(&[1, 2]);
+
+        let batch = retry.next_batch(&queue, 1).unwrap();
+        retry.failed(batch, ErrorKind::Transient, &anyhow!("timed out"), &queue);
+        assert!(!retry.has_due());
+        // New rows are written meanwhile
+        assert_eq!(retry.next_batch(&queue, 1).unwrap().items, vec![2]);
+        assert!(retry.next_batch(&queue, 1).is_none());
+    }
+
+    #[test]
+    fn quarantines_a_row_after_the_last_retry() {
+        let path = std::env::temp_dir().join(format!("quarantine-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let retry = retry_queue(
+            policy(2, Duration::ZERO),
+            Some(path.to_string_lossy().into_owned()),
+        );
+        let queue = queued(&[7]);
+        let error = anyhow!("timed out");
+
+        for attempt in 0..2 {
+            let batch = retry.next_batch(&queue, 1).unwrap();
+            assert_eq!(batch.states[0].attempts, attempt);
+            assert_eq!(retry.failed(batch, ErrorKind::Transient, &error, &queue), 0);
+        }
+        let batch = retry.next_batch(&queue, 1).unwrap();
+        assert_eq!(retry.failed(batch, ErrorKind::Transient, &error, &queue), 1);
+        assert!(retry.next_batch(&queue, 1).is_none());
+
+        let quarantined = std::fs::read_to_string(&path).unwrap();
+        let record: serde_json::Value = serde_json::from_str(quarantined.trim()).unwrap();
+        assert_eq!(record["row"], 7);
+        assert_eq!(record["attempts"], 3);
+        assert_eq!(record["table"], "account");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn isolates_the_rows_of_a_batch_that_failed_permanently() {
+        let retry = retry_queue(policy(3, Duration::ZERO), None);
+        let queue = queued(&[1, 2, 3]);
+        let error = anyhow!("invalid input");
+
+        let batch = retry.next_batch(&queue, 3).unwrap();
+        assert_eq!(retry.failed(batch, ErrorKind::Permanent, &error, &queue), 0);
+
+        // Every row is retried alone and given up on if it fails again
+        for _ in 0..3 {
+            let batch = retry.next_batch(&queue, 3).unwrap();
+            assert_eq!(batch.items.len(), 1);
+            assert!(batch.states[0].isolated);
+            assert_eq!(retry.failed(batch, ErrorKind::Permanent, &error, &queue), 1);
+        }
+        assert!(retry.next_batch(&queue, 3).is_none());
+    }
+
+    #[test]
+    fn puts_rows_back_on_a_lost_connection() {
+        let retry = retry_queue(policy(0, Duration::ZERO), None);
+        let queue = queued(&[1, 2]);
+
+        let batch = retry.next_batch(&queue, 2).unwrap();
+        assert_eq!(
+            retry.failed(batch, ErrorKind::Connection, &anyhow!("closed"), &queue),
+            0
+        );
+        assert_eq!(retry.retrying.get(), 0);
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn stalls_on_a_missing_partition_until_the_timeout() {
+        let error = anyhow!("no partition");
+
+        let retry = retry_queue(policy(0, Duration::from_secs(3600)), None);
+        let queue = queued(&[1]);
+        for _ in 0..3 {
+            let batch = retry.next_batch(&queue, 1).unwrap();
+            assert_eq!(
+                retry.failed(batch, ErrorKind::MissingPartition, &error, &queue),
+                0
+            );
+        }
+
+        let retry = retry_queue(policy(3, Duration::ZERO), None);
+        let queue = queued(&[1]);
+        let batch = retry.next_batch(&queue, 1).unwrap();
+        assert_eq!(
+            retry.failed(batch, ErrorKind::Misconfigured, &error, &queue),
+            1
+        );
+    }
+}
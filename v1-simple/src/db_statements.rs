@@ -3,14 +3,110 @@ use anyhow::Result;
 use std::sync::Arc;
 use tokio_postgres::{Client, Statement};
 
-pub async fn create_account_insert_statement(client: Arc<Client>) -> Result<Statement> {
-    let stmt = "INSERT INTO account AS acct (pubkey, slot, owner, lamports, executable, rent_epoch, data, write_version, updated_on, txn_signature) \
+use crate::config::PersistenceMode;
+
+pub const ACCOUNT_UPSERT: &str = "INSERT INTO account AS acct (pubkey, slot, owner, lamports, executable, rent_epoch, data, write_version, updated_on, txn_signature) \
     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) \
     ON CONFLICT (pubkey) DO UPDATE SET slot=excluded.slot, owner=excluded.owner, lamports=excluded.lamports, executable=excluded.executable, rent_epoch=excluded.rent_epoch, \
     data=excluded.data, write_version=excluded.write_version, updated_on=excluded.updated_on, txn_signature=excluded.txn_signature  WHERE acct.slot < excluded.slot OR (\
     acct.slot = excluded.slot AND acct.write_version < excluded.write_version)";
 
-    let stmt = client.prepare(stmt).await;
+pub const ACCOUNT_HISTORY_INSERT: &str = "INSERT INTO account_audit (pubkey, slot, owner, lamports, executable, rent_epoch, data, write_version, updated_on, txn_signature) \
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)";
+
+pub const BLOCK_INSERT: &str =
+    "INSERT INTO block (slot, blockhash, rewards, block_time, block_height, updated_on) \
+    VALUES ($1, $2, $3, $4, $5, $6) \
+    ON CONFLICT DO NOTHING";
+
+pub const SLOT_UPSERT_WITH_PARENT: &str = "INSERT INTO slot (slot, parent, status, updated_on) \
+    VALUES ($1, $2, $3, $4) \
+    ON CONFLICT (slot) DO UPDATE SET parent=excluded.parent, status=excluded.status, updated_on=excluded.updated_on";
+
+pub const SLOT_UPSERT_WITHOUT_PARENT: &str = "INSERT INTO slot (slot, status, updated_on) \
+    VALUES ($1, $2, $3) \
+    ON CONFLICT (slot) DO UPDATE SET status=excluded.status, updated_on=excluded.updated_on";
+
+pub const KAFKA_OFFSETS_CREATE: &str = "CREATE TABLE IF NOT EXISTS kafka_offsets (\
+    consumer_group VARCHAR NOT NULL, topic VARCHAR NOT NULL, partition INT NOT NULL, next_offset BIGINT NOT NULL, updated_on TIMESTAMP NOT NULL, \
+    PRIMARY KEY (consumer_group, topic, partition))";
+
+pub const KAFKA_OFFSETS_SELECT: &str =
+    "SELECT partition, next_offset FROM kafka_offsets WHERE consumer_group = $1 AND topic = $2";
+
+// Moves the offset of a partition forward only from the offset the batch continues from,
+// so two instances can't both commit a batch of the same messages
+pub const KAFKA_OFFSETS_ADVANCE: &str = "INSERT INTO kafka_offsets (consumer_group, topic, partition, next_offset, updated_on) \
+    VALUES ($1, $2, $3, $5, $6) \
+    ON CONFLICT (consumer_group, topic, partition) DO UPDATE SET next_offset=excluded.next_offset, updated_on=excluded.updated_on \
+    WHERE kafka_offsets.next_offset = $4";
+
+pub const ACCOUNT_NOTIFY: &str = "SELECT pg_notify($1, $2)";
+
+const ACCOUNT_COLUMNS: &str =
+    "pubkey, slot, owner, lamports, executable, rent_epoch, data, write_version, updated_on, txn_signature";
+
+// The rows of a batch passed as one array per column, in the order of ACCOUNT_COLUMNS
+const ACCOUNT_BATCH: &str = "unnest($1::BYTEA[], $2::BIGINT[], $3::BYTEA[], $4::BIGINT[], $5::BOOL[], $6::BIGINT[], $7::BYTEA[], $8::BIGINT[], $9::TIMESTAMP[], $10::BYTEA[]) \
+    AS batch (pubkey, slot, owner, lamports, executable, rent_epoch, data, write_version, updated_on, txn_signature)";
+
+fn account_history_batch_insert() -> String {
+    format!("INSERT INTO account_audit ({ACCOUNT_COLUMNS}) SELECT {ACCOUNT_COLUMNS} FROM {ACCOUNT_BATCH}")
+}
+
+// Only the latest version of a pubkey in the batch is upserted, an upsert can't change a row twice
+fn account_batch_upsert() -> String {
+    format!(
+        "INSERT INTO account AS acct ({ACCOUNT_COLUMNS}) \
+        SELECT DISTINCT ON (pubkey) {ACCOUNT_COLUMNS} FROM {ACCOUNT_BATCH} ORDER BY pubkey, slot DESC, write_version DESC \
+        ON CONFLICT (pubkey) DO UPDATE SET slot=excluded.slot, owner=excluded.owner, lamports=excluded.lamports, executable=excluded.executable, rent_epoch=excluded.rent_epoch, \
+        data=excluded.data, write_version=excluded.write_version, updated_on=excluded.updated_on, txn_signature=excluded.txn_signature WHERE acct.slot < excluded.slot OR (\
+        acct.slot = excluded.slot AND acct.write_version < excluded.write_version)"
+    )
+}
+
+// Writes all accounts of a batch in a single statement, so a failed batch leaves nothing behind for its retry to write twice.
+// Returns the pubkey, slot and write_version of every version it stored.
+pub async fn create_account_batch_statement(
+    client: Arc<Client>,
+    mode: PersistenceMode,
+) -> Result<Statement> {
+    let returning = "RETURNING pubkey, slot, write_version";
+    let stmt = match mode {
+        PersistenceMode::LatestState => format!("{} {returning}", account_batch_upsert()),
+        PersistenceMode::History => format!("{} {returning}", account_history_batch_insert()),
+        PersistenceMode::Both => format!(
+            "WITH history AS ({}) {} {returning}",
+            account_history_batch_insert(),
+            account_batch_upsert()
+        ),
+    };
+
+    client.prepare(&stmt).await.map_err(|err| anyhow!(err))
+}
+
+pub async fn create_account_history_batch_statement(client: Arc<Client>) -> Result<Statement> {
+    client
+        .prepare(&account_history_batch_insert())
+        .await
+        .map_err(|err| anyhow!(err))
+}
+
+// All account statements take the same parameters, so they are interchangeable for insert_account_info
+pub async fn create_account_insert_statement(
+    client: Arc<Client>,
+    mode: PersistenceMode,
+) -> Result<Statement> {
+    let stmt = match mode {
+        PersistenceMode::LatestState => ACCOUNT_UPSERT.to_string(),
+        PersistenceMode::History => ACCOUNT_HISTORY_INSERT.to_string(),
+        // A single statement, so both tables are written or none of them
+        PersistenceMode::Both => {
+            format!("WITH history AS ({ACCOUNT_HISTORY_INSERT}) {ACCOUNT_UPSERT}")
+        }
+    };
+
+    let stmt = client.prepare(&stmt).await;
 
     match stmt {
         Ok(update_account_stmt) => Ok(update_account_stmt),
@@ -18,13 +114,17 @@ pub async fn create_account_insert_statement(client: Arc<Client>) -> Result<Stat
     }
 }
 
-pub async fn create_block_metadata_insert_statement(client: Arc<Client>) -> Result<Statement> {
-    let stmt =
-        "INSERT INTO block (slot, blockhash, rewards, block_time, block_height, updated_on) \
-    VALUES ($1, $2, $3, $4, $5, $6) \
-    ON CONFLICT DO NOTHING";
+pub async fn create_account_history_insert_statement(client: Arc<Client>) -> Result<Statement> {
+    let stmt = client.prepare(ACCOUNT_HISTORY_INSERT).await;
 
-    let stmt = client.prepare(stmt).await;
+    match stmt {
+        Ok(account_history_stmt) => Ok(account_history_stmt),
+        Err(err) => Err(anyhow!(err)),
+    }
+}
+
+pub async fn create_block_metadata_insert_statement(client: Arc<Client>) -> Result<Statement> {
+    let stmt = client.prepare(BLOCK_INSERT).await;
 
     match stmt {
         Ok(notify_block_metadata_stmt) => Ok(notify_block_metadata_stmt),
@@ -33,11 +133,7 @@ pub async fn create_block_metadata_insert_statement(client: Arc<Client>) -> Resu
 }
 
 pub async fn create_slot_insert_statement_with_parent(client: Arc<Client>) -> Result<Statement> {
-    let stmt = "INSERT INTO slot (slot, parent, status, updated_on) \
-    VALUES ($1, $2, $3, $4) \
-    ON CONFLICT (slot) DO UPDATE SET parent=excluded.parent, status=excluded.status, updated_on=excluded.updated_on";
-
-    let stmt = client.prepare(stmt).await;
+    let stmt = client.prepare(SLOT_UPSERT_WITH_PARENT).await;
 
     match stmt {
         Ok(notify_block_metadata_stmt) => Ok(notify_block_metadata_stmt),
@@ -46,11 +142,7 @@ pub async fn create_slot_insert_statement_with_parent(client: Arc<Client>) -> Re
 }
 
 pub async fn create_slot_insert_statement_without_parent(client: Arc<Client>) -> Result<Statement> {
-    let stmt = "INSERT INTO slot (slot, status, updated_on) \
-    VALUES ($1, $2, $3) \
-    ON CONFLICT (slot) DO UPDATE SET status=excluded.status, updated_on=excluded.updated_on";
-
-    let stmt = client.prepare(stmt).await;
+    let stmt = client.prepare(SLOT_UPSERT_WITHOUT_PARENT).await;
 
     match stmt {
         Ok(notify_block_metadata_stmt) => Ok(notify_block_metadata_stmt),
@@ -0,0 +1,796 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use ahash::AHashMap;
+use ahash::AHashSet;
+use anyhow::anyhow;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::Utc;
+use kafka_common::kafka_structs::NotifyBlockMetaData;
+use kafka_common::kafka_structs::UpdateAccount;
+use kafka_common::kafka_structs::UpdateSlotStatus;
+use kafka_common::message_type::GetMessageType;
+use log::error;
+use log::info;
+use log::warn;
+use rdkafka::consumer::CommitMode;
+use rdkafka::consumer::Consumer;
+use rdkafka::consumer::ConsumerContext;
+use rdkafka::consumer::Rebalance;
+use rdkafka::consumer::StreamConsumer;
+use rdkafka::ClientConfig;
+use rdkafka::ClientContext;
+use rdkafka::Message;
+use rdkafka::Offset;
+use rdkafka::Statistics;
+use rdkafka::TopicPartitionList;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::time::Instant;
+use tokio_postgres::Client;
+
+use crate::config::FilterConfig;
+use crate::config::SinkConfig;
+use crate::consumer::extract_from_message;
+use crate::consumer::get_counter;
+use crate::consumer_stats::ContextWithStats;
+use crate::db::initialize_db_client;
+use crate::db::DbAccountInfo;
+use crate::db::DbBlockInfo;
+use crate::db_inserts::insert_account_info;
+use crate::db_inserts::insert_into_block_metadata;
+use crate::db_inserts::insert_slot_status_internal;
+use crate::db_maintenance::MissingPartitions;
+use crate::db_retry::classify_postgres_error;
+use crate::db_retry::ErrorKind;
+use crate::db_retry::Quarantine;
+use crate::db_retry::RetryPolicy;
+use crate::db_statements::create_account_insert_statement;
+use crate::db_statements::create_block_metadata_insert_statement;
+use crate::db_statements::create_slot_insert_statement_with_parent;
+use crate::db_statements::create_slot_insert_statement_without_parent;
+use crate::db_statements::KAFKA_OFFSETS_ADVANCE;
+use crate::db_statements::KAFKA_OFFSETS_CREATE;
+use crate::db_statements::KAFKA_OFFSETS_SELECT;
+use crate::filter::filter_account;
+use crate::filter_metrics::FilterMetrics;
+use crate::sqlite_sink::SQLITE_SCHEME;
+
+// How the messages of a topic are turned into rows and written inside the offset transaction
+#[async_trait]
+trait TopicWriter: Send + Sync + 'static {
+    type Event: DeserializeOwned + GetMessageType + Send;
+    type Row: Serialize + Send + Sync;
+
+    const TABLE: &'static str;
+
+    fn filter(&self, event: Self::Event) -> Result<Option<Self::Row>>;
+
+    async fn write(&self, client: Arc<Client>, rows: &[Self::Row]) -> Result<()>;
+}
+
+struct AccountWriter {
+    config: Arc<FilterConfig>,
+    filter_metrics: Arc<FilterMetrics>,
+}
+
+#[async_trait]
+impl TopicWriter for AccountWriter {
+    type Event = UpdateAccount;
+    type Row = DbAccountInfo;
+
+    const TABLE: &'static str = "account";
+
+    fn filter(&self, event: UpdateAccount) -> Result<Option<DbAccountInfo>> {
+        filter_account(&self.config, &self.filter_metrics, &event)
+    }
+
+    async fn write(&self, client: Arc<Client>, accounts: &[DbAccountInfo]) -> Result<()> {
+        let statement =
+            create_account_insert_statement(client.clone(), self.config.persistence_mode).await?;
+
+        for account in accounts {
+            insert_account_info(account, &statement, client.clone()).await?;
+        }
+        Ok(())
+    }
+}
+
+struct BlockWriter;
+
+#[async_trait]
+impl TopicWriter for BlockWriter {
+    type Event = NotifyBlockMetaData;
+    type Row = DbBlockInfo;
+
+    const TABLE: &'static str = "block";
+
+    fn filter(&self, event: NotifyBlockMetaData) -> Result<Option<DbBlockInfo>> {
+        Ok(Some(event.into()))
+    }
+
+    async fn write(&self, client: Arc<Client>, blocks: &[DbBlockInfo]) -> Result<()> {
+        let statement = create_block_metadata_insert_statement(client.clone()).await?;
+
+        for block in blocks {
+            insert_into_block_metadata(block, &statement, client.clone()).await?;
+        }
+        Ok(())
+    }
+}
+
+struct SlotWriter {
+    chain_tip: Arc<AtomicU64>,
+}
+
+#[async_trait]
+impl TopicWriter for SlotWriter {
+    type Event = UpdateSlotStatus;
+    type Row = UpdateSlotStatus;
+
+    const TABLE: &'static str = "slot";
+
+    fn filter(&self, event: UpdateSlotStatus) -> Result<Option<UpdateSlotStatus>> {
+        self.chain_tip.fetch_max(event.slot, Ordering::Relaxed);
+        Ok(Some(event))
+    }
+
+    async fn write(&self, client: Arc<Client>, slots: &[UpdateSlotStatus]) -> Result<()> {
+        for slot in slots {
+            let statement = match slot.parent {
+                Some(_) => create_slot_insert_statement_with_parent(client.clone()).await?,
+                None => create_slot_insert_statement_without_parent(client.clone()).await?,
+            };
+            insert_slot_status_internal(slot, &statement, client.clone()).await?;
+        }
+        Ok(())
+    }
+}
+
+// Panics if the configuration can't give the exactly once guarantee
+pub fn check_exactly_once_config(config: &FilterConfig) {
+    if !matches!(config.sinks.as_slice(), [SinkConfig::Postgres])
+        || config.postgres_connection_str.starts_with(SQLITE_SCHEME)
+    {
+        panic!("exactly_once requires Postgres to be the only sink");
+    }
+
+    if config.spill_queue_path.is_some()
+        || config.account_coalesce_window_ms > 0
+        || config.account_notify_channel.is_some()
+        || config.slot_atomic
+        || config.pubsub_port.is_some()
+        || config.grpc_port.is_some()
+    {
+        warn!("spill_queue_path, account_coalesce_window_ms, account_notify_channel, slot_atomic, pubsub_port and grpc_port are ignored with exactly_once");
+    }
+}
+
+async fn load_offsets(client: &Client, group: &str, topic: &str) -> Result<AHashMap<i32, i64>> {
+    client.batch_execute(KAFKA_OFFSETS_CREATE).await?;
+
+    let rows = client
+        .query(KAFKA_OFFSETS_SELECT, &[&group, &topic])
+        .await?;
+    Ok(rows
+        .iter()
+        .map(|row| (row.get("partition"), row.get("next_offset")))
+        .collect())
+}
+
+// Remembers the partitions the group assigns, their offsets are loaded from Postgres before they are read
+struct ExactlyOnceContext {
+    inner: ContextWithStats,
+    assigned: Mutex<Vec<i32>>,
+}
+
+impl ExactlyOnceContext {
+    fn take_assigned(&self) -> Vec<i32> {
+        std::mem::take(
+            &mut *self
+                .assigned
+                .lock()
+                .expect("Assigned partitions lock is poisoned"),
+        )
+    }
+}
+
+impl ClientContext for ExactlyOnceContext {
+    fn stats(&self, stats: Statistics) {
+        self.inner.stats(stats);
+    }
+}
+
+impl ConsumerContext for ExactlyOnceContext {
+    fn post_rebalance<'a>(&self, rebalance: &Rebalance<'a>) {
+        self.inner.post_rebalance(rebalance);
+        if let Rebalance::Assign(assignment) = rebalance {
+            self.assigned
+                .lock()
+                .expect("Assigned partitions lock is poisoned")
+                .extend(assignment.elements().iter().map(|e| e.partition()));
+        }
+    }
+}
+
+// The partitions of a batch whose stored offset moved since they were assigned,
+// another instance got them meanwhile and the batch must not be committed for them
+#[derive(Debug)]
+struct OffsetConflict {
+    partitions: Vec<i32>,
+}
+
+impl fmt::Display for OffsetConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The stored offsets of partitions {:?} were moved by another instance",
+            self.partitions
+        )
+    }
+}
+
+impl std::error::Error for OffsetConflict {}
+
+// The filtered rows of a batch with the partition of every row.
+// For every partition it covers, the stored offset the batch continues from and the offset following its last message.
+struct Batch<R> {
+    rows: Vec<R>,
+    partitions: Vec<i32>,
+    offsets: BTreeMap<i32, (Option<i64>, i64)>,
+}
+
+impl<R> Batch<R> {
+    fn drop_partitions(&mut self, dropped: &[i32]) {
+        (self.rows, self.partitions) = std::mem::take(&mut self.rows)
+            .into_iter()
+            .zip(std::mem::take(&mut self.partitions))
+            .filter(|(_, partition)| !dropped.contains(partition))
+            .unzip();
+        self.offsets
+            .retain(|partition, _| !dropped.contains(partition));
+    }
+}
+
+// Writes the rows and the offsets following them in one transaction.
+// In the isolated mode every row gets its own savepoint, so rows that can never be written are left out.
+async fn write_batch<W: TopicWriter>(
+    writer: &W,
+    client: Arc<Client>,
+    group: &str,
+    topic: &str,
+    batch: &Batch<W::Row>,
+    isolated: bool,
+) -> Result<Vec<(usize, anyhow::Error)>> {
+    let mut rejected = Vec::new();
+    client.batch_execute("BEGIN").await?;
+
+    if isolated {
+        for (index, row) in batch.rows.iter().enumerate() {
+            client.batch_execute("SAVEPOINT row").await?;
+            match writer
+                .write(client.clone(), std::slice::from_ref(row))
+                .await
+            {
+                Ok(()) => client.batch_execute("RELEASE SAVEPOINT row").await?,
+                Err(e) if classify_postgres_error(&e) == ErrorKind::Permanent => {
+                    client.batch_execute("ROLLBACK TO SAVEPOINT row").await?;
+                    rejected.push((index, e));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    } else {
+        writer.write(client.clone(), &batch.rows).await?;
+    }
+
+    let updated_on = Utc::now().naive_utc();
+    let mut conflicts = Vec::new();
+    for (partition, (stored_offset, next_offset)) in &batch.offsets {
+        let advanced = client
+            .execute(
+                KAFKA_OFFSETS_ADVANCE,
+                &[
+                    &group,
+                    &topic,
+                    partition,
+                    stored_offset,
+                    next_offset,
+                    &updated_on,
+                ],
+            )
+            .await?;
+        if advanced == 0 {
+            conflicts.push(*partition);
+        }
+    }
+    if !conflicts.is_empty() {
+        return Err(anyhow!(OffsetConflict {
+            partitions: conflicts
+        }));
+    }
+
+    client.batch_execute("COMMIT").await?;
+    Ok(rejected)
+}
+
+struct OffsetWriter<W: TopicWriter> {
+    config: Arc<FilterConfig>,
+    topic: String,
+    writer: W,
+    client: Arc<Client>,
+    policy: RetryPolicy,
+    quarantine: Arc<Quarantine>,
+    missing_partitions: Arc<MissingPartitions>,
+    // Whether the assigned partitions were paused during the last commit
+    paused: bool,
+}
+
+impl<W: TopicWriter> OffsetWriter<W> {
+    // The stored offsets of the topic, retried until Postgres answers
+    async fn stored_offsets(&mut self) -> AHashMap<i32, i64> {
+        loop {
+            let group = &self.config.kafka_consumer_group_id;
+            match load_offsets(&self.client, group, &self.topic).await {
+                Ok(offsets) => return offsets,
+                Err(e) => error!(
+                    "Failed to load the stored offsets of {}, error: {e:?}",
+                    self.topic
+                ),
+            }
+
+            if self.client.is_closed() {
+                warn!("Postgres client was unexpectedly closed");
+                self.client = initialize_db_client(self.config.clone()).await;
+            } else {
+                tokio::time::sleep(Duration::from_secs(2)).await;
+            }
+        }
+    }
+
+    // Doesn't return until the offsets of the batch are committed. While it is retried the assigned partitions
+    // are paused and the consumer keeps polling so that it stays in the group, the messages it gets meanwhile
+    // are left in `dropped` to be read again. Rows that keep failing are quarantined and only the offsets are written.
+    // Returns the offsets following the batch in the partitions it was committed for.
+    async fn commit(
+        &mut self,
+        mut batch: Batch<W::Row>,
+        consumer: &StreamConsumer<ExactlyOnceContext>,
+        dropped: &mut BTreeMap<i32, i64>,
+    ) -> BTreeMap<i32, i64> {
+        let group = &self.config.kafka_consumer_group_id;
+        let mut attempts = 0;
+        let mut isolated = false;
+        let mut stalled_since = None;
+
+        loop {
+            if batch.offsets.is_empty() {
+                return BTreeMap::new();
+            }
+
+            let result = write_batch(
+                &self.writer,
+                self.client.clone(),
+                group,
+                &self.topic,
+                &batch,
+                isolated,
+            )
+            .await;
+
+            let error = match result {
+                Ok(rejected) => {
+                    for (index, e) in rejected {
+                        self.quarantine(&batch.rows[index], attempts + 1, &e);
+                    }
+                    return batch
+                        .offsets
+                        .into_iter()
+                        .map(|(partition, (_, next_offset))| (partition, next_offset))
+                        .collect();
+                }
+                Err(e) => e,
+            };
+
+            if self.client.is_closed() {
+                warn!("Postgres client was unexpectedly closed");
+                self.client = initialize_db_client(self.config.clone()).await;
+            } else if let Err(e) = self.client.batch_execute("ROLLBACK").await {
+                error!("Failed to roll back the {} batch, error: {e}", W::TABLE);
+            }
+
+            // The new owner of the partitions reads their messages again from the stored offsets
+            if let Some(conflict) = error.downcast_ref::<OffsetConflict>() {
+                warn!(
+                    "{conflict}, the messages of the {} batch from these partitions are dropped",
+                    W::TABLE
+                );
+                let partitions = conflict.partitions.clone();
+                batch.drop_partitions(&partitions);
+                continue;
+            }
+
+            let kind = classify_postgres_error(&error);
+            if kind == ErrorKind::MissingPartition {
+                self.missing_partitions.report(&error);
+            }
+            if matches!(kind, ErrorKind::MissingPartition | ErrorKind::Misconfigured) {
+                stalled_since.get_or_insert_with(std::time::Instant::now);
+            }
+            let may_stall = stalled_since.is_none_or(|since| self.policy.may_stall(since));
+
+            let backoff = match kind {
+                // Does not count as an attempt, the batch waits for the reconnect
+                ErrorKind::Connection => self.policy.backoff,
+                // Does not count as an attempt either, the batch waits for the maintenance task
+                ErrorKind::MissingPartition if may_stall => self.policy.backoff,
+                ErrorKind::Misconfigured if may_stall => self.policy.max_backoff,
+                ErrorKind::Permanent if !isolated => {
+                    warn!(
+                        "Writing the {} batch failed permanently, retrying its rows one by one: {error:?}",
+                        W::TABLE
+                    );
+                    isolated = true;
+                    continue;
+                }
+                ErrorKind::Transient if attempts < self.policy.max_retries => {
+                    attempts += 1;
+                    self.policy.backoff(attempts)
+                }
+                kind => {
+                    error!(
+                        "Giving up on {} row(s) of the {} batch after {} attempt(s), {kind:?} error: {error:#}",
+                        batch.rows.len(),
+                        W::TABLE,
+                        attempts + 1
+                    );
+                    for row in &batch.rows {
+                        self.quarantine(row, attempts + 1, &error);
+                    }
+                    batch.rows.clear();
+                    batch.partitions.clear();
+                    attempts = 0;
+                    stalled_since = None;
+                    continue;
+                }
+            };
+
+            error!(
+                "Failed to write the {} batch, attempt {attempts}, retrying in {backoff:?}: {error:?}",
+                W::TABLE
+            );
+            pause_assignment(consumer);
+            self.paused = true;
+            poll_paused(consumer, backoff, dropped).await;
+        }
+    }
+
+    fn quarantine(&self, row: &W::Row, attempts: u32, error: &anyhow::Error) {
+        if let Err(e) =
+            self.quarantine
+                .write("postgres", W::TABLE, attempts, &format!("{error:#}"), row)
+        {
+            error!("Failed to quarantine a {} row, error: {e}", W::TABLE);
+        }
+    }
+}
+
+fn pause_assignment(consumer: &StreamConsumer<ExactlyOnceContext>) {
+    let result = consumer
+        .assignment()
+        .and_then(|assignment| consumer.pause(&assignment));
+    if let Err(e) = result {
+        warn!("Failed to pause the assigned partitions, error: {e}");
+    }
+}
+
+fn resume_assignment(consumer: &StreamConsumer<ExactlyOnceContext>) {
+    let result = consumer
+        .assignment()
+        .and_then(|assignment| consumer.resume(&assignment));
+    if let Err(e) = result {
+        warn!("Failed to resume the assigned partitions, error: {e}");
+    }
+}
+
+// Polls the consumer for `duration` without processing the messages, so that it isn't evicted from the group.
+// Paused partitions deliver nothing, the first offset of the others is kept in `dropped`.
+async fn poll_paused(
+    consumer: &StreamConsumer<ExactlyOnceContext>,
+    duration: Duration,
+    dropped: &mut BTreeMap<i32, i64>,
+) {
+    let deadline = Instant::now() + duration;
+    while let Ok(result) = tokio::time::timeout_at(deadline, consumer.recv()).await {
+        match result {
+            Ok(message) => {
+                dropped
+                    .entry(message.partition())
+                    .or_insert(message.offset());
+                // Most likely a partition assigned in the meantime
+                pause_assignment(consumer);
+            }
+            Err(e) => error!("Kafka consumer error: {}", e),
+        }
+    }
+}
+
+// Seeks a partition back to `offset`. The messages fetched after `seen` before the seek took effect are skipped,
+// the first message at or before it shows the partition is read from the new position.
+fn rewind(
+    consumer: &StreamConsumer<ExactlyOnceContext>,
+    topic: &str,
+    partition: i32,
+    offset: i64,
+    seen: i64,
+    rewound: &mut AHashMap<i32, i64>,
+) -> bool {
+    let seek = tokio::task::block_in_place(|| {
+        consumer.seek(
+            topic,
+            partition,
+            Offset::Offset(offset),
+            Duration::from_secs(10),
+        )
+    });
+    match seek {
+        Ok(()) => {
+            rewound.insert(partition, seen);
+            true
+        }
+        Err(e) => {
+            error!("Failed to rewind {topic} partition {partition}, error: {e}");
+            false
+        }
+    }
+}
+
+async fn exactly_once_consumer<W: TopicWriter>(
+    config: Arc<FilterConfig>,
+    topic: String,
+    writer: W,
+    ctx_stats: ContextWithStats,
+    quarantine: Arc<Quarantine>,
+    missing_partitions: Arc<MissingPartitions>,
+) {
+    let type_name = std::any::type_name::<W::Event>();
+    let stats = ctx_stats.stats.clone();
+    let client = initialize_db_client(config.clone()).await;
+
+    // Offsets are committed to Kafka only to keep the consumer group lag visible
+    let context = ExactlyOnceContext {
+        inner: ctx_stats.clone(),
+        assigned: Mutex::default(),
+    };
+    let consumer: StreamConsumer<ExactlyOnceContext> = ClientConfig::new()
+        .set("group.id", &config.kafka_consumer_group_id)
+        .set("bootstrap.servers", &config.bootstrap_servers)
+        .set("enable.partition.eof", "false")
+        .set("session.timeout.ms", &config.session_timeout_ms)
+        .set("fetch.message.max.bytes", &config.fetch_message_max_bytes)
+        .set("enable.auto.commit", "false")
+        .set("security.protocol", &config.security_protocol)
+        .set("sasl.mechanism", &config.sasl_mechanism)
+        .set("sasl.username", &config.sasl_username)
+        .set("sasl.password", &config.sasl_password)
+        .set("statistics.interval.ms", &config.statistics_interval_ms)
+        .set_log_level((&config.kafka_log_level).into())
+        .create_with_context(context)
+        .expect("Consumer creation failed");
+
+    consumer.subscribe(&[&topic]).unwrap_or_else(|e| {
+        panic!("Couldn't subscribe to specified topic with {type_name}, error: {e}")
+    });
+    ctx_stats.health.add_topic(&topic);
+
+    info!("The exactly once consumer loop for {type_name} is about to start!");
+
+    let batch_window = Duration::from_millis(config.exactly_once_batch_ms);
+    let mut offset_writer = OffsetWriter {
+        policy: RetryPolicy::from_config(&config),
+        config: config.clone(),
+        topic: topic.clone(),
+        writer,
+        client,
+        quarantine,
+        missing_partitions,
+        paused: false,
+    };
+    // The offset following the last written message of the assigned partitions that have one stored
+    let mut stored_offsets: AHashMap<i32, i64> = AHashMap::new();
+    // Assigned partitions whose first message wasn't checked against the stored offset yet
+    let mut unchecked: AHashSet<i32> = AHashSet::new();
+    // Rewound partitions with the last offset fetched before the seek
+    let mut rewound: AHashMap<i32, i64> = AHashMap::new();
+
+    loop {
+        let mut batch = Batch {
+            rows: Vec::new(),
+            partitions: Vec::new(),
+            offsets: BTreeMap::new(),
+        };
+        let mut messages = 0;
+        let mut deadline = None;
+
+        while messages < config.exactly_once_batch_size {
+            let result = match deadline {
+                None => consumer.recv().await,
+                Some(deadline) => match tokio::time::timeout_at(deadline, consumer.recv()).await {
+                    Ok(result) => result,
+                    Err(_) => break,
+                },
+            };
+
+            let message = match result {
+                Ok(message) => message,
+                Err(e) => {
+                    stats.kafka_errors_consumer.inc();
+                    error!("Kafka consumer error: {}", e);
+                    continue;
+                }
+            };
+
+            // The assignment is applied before the first message of a new partition is received
+            let assigned = consumer.context().take_assigned();
+            if !assigned.is_empty() {
+                let offsets = offset_writer.stored_offsets().await;
+                for partition in assigned {
+                    match offsets.get(&partition) {
+                        Some(offset) => {
+                            info!("Reading {topic} partition {partition} from {offset}");
+                            stored_offsets.insert(partition, *offset);
+                            unchecked.insert(partition);
+                        }
+                        None => {
+                            info!("Reading {topic} partition {partition} from the committed offset of the group");
+                            stored_offsets.remove(&partition);
+                        }
+                    }
+                }
+            }
+
+            let partition = message.partition();
+            let offset = message.offset();
+            // Fetched before the partition was rewound
+            if let Some(seen) = rewound.get(&partition).copied() {
+                if offset > seen {
+                    continue;
+                }
+                rewound.remove(&partition);
+            }
+            if let Some(stored_offset) = stored_offsets.get(&partition).copied() {
+                // The group offset is ahead of the stored one, i.e. it expired or was reset
+                if unchecked.remove(&partition) && offset > stored_offset {
+                    warn!("{topic} partition {partition} is read from {offset}, rewinding it to the stored offset {stored_offset}");
+                    if !rewind(
+                        &consumer,
+                        &topic,
+                        partition,
+                        stored_offset,
+                        offset,
+                        &mut rewound,
+                    ) {
+                        unchecked.insert(partition);
+                    }
+                    continue;
+                }
+                // Already written before the partition was assigned
+                if offset < stored_offset {
+                    continue;
+                }
+            }
+
+            deadline.get_or_insert_with(|| Instant::now() + batch_window);
+            batch.offsets.insert(
+                partition,
+                (stored_offsets.get(&partition).copied(), offset + 1),
+            );
+            messages += 1;
+
+            // Messages that don't produce a row still move the offsets forward
+            let payload = match extract_from_message(&message) {
+                Some(payload) => payload,
+                None => continue,
+            };
+            stats
+                .kafka_bytes_rx
+                .inner()
+                .fetch_add(payload.len() as u64, Ordering::Relaxed);
+
+            match serde_json::from_str::<W::Event>(payload) {
+                Ok(event) => {
+                    get_counter(&stats, event.get_type()).inc();
+                    match offset_writer.writer.filter(event) {
+                        Ok(Some(row)) => {
+                            batch.rows.push(row);
+                            batch.partitions.push(partition);
+                        }
+                        Ok(None) => (),
+                        Err(e) => error!("Failed to process {type_name}, error: {e}"),
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to deserialize {type_name} {e}");
+                    stats.kafka_errors_deserialize.inc();
+                }
+            }
+        }
+
+        let mut dropped = BTreeMap::new();
+        let offsets = offset_writer.commit(batch, &consumer, &mut dropped).await;
+        stored_offsets.extend(&offsets);
+        if std::mem::take(&mut offset_writer.paused) {
+            resume_assignment(&consumer);
+        }
+        for (partition, offset) in dropped {
+            rewind(&consumer, &topic, partition, offset, offset, &mut rewound);
+        }
+
+        let mut committed = TopicPartitionList::new();
+        for (partition, next_offset) in &offsets {
+            if let Err(e) =
+                committed.add_partition_offset(&topic, *partition, Offset::Offset(*next_offset))
+            {
+                warn!("Wrong offset for {topic}, error: {e}");
+            }
+        }
+        // An empty list would commit the current positions, including the messages that were dropped
+        if committed.count() > 0 {
+            if let Err(e) = consumer.commit(&committed, CommitMode::Async) {
+                warn!("Failed to commit the offsets of {topic} to Kafka, error: {e}");
+            }
+        }
+    }
+}
+
+// Replaces the consumers, filters and sink executors when exactly_once is set.
+// Every topic gets its own consumer and Postgres connection, batches are written one after another.
+#[allow(clippy::too_many_arguments)]
+pub async fn exactly_once_consumers(
+    config: Arc<FilterConfig>,
+    update_account_topic: String,
+    update_slot_topic: String,
+    notify_block_topic: String,
+    ctx_stats: ContextWithStats,
+    chain_tip: Arc<AtomicU64>,
+    quarantine: Arc<Quarantine>,
+    missing_partitions: Arc<MissingPartitions>,
+) {
+    let accounts = tokio::spawn(exactly_once_consumer(
+        config.clone(),
+        update_account_topic,
+        AccountWriter {
+            config: config.clone(),
+            filter_metrics: ctx_stats.filter_metrics.clone(),
+        },
+        ctx_stats.clone(),
+        quarantine.clone(),
+        missing_partitions.clone(),
+    ));
+
+    let slots = tokio::spawn(exactly_once_consumer(
+        config.clone(),
+        update_slot_topic,
+        SlotWriter { chain_tip },
+        ctx_stats.clone(),
+        quarantine.clone(),
+        missing_partitions.clone(),
+    ));
+
+    let blocks = tokio::spawn(exactly_once_consumer(
+        config,
+        notify_block_topic,
+        BlockWriter,
+        ctx_stats,
+        quarantine,
+        missing_partitions,
+    ));
+
+    let _ = tokio::join!(accounts, slots, blocks);
+}
@@ -1,82 +1,155 @@
-use std::sync::Arc;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 
 use crate::{
     config::FilterConfig,
-    db::{DbAccountInfo, DbBlockInfo},
+    consumer::Consumed,
+    consumer_stats::{FilterLatency, Stats},
+    db::DbAccountInfo,
+    filter_metrics::FilterMetrics,
+    grpc::GrpcStreams,
+    pubsub::PubSub,
+    sink::Sinks,
+    slot_progress::SlotProgress,
+    telemetry::tracer,
 };
 use anyhow::Result;
-use crossbeam_queue::SegQueue;
 use flume::Receiver;
-use kafka_common::kafka_structs::{NotifyBlockMetaData, UpdateAccount, UpdateSlotStatus};
+use kafka_common::kafka_structs::{
+    KafkaReplicaAccountInfoVersions, NotifyBlockMetaData, UpdateAccount, UpdateSlotStatus,
+};
 use log::{error, trace};
+use opentelemetry::{
+    trace::{Span, Status, TraceContextExt, Tracer},
+    KeyValue,
+};
 
-#[inline(always)]
-async fn check_account(
-    config: Arc<FilterConfig>,
-    account_queue: Arc<SegQueue<DbAccountInfo>>,
+fn owner_and_pubkey(update_account: &UpdateAccount) -> (&[u8], &[u8]) {
+    match &update_account.account {
+        // for 1.13.x or earlier
+        KafkaReplicaAccountInfoVersions::V0_0_1(account_info) => {
+            (&account_info.owner, &account_info.pubkey)
+        }
+        KafkaReplicaAccountInfoVersions::V0_0_2(account_info) => {
+            (&account_info.owner, &account_info.pubkey)
+        }
+    }
+}
+
+// The row of an account update whose pubkey or owner is included, None for the other accounts
+pub fn filter_account(
+    config: &FilterConfig,
+    metrics: &FilterMetrics,
     update_account: &UpdateAccount,
-    owner: &Vec<u8>,
-    pubkey: &Vec<u8>,
-) -> Result<()> {
+) -> Result<Option<DbAccountInfo>> {
+    let (owner, pubkey) = owner_and_pubkey(update_account);
     let owner = bs58::encode(owner).into_string();
     let pubkey = bs58::encode(pubkey).into_string();
+    metrics.evaluated();
     if config.filter_include_pubkeys.contains(&pubkey)
         || config.filter_include_owners.contains(&owner)
     {
-        account_queue.push(update_account.try_into()?);
-        trace!(
-            "Add update_account entry to db queue for pubkey {}, owner {}",
-            pubkey,
-            owner
-        );
+        metrics.matched(&owner, &pubkey);
+        trace!("Account update for pubkey {pubkey}, owner {owner} matches the filter");
+        return Ok(Some(update_account.try_into()?));
     }
-    Ok(())
+    Ok(None)
 }
 
+// Observes how long the message took from Kafka to the filter, returns when the filter received it
+fn received<T>(latency: &FilterLatency, consumed: &Consumed<T>) -> Instant {
+    let received = Instant::now();
+    if let Some(appended) = consumed.appended {
+        latency
+            .kafka_to_filter
+            .observe(received.duration_since(appended).as_secs_f64());
+    }
+    received
+}
+
+// Returns true if the account matched the filter and was queued
 async fn process_account_info(
     config: Arc<FilterConfig>,
-    account_queue: Arc<SegQueue<DbAccountInfo>>,
-    update_account: UpdateAccount,
-) -> Result<()> {
-    match &update_account.account {
-        // for 1.13.x or earlier
-        kafka_common::kafka_structs::KafkaReplicaAccountInfoVersions::V0_0_1(account_info) => {
-            check_account(
-                config,
-                account_queue,
-                &update_account,
-                &account_info.owner,
-                &account_info.pubkey,
-            )
-            .await?;
+    sinks: Arc<Sinks>,
+    pubsub: Option<Arc<PubSub>>,
+    grpc: Option<Arc<GrpcStreams>>,
+    metrics: Arc<FilterMetrics>,
+    consumed: Consumed<UpdateAccount>,
+) -> Result<bool> {
+    let update_account = consumed.event;
+    let mut span = tracer().start_with_context("filter account", &consumed.context);
+    if span.is_recording() {
+        let (owner, pubkey) = owner_and_pubkey(&update_account);
+        span.set_attribute(KeyValue::new(
+            "account.pubkey",
+            bs58::encode(pubkey).into_string(),
+        ));
+        span.set_attribute(KeyValue::new(
+            "account.owner",
+            bs58::encode(owner).into_string(),
+        ));
+    }
+
+    let account = match filter_account(&config, &metrics, &update_account) {
+        Ok(account) => account,
+        Err(e) => {
+            span.set_status(Status::error(e.to_string()));
+            return Err(e);
         }
-        kafka_common::kafka_structs::KafkaReplicaAccountInfoVersions::V0_0_2(account_info) => {
-            check_account(
-                config,
-                account_queue,
-                &update_account,
-                &account_info.owner,
-                &account_info.pubkey,
-            )
-            .await?;
+    };
+    span.set_attribute(KeyValue::new("account.matched", account.is_some()));
+
+    if let Some(mut account) = account {
+        account.source = consumed.source;
+        span.set_attribute(KeyValue::new("account.slot", account.slot));
+        span.set_attribute(KeyValue::new(
+            "account.write_version",
+            account.write_version,
+        ));
+        if let Some(pubsub) = pubsub {
+            pubsub.notify_account(&account);
+        }
+        if let Some(grpc) = grpc {
+            grpc.notify_account(&account);
         }
+        sinks.push_account(account, span.span_context().clone(), consumed.ack);
+        return Ok(true);
     }
-    Ok(())
+    Ok(false)
 }
 
 pub async fn account_filter(
     config: Arc<FilterConfig>,
-    account_queue: Arc<SegQueue<DbAccountInfo>>,
-    filter_rx: Receiver<UpdateAccount>,
+    sinks: Arc<Sinks>,
+    pubsub: Option<Arc<PubSub>>,
+    grpc: Option<Arc<GrpcStreams>>,
+    stats: Arc<Stats>,
+    metrics: Arc<FilterMetrics>,
+    filter_rx: Receiver<Consumed<UpdateAccount>>,
 ) {
+    let latency = FilterLatency::new(&stats, "account");
     loop {
-        if let Ok(update_account) = filter_rx.recv_async().await {
+        if let Ok(consumed) = filter_rx.recv_async().await {
+            let received = received(&latency, &consumed);
             let config = config.clone();
-            let account_queue = account_queue.clone();
+            let sinks = sinks.clone();
+            let pubsub = pubsub.clone();
+            let grpc = grpc.clone();
+            let metrics = metrics.clone();
+            let latency = latency.clone();
 
             tokio::spawn(async move {
-                if let Err(e) = process_account_info(config, account_queue, update_account).await {
-                    error!("Failed to process account info, error: {e}");
+                match process_account_info(config, sinks, pubsub, grpc, metrics, consumed).await {
+                    Ok(true) => latency
+                        .filter_to_queue
+                        .observe(received.elapsed().as_secs_f64()),
+                    Ok(false) => (),
+                    Err(e) => error!("Failed to process account info, error: {e}"),
                 }
             });
         }
@@ -84,27 +157,58 @@ pub async fn account_filter(
 }
 
 pub async fn block_filter(
-    block_queue: Arc<SegQueue<DbBlockInfo>>,
-    filter_rx: Receiver<NotifyBlockMetaData>,
+    sinks: Arc<Sinks>,
+    grpc: Option<Arc<GrpcStreams>>,
+    stats: Arc<Stats>,
+    filter_rx: Receiver<Consumed<NotifyBlockMetaData>>,
 ) {
+    let latency = FilterLatency::new(&stats, "block");
     loop {
-        if let Ok(notify_block_data) = filter_rx.recv_async().await {
-            match notify_block_data.block_info {
-                kafka_common::kafka_structs::KafkaReplicaBlockInfoVersions::V0_0_1(bi) => {
-                    block_queue.push(bi.into());
-                }
+        if let Ok(consumed) = filter_rx.recv_async().await {
+            let received = received(&latency, &consumed);
+            let block = consumed.event.into();
+            if let Some(grpc) = &grpc {
+                grpc.notify_block(&block);
             }
+            sinks.push_block(
+                block,
+                consumed.context.span().span_context().clone(),
+                consumed.ack,
+            );
+            latency
+                .filter_to_queue
+                .observe(received.elapsed().as_secs_f64());
         }
     }
 }
 
 pub async fn slot_filter(
-    slot_queue: Arc<SegQueue<UpdateSlotStatus>>,
-    filter_rx: Receiver<UpdateSlotStatus>,
+    sinks: Arc<Sinks>,
+    pubsub: Option<Arc<PubSub>>,
+    grpc: Option<Arc<GrpcStreams>>,
+    chain_tip: Arc<AtomicU64>,
+    stats: Arc<Stats>,
+    filter_rx: Receiver<Consumed<UpdateSlotStatus>>,
 ) {
+    let latency = FilterLatency::new(&stats, "slot");
+    let mut progress = SlotProgress::new(&stats);
     loop {
-        if let Ok(update_slot) = filter_rx.recv_async().await {
-            slot_queue.push(update_slot)
+        if let Ok(consumed) = filter_rx.recv_async().await {
+            let received = received(&latency, &consumed);
+            let update_slot = consumed.event;
+            let span = consumed.context.span().span_context().clone();
+            chain_tip.fetch_max(update_slot.slot, Ordering::Relaxed);
+            progress.update(&update_slot);
+            if let Some(pubsub) = &pubsub {
+                pubsub.notify_slot(&update_slot);
+            }
+            if let Some(grpc) = &grpc {
+                grpc.notify_slot(&update_slot);
+            }
+            sinks.push_slot(update_slot, span, consumed.ack);
+            latency
+                .filter_to_queue
+                .observe(received.elapsed().as_secs_f64());
         }
     }
 }
@@ -0,0 +1,283 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::Mutex;
+
+use ahash::AHashMap;
+use ahash::AHashSet;
+use crossbeam_queue::SegQueue;
+use prometheus_client::metrics::counter::Counter;
+use serde::Serialize;
+
+use crate::config::FilterConfig;
+use crate::consumer_stats::labels;
+use crate::consumer_stats::Stats;
+
+#[derive(Clone, Serialize)]
+pub struct HotPubkey {
+    pub pubkey: String,
+    pub matches: u64,
+    // The count may be overestimated by up to this many matches
+    pub error: u64,
+}
+
+#[derive(Clone, Copy)]
+struct HotCount {
+    matches: u64,
+    error: u64,
+}
+
+// The Space-Saving algorithm over a stream summary: the pubkeys are grouped by their count,
+// so both a match and the replacement of the least matched pubkey take constant time.
+// Once full, a new pubkey replaces the least matched one and inherits its count as the error.
+#[derive(Default)]
+struct StreamSummary {
+    counts: AHashMap<String, HotCount>,
+    buckets: AHashMap<u64, AHashSet<String>>,
+    // The lowest count of a bucket, 0 while empty
+    min: u64,
+}
+
+impl StreamSummary {
+    fn record(&mut self, pubkey: String, capacity: usize) {
+        if let Some(count) = self.counts.get_mut(&pubkey) {
+            let matches = count.matches;
+            count.matches += 1;
+            self.raise(pubkey, matches);
+            return;
+        }
+
+        let error = match self.counts.len() < capacity {
+            true => 0,
+            false => {
+                let min = self.min;
+                let coldest = self
+                    .buckets
+                    .get(&min)
+                    .and_then(|bucket| bucket.iter().next().cloned())
+                    .expect("Hot pubkeys bucket of the lowest count is not empty");
+                self.take(&coldest, min);
+                self.counts.remove(&coldest);
+                min
+            }
+        };
+        self.counts.insert(
+            pubkey.clone(),
+            HotCount {
+                matches: error + 1,
+                error,
+            },
+        );
+        self.buckets.entry(error + 1).or_default().insert(pubkey);
+        if self.min == 0 || error + 1 < self.min {
+            self.min = error + 1;
+        } else if !self.buckets.contains_key(&self.min) {
+            // The evicted pubkey was the last one with the lowest count
+            self.min = error + 1;
+        }
+    }
+
+    // Moves a pubkey from the bucket of its previous count to the next one
+    fn raise(&mut self, pubkey: String, matches: u64) {
+        self.take(&pubkey, matches);
+        self.buckets.entry(matches + 1).or_default().insert(pubkey);
+        if matches == self.min && !self.buckets.contains_key(&matches) {
+            self.min = matches + 1;
+        }
+    }
+
+    fn take(&mut self, pubkey: &str, matches: u64) {
+        if let Some(bucket) = self.buckets.get_mut(&matches) {
+            bucket.remove(pubkey);
+            if bucket.is_empty() {
+                self.buckets.remove(&matches);
+            }
+        }
+    }
+}
+
+// The most often matched pubkeys, counted in bounded memory
+struct HotPubkeys {
+    capacity: usize,
+    // Matches not counted yet, so that the filter tasks never wait for the summary
+    pending: SegQueue<String>,
+    summary: Mutex<StreamSummary>,
+}
+
+impl HotPubkeys {
+    fn record(&self, pubkey: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.pending.push(pubkey.to_string());
+        // Whoever gets the lock counts the pending matches of everyone, the others move on
+        if let Ok(mut summary) = self.summary.try_lock() {
+            self.drain(&mut summary);
+        }
+    }
+
+    // Counts the matches that were pending when called, the ones pushed meanwhile wait for the next drain
+    fn drain(&self, summary: &mut StreamSummary) {
+        for _ in 0..self.pending.len() {
+            match self.pending.pop() {
+                Some(pubkey) => summary.record(pubkey, self.capacity),
+                None => break,
+            }
+        }
+    }
+
+    fn top(&self, limit: usize) -> Vec<HotPubkey> {
+        let mut summary = self.summary.lock().expect("Hot pubkeys lock is poisoned");
+        self.drain(&mut summary);
+
+        let mut top: Vec<HotPubkey> = summary
+            .counts
+            .iter()
+            .map(|(pubkey, count)| HotPubkey {
+                pubkey: pubkey.clone(),
+                matches: count.matches,
+                error: count.error,
+            })
+            .collect();
+        top.sort_unstable_by_key(|hot| std::cmp::Reverse(hot.matches));
+        top.truncate(limit);
+        top
+    }
+}
+
+// Which filter rules the account updates match.
+// Every update is checked against every rule, so a single counter covers the evaluated updates.
+pub struct FilterMetrics {
+    evaluated: Counter<u64, AtomicU64>,
+    // Resolved for every configured rule up front
+    owners: AHashMap<String, Counter<u64, AtomicU64>>,
+    pubkeys: AHashMap<String, Counter<u64, AtomicU64>>,
+    hot_pubkeys: HotPubkeys,
+}
+
+impl FilterMetrics {
+    pub fn new(config: &FilterConfig, stats: &Stats) -> Self {
+        let rules = |rule: &str, values: &AHashSet<String>| {
+            values
+                .iter()
+                .map(|value| {
+                    let labels = labels(&[("rule", rule), ("value", value)]);
+                    let counter = stats.filter_rule_matched.get_or_create(&labels).clone();
+                    (value.clone(), counter)
+                })
+                .collect()
+        };
+
+        Self {
+            evaluated: stats.filter_evaluated.clone(),
+            owners: rules("owner", &config.filter_include_owners),
+            pubkeys: rules("pubkey", &config.filter_include_pubkeys),
+            hot_pubkeys: HotPubkeys {
+                capacity: config.filter_hot_pubkeys_capacity,
+                pending: SegQueue::new(),
+                summary: Mutex::default(),
+            },
+        }
+    }
+
+    pub fn evaluated(&self) {
+        self.evaluated.inc();
+    }
+
+    // Counts every rule a matched account falls under
+    pub fn matched(&self, owner: &str, pubkey: &str) {
+        if let Some(counter) = self.owners.get(owner) {
+            counter.inc();
+        }
+        if let Some(counter) = self.pubkeys.get(pubkey) {
+            counter.inc();
+        }
+        self.hot_pubkeys.record(pubkey);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.hot_pubkeys.capacity
+    }
+
+    pub fn hot_pubkeys(&self, limit: usize) -> Vec<HotPubkey> {
+        self.hot_pubkeys.top(limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hot_pubkeys(capacity: usize, pubkeys: &[&str]) -> HotPubkeys {
+        let hot = HotPubkeys {
+            capacity,
+            pending: SegQueue::new(),
+            summary: Mutex::default(),
+        };
+        pubkeys.iter().for_each(|pubkey| hot.record(pubkey));
+        hot
+    }
+
+    fn top(hot: &HotPubkeys) -> Vec<(String, u64, u64)> {
+        let mut top: Vec<(String, u64, u64)> = hot
+            .top(usize::MAX)
+            .into_iter()
+            .map(|hot| (hot.pubkey, hot.matches, hot.error))
+            .collect();
+        top.sort();
+        top
+    }
+
+    fn entry(pubkey: &str, matches: u64, error: u64) -> (String, u64, u64) {
+        (pubkey.to_string(), matches, error)
+    }
+
+    #[test]
+    fn counts_exactly_below_the_capacity() {
+        let hot = hot_pubkeys(3, &["a", "b", "a", "c", "a", "b"]);
+        assert_eq!(
+            top(&hot),
+            vec![entry("a", 3, 0), entry("b", 2, 0), entry("c", 1, 0)]
+        );
+    }
+
+    #[test]
+    fn replaces_the_least_matched_pubkey_once_full() {
+        let hot = hot_pubkeys(2, &["a", "a", "a", "b", "c"]);
+        assert_eq!(top(&hot), vec![entry("a", 3, 0), entry("c", 2, 1)]);
+
+        // The lowest count moved up with the eviction
+        hot.record("b");
+        assert_eq!(top(&hot), vec![entry("a", 3, 0), entry("b", 3, 2)]);
+    }
+
+    #[test]
+    fn keeps_every_pubkey_matched_more_often_than_the_capacity_allows() {
+        // 1000 matches over 4 slots: a pubkey with more than 250 of them is never lost
+        let mut pubkeys = Vec::new();
+        for i in 0..1000 {
+            pubkeys.push(if i % 3 == 0 {
+                "heavy".to_string()
+            } else {
+                format!("light-{i}")
+            });
+        }
+        let pubkeys: Vec<&str> = pubkeys.iter().map(String::as_str).collect();
+        let hot = hot_pubkeys(4, &pubkeys);
+
+        let top = hot.top(1);
+        assert_eq!(top[0].pubkey, "heavy");
+        assert!(top[0].matches >= 334);
+        assert!(top[0].matches - top[0].error <= 334);
+
+        // Every match is counted once, by the pubkey that holds the slot now
+        let summary = hot.summary.lock().unwrap();
+        let total: u64 = summary.counts.values().map(|count| count.matches).sum();
+        assert_eq!(total, 1000);
+    }
+
+    #[test]
+    fn counts_nothing_without_a_capacity() {
+        let hot = hot_pubkeys(0, &["a", "b"]);
+        assert!(hot.top(10).is_empty());
+    }
+}
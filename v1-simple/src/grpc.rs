@@ -0,0 +1,482 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use ahash::AHashSet;
+use kafka_common::kafka_structs::{KafkaSlotStatus, UpdateSlotStatus};
+use log::{error, info, warn};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{broadcast, broadcast::error::RecvError, mpsc, RwLock},
+};
+use tokio_postgres::{Client, Row};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::{
+    config::{FilterConfig, PersistenceMode},
+    db::{initialize_db_client, DbAccountInfo, DbBlockInfo},
+    sqlite_sink::SQLITE_SCHEME,
+};
+
+use proto::{
+    geyser_filter_server::{GeyserFilter, GeyserFilterServer},
+    AccountFilter, AccountUpdate, BlockFilter, BlockUpdate, GetAccountRequest, GetAccountResponse,
+    Reward, SlotFilter, SlotStatus, SlotUpdate,
+};
+
+pub mod proto {
+    tonic::include_proto!("geyser_filter");
+}
+
+// Updates waiting to be sent to a single client, the client is dropped once the stream buffer overflows
+const CLIENT_BUFFER: usize = 128;
+
+const MAX_MEMCMP_FILTERS: usize = 4;
+
+const MAX_MEMCMP_BYTES: usize = 128;
+
+const ACCOUNT_SELECT: &str = "SELECT pubkey, owner, lamports, slot, executable, rent_epoch, data, write_version, txn_signature \
+    FROM account WHERE pubkey = $1";
+
+const ACCOUNT_AUDIT_SELECT: &str = "SELECT pubkey, owner, lamports, slot, executable, rent_epoch, data, write_version, txn_signature \
+    FROM account_audit WHERE pubkey = $1 \
+    ORDER BY slot DESC, write_version DESC LIMIT 1";
+
+impl From<&DbAccountInfo> for AccountUpdate {
+    fn from(account: &DbAccountInfo) -> Self {
+        Self {
+            pubkey: account.pubkey.clone(),
+            owner: account.owner.clone(),
+            lamports: account.lamports as u64,
+            executable: account.executable,
+            rent_epoch: account.rent_epoch as u64,
+            data: account.data.clone(),
+            slot: account.slot as u64,
+            write_version: account.write_version as u64,
+            txn_signature: account.txn_signature.clone(),
+            is_startup: account.is_startup,
+        }
+    }
+}
+
+impl TryFrom<&Row> for AccountUpdate {
+    type Error = tokio_postgres::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        Ok(Self {
+            pubkey: row.try_get("pubkey")?,
+            owner: row
+                .try_get::<_, Option<Vec<u8>>>("owner")?
+                .unwrap_or_default(),
+            lamports: row.try_get::<_, i64>("lamports")? as u64,
+            executable: row.try_get("executable")?,
+            rent_epoch: row.try_get::<_, i64>("rent_epoch")? as u64,
+            data: row
+                .try_get::<_, Option<Vec<u8>>>("data")?
+                .unwrap_or_default(),
+            slot: row.try_get::<_, i64>("slot")? as u64,
+            write_version: row.try_get::<_, i64>("write_version")? as u64,
+            txn_signature: row.try_get("txn_signature")?,
+            is_startup: false,
+        })
+    }
+}
+
+impl From<&UpdateSlotStatus> for SlotUpdate {
+    fn from(update_slot: &UpdateSlotStatus) -> Self {
+        Self {
+            slot: update_slot.slot,
+            parent: update_slot.parent,
+            status: slot_status(&update_slot.status) as i32,
+        }
+    }
+}
+
+impl From<&DbBlockInfo> for BlockUpdate {
+    fn from(block: &DbBlockInfo) -> Self {
+        Self {
+            slot: block.slot as u64,
+            blockhash: block.blockhash.clone(),
+            rewards: block
+                .rewards
+                .iter()
+                .map(|reward| Reward {
+                    pubkey: reward.pubkey.clone(),
+                    lamports: reward.lamports,
+                    post_balance: reward.post_balance as u64,
+                    reward_type: reward.reward_type.as_ref().map(|t| format!("{t:?}")),
+                    commission: reward.commission.map(|commission| commission as u32),
+                })
+                .collect(),
+            block_time: block.block_time,
+            block_height: block.block_height.map(|height| height as u64),
+        }
+    }
+}
+
+fn slot_status(status: &KafkaSlotStatus) -> SlotStatus {
+    match status {
+        KafkaSlotStatus::Processed => SlotStatus::Processed,
+        KafkaSlotStatus::Confirmed => SlotStatus::Confirmed,
+        KafkaSlotStatus::Rooted => SlotStatus::Rooted,
+    }
+}
+
+// The filter of a SubscribeAccounts client with the keys collected into sets
+struct ClientAccountFilter {
+    owners: AHashSet<Vec<u8>>,
+    pubkeys: AHashSet<Vec<u8>>,
+    memcmp: Vec<(usize, Vec<u8>)>,
+}
+
+impl TryFrom<AccountFilter> for ClientAccountFilter {
+    type Error = Status;
+
+    fn try_from(filter: AccountFilter) -> Result<Self, Self::Error> {
+        if filter
+            .owners
+            .iter()
+            .chain(filter.pubkeys.iter())
+            .any(|key| key.len() != 32)
+        {
+            return Err(Status::invalid_argument("Pubkeys must be 32 bytes long"));
+        }
+
+        if filter.memcmp.len() > MAX_MEMCMP_FILTERS {
+            return Err(Status::invalid_argument(format!(
+                "At most {MAX_MEMCMP_FILTERS} memcmp filters are allowed"
+            )));
+        }
+
+        if filter
+            .memcmp
+            .iter()
+            .any(|memcmp| memcmp.bytes.len() > MAX_MEMCMP_BYTES)
+        {
+            return Err(Status::invalid_argument(format!(
+                "Memcmp bytes must not be longer than {MAX_MEMCMP_BYTES} bytes"
+            )));
+        }
+
+        Ok(Self {
+            owners: filter.owners.into_iter().collect(),
+            pubkeys: filter.pubkeys.into_iter().collect(),
+            memcmp: filter
+                .memcmp
+                .into_iter()
+                .map(|memcmp| (memcmp.offset as usize, memcmp.bytes))
+                .collect(),
+        })
+    }
+}
+
+impl ClientAccountFilter {
+    fn matches(&self, account: &DbAccountInfo) -> bool {
+        let keys_match = (self.owners.is_empty() && self.pubkeys.is_empty())
+            || self.pubkeys.contains(&account.pubkey)
+            || self.owners.contains(&account.owner);
+
+        keys_match
+            && self.memcmp.iter().all(|(offset, bytes)| {
+                account
+                    .data
+                    .get(*offset..offset.saturating_add(bytes.len()))
+                    .is_some_and(|data| data == bytes.as_slice())
+            })
+    }
+}
+
+// Fans the filtered rows out to the gRPC clients. Every client reads the same broadcast channel,
+// a client falling `grpc_stream_buffer` updates behind gets a RESOURCE_EXHAUSTED error and is dropped.
+pub struct GrpcStreams {
+    accounts: broadcast::Sender<Arc<DbAccountInfo>>,
+    slots: broadcast::Sender<Arc<UpdateSlotStatus>>,
+    blocks: broadcast::Sender<Arc<DbBlockInfo>>,
+}
+
+impl GrpcStreams {
+    pub fn new(buffer: usize) -> Self {
+        Self {
+            accounts: broadcast::channel(buffer).0,
+            slots: broadcast::channel(buffer).0,
+            blocks: broadcast::channel(buffer).0,
+        }
+    }
+
+    // Nothing is cloned while there are no clients
+    pub fn notify_account(&self, account: &DbAccountInfo) {
+        if self.accounts.receiver_count() > 0 {
+            let _ = self.accounts.send(Arc::new(account.clone()));
+        }
+    }
+
+    pub fn notify_slot(&self, update_slot: &UpdateSlotStatus) {
+        if self.slots.receiver_count() > 0 {
+            let _ = self.slots.send(Arc::new(update_slot.clone()));
+        }
+    }
+
+    pub fn notify_block(&self, block: &DbBlockInfo) {
+        if self.blocks.receiver_count() > 0 {
+            let _ = self.blocks.send(Arc::new(block.clone()));
+        }
+    }
+}
+
+// Sends the broadcast items accepted by `map` to a client until it disconnects or falls behind
+fn forward<T, U>(
+    mut receiver: broadcast::Receiver<Arc<T>>,
+    map: impl Fn(&T) -> Option<U> + Send + 'static,
+) -> ReceiverStream<Result<U, Status>>
+where
+    T: Send + Sync + 'static,
+    U: Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(CLIENT_BUFFER);
+
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(item) => {
+                    if let Some(update) = map(&item) {
+                        if tx.send(Ok(update)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("gRPC client fell {skipped} updates behind, closing the stream");
+                    let _ = tx
+                        .send(Err(Status::resource_exhausted(format!(
+                            "The client fell {skipped} updates behind"
+                        ))))
+                        .await;
+                    break;
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    ReceiverStream::new(rx)
+}
+
+struct GrpcService {
+    config: Arc<FilterConfig>,
+    streams: Arc<GrpcStreams>,
+    // Connected on the first GetAccount call
+    client: RwLock<Option<Arc<Client>>>,
+}
+
+impl GrpcService {
+    async fn client(&self) -> Arc<Client> {
+        if let Some(client) = self.client.read().await.as_ref() {
+            if !client.is_closed() {
+                return client.clone();
+            }
+            warn!("gRPC Postgres client was unexpectedly closed");
+        }
+
+        let mut client = self.client.write().await;
+        match client.as_ref() {
+            Some(current) if !current.is_closed() => current.clone(),
+            _ => client
+                .insert(initialize_db_client(self.config.clone()).await)
+                .clone(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl GeyserFilter for GrpcService {
+    type SubscribeAccountsStream = ReceiverStream<Result<AccountUpdate, Status>>;
+    type SubscribeSlotsStream = ReceiverStream<Result<SlotUpdate, Status>>;
+    type SubscribeBlocksStream = ReceiverStream<Result<BlockUpdate, Status>>;
+
+    async fn subscribe_accounts(
+        &self,
+        request: Request<AccountFilter>,
+    ) -> Result<Response<Self::SubscribeAccountsStream>, Status> {
+        let filter = ClientAccountFilter::try_from(request.into_inner())?;
+
+        Ok(Response::new(forward(
+            self.streams.accounts.subscribe(),
+            move |account| filter.matches(account).then(|| account.into()),
+        )))
+    }
+
+    async fn subscribe_slots(
+        &self,
+        request: Request<SlotFilter>,
+    ) -> Result<Response<Self::SubscribeSlotsStream>, Status> {
+        let statuses = request.into_inner().statuses;
+
+        Ok(Response::new(forward(
+            self.streams.slots.subscribe(),
+            move |update_slot| {
+                let update = SlotUpdate::from(update_slot);
+                (statuses.is_empty() || statuses.contains(&update.status)).then_some(update)
+            },
+        )))
+    }
+
+    async fn subscribe_blocks(
+        &self,
+        _request: Request<BlockFilter>,
+    ) -> Result<Response<Self::SubscribeBlocksStream>, Status> {
+        Ok(Response::new(forward(
+            self.streams.blocks.subscribe(),
+            |block| Some(block.into()),
+        )))
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<GetAccountRequest>,
+    ) -> Result<Response<GetAccountResponse>, Status> {
+        if self
+            .config
+            .postgres_connection_str
+            .starts_with(SQLITE_SCHEME)
+        {
+            return Err(Status::unimplemented(
+                "Accounts are read from Postgres only",
+            ));
+        }
+
+        let pubkey = request.into_inner().pubkey;
+        if pubkey.len() != 32 {
+            return Err(Status::invalid_argument("Pubkeys must be 32 bytes long"));
+        }
+
+        // The account table is not written in the History mode
+        let query = match self.config.persistence_mode {
+            PersistenceMode::History => ACCOUNT_AUDIT_SELECT,
+            _ => ACCOUNT_SELECT,
+        };
+
+        let account = self
+            .client()
+            .await
+            .query_opt(query, &[&pubkey])
+            .await
+            .and_then(|row| row.as_ref().map(AccountUpdate::try_from).transpose())
+            .map_err(|e| {
+                error!("Failed to read an account for a gRPC client, error: {e}");
+                Status::internal("Failed to read the account")
+            })?;
+
+        Ok(Response::new(GetAccountResponse { account }))
+    }
+}
+
+pub async fn start_grpc_server(config: Arc<FilterConfig>, streams: Arc<GrpcStreams>, port: u16) {
+    let mut shutdown_stream = signal(SignalKind::terminate()).unwrap();
+    let service = GrpcService {
+        config,
+        streams,
+        client: RwLock::new(None),
+    };
+
+    let grpc_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+    info!("Starting gRPC server on {grpc_addr}");
+
+    if let Err(e) = Server::builder()
+        .add_service(GeyserFilterServer::new(service))
+        .serve_with_shutdown(grpc_addr, async move {
+            shutdown_stream.recv().await;
+        })
+        .await
+    {
+        error!("gRPC server failed, error: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::proto::Memcmp;
+    use super::*;
+
+    fn account(pubkey: u8, owner: u8, data: &[u8]) -> DbAccountInfo {
+        DbAccountInfo {
+            pubkey: vec![pubkey; 32],
+            lamports: 1,
+            owner: vec![owner; 32],
+            executable: false,
+            rent_epoch: 0,
+            data: data.to_vec(),
+            slot: 1,
+            write_version: 1,
+            txn_signature: None,
+            is_startup: false,
+            retrieved_time: Utc::now().naive_utc(),
+            source: None,
+        }
+    }
+
+    fn filter(owners: &[u8], pubkeys: &[u8], memcmp: &[(u64, &[u8])]) -> ClientAccountFilter {
+        AccountFilter {
+            owners: owners.iter().map(|owner| vec![*owner; 32]).collect(),
+            pubkeys: pubkeys.iter().map(|pubkey| vec![*pubkey; 32]).collect(),
+            memcmp: memcmp
+                .iter()
+                .map(|(offset, bytes)| Memcmp {
+                    offset: *offset,
+                    bytes: bytes.to_vec(),
+                })
+                .collect(),
+        }
+        .try_into()
+        .unwrap()
+    }
+
+    #[test]
+    fn matches_listed_pubkeys_or_owners() {
+        let filter = filter(&[9], &[1], &[]);
+        assert!(filter.matches(&account(1, 0, &[])));
+        assert!(filter.matches(&account(2, 9, &[])));
+        assert!(!filter.matches(&account(2, 0, &[])));
+    }
+
+    #[test]
+    fn matches_every_account_without_keys() {
+        assert!(filter(&[], &[], &[]).matches(&account(3, 4, &[])));
+    }
+
+    #[test]
+    fn requires_every_memcmp_to_match() {
+        let filter = filter(&[], &[], &[(0, &[1]), (2, &[3, 4])]);
+        assert!(filter.matches(&account(1, 0, &[1, 2, 3, 4])));
+        assert!(!filter.matches(&account(1, 0, &[1, 2, 3, 5])));
+        // The data ends before the bytes do
+        assert!(!filter.matches(&account(1, 0, &[1, 2, 3])));
+    }
+
+    #[test]
+    fn rejects_invalid_filters() {
+        let short_key = AccountFilter {
+            owners: vec![vec![1; 31]],
+            ..Default::default()
+        };
+        assert!(ClientAccountFilter::try_from(short_key).is_err());
+
+        let too_many = AccountFilter {
+            memcmp: vec![Memcmp::default(); MAX_MEMCMP_FILTERS + 1],
+            ..Default::default()
+        };
+        assert!(ClientAccountFilter::try_from(too_many).is_err());
+
+        let too_long = AccountFilter {
+            memcmp: vec![Memcmp {
+                offset: 0,
+                bytes: vec![0; MAX_MEMCMP_BYTES + 1],
+            }],
+            ..Default::default()
+        };
+        assert!(ClientAccountFilter::try_from(too_long).is_err());
+    }
+}
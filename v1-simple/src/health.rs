@@ -0,0 +1,235 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use ahash::AHashMap;
+use rdkafka::{consumer::Rebalance, Statistics, TopicPartitionList};
+use serde_json::{json, Value};
+use tokio_postgres::Client;
+
+use crate::{config::FilterConfig, db::connect_to_db, sink::SinkQueues};
+
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Marks its task as stopped when dropped, also when the task panics
+struct RunningGuard(Arc<AtomicBool>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+// State behind the /healthz and /readyz endpoints of the metrics server
+pub struct Health {
+    max_queue_depth: usize,
+    max_kafka_lag: u64,
+    tasks: Mutex<Vec<(String, Arc<AtomicBool>)>>,
+    assigned_partitions: Mutex<AHashMap<String, usize>>,
+    // Reported with the rdkafka statistics, unknown while `statistics_interval_ms` is 0
+    kafka_lag: Mutex<AHashMap<String, u64>>,
+    sink_queues: Mutex<Vec<(String, Arc<SinkQueues>)>>,
+    database: Mutex<Option<Arc<FilterConfig>>>,
+    // Connected on the first readiness check
+    database_client: tokio::sync::Mutex<Option<Arc<Client>>>,
+}
+
+impl Health {
+    pub fn new(max_queue_depth: usize, max_kafka_lag: u64) -> Self {
+        Self {
+            max_queue_depth,
+            max_kafka_lag,
+            tasks: Mutex::default(),
+            assigned_partitions: Mutex::default(),
+            kafka_lag: Mutex::default(),
+            sink_queues: Mutex::default(),
+            database: Mutex::default(),
+            database_client: tokio::sync::Mutex::default(),
+        }
+    }
+
+    // Runs the task, the process is reported as not alive once it returns
+    pub fn watch<F: Future>(
+        &self,
+        name: impl Into<String>,
+        task: F,
+    ) -> impl Future<Output = F::Output> {
+        let running = Arc::new(AtomicBool::new(true));
+        self.tasks
+            .lock()
+            .expect("Health lock is poisoned")
+            .push((name.into(), running.clone()));
+
+        async move {
+            let _guard = RunningGuard(running);
+            task.await
+        }
+    }
+
+    // The process is ready only once every topic has partitions assigned
+    pub fn add_topic(&self, topic: &str) {
+        self.assigned_partitions
+            .lock()
+            .expect("Health lock is poisoned")
+            .entry(topic.to_string())
+            .or_default();
+    }
+
+    pub fn set_assignment(&self, assignment: &TopicPartitionList) {
+        let mut assigned_partitions = self
+            .assigned_partitions
+            .lock()
+            .expect("Health lock is poisoned");
+
+        let mut counts: AHashMap<String, usize> = AHashMap::new();
+        for element in assignment.elements() {
+            *counts.entry(element.topic().to_string()).or_default() += 1;
+        }
+        assigned_partitions.extend(counts);
+    }
+
+    pub fn rebalance(&self, rebalance: &Rebalance<'_>) {
+        match rebalance {
+            Rebalance::Assign(assignment) => self.set_assignment(assignment),
+            Rebalance::Revoke(revoked) => {
+                let mut assigned_partitions = self
+                    .assigned_partitions
+                    .lock()
+                    .expect("Health lock is poisoned");
+                for element in revoked.elements() {
+                    assigned_partitions.insert(element.topic().to_string(), 0);
+                }
+            }
+            Rebalance::Error(_) => (),
+        }
+    }
+
+    pub fn statistics(&self, statistics: &Statistics) {
+        let mut kafka_lag = self.kafka_lag.lock().expect("Health lock is poisoned");
+        for (name, topic) in &statistics.topics {
+            // The internal partition -1 and partitions with an unknown lag report -1
+            let lag = topic
+                .partitions
+                .values()
+                .filter(|partition| partition.partition >= 0 && partition.consumer_lag >= 0)
+                .map(|partition| partition.consumer_lag as u64)
+                .sum();
+            kafka_lag.insert(name.clone(), lag);
+        }
+    }
+
+    pub fn add_sink_queues(&self, sink: &str, queues: Arc<SinkQueues>) {
+        self.sink_queues
+            .lock()
+            .expect("Health lock is poisoned")
+            .push((sink.to_string(), queues));
+    }
+
+    // The Postgres connection is checked by the readiness probe
+    pub fn check_database(&self, config: Arc<FilterConfig>) {
+        *self.database.lock().expect("Health lock is poisoned") = Some(config);
+    }
+
+    // Names of the tasks and whether they are still running
+    pub fn liveness(&self) -> (bool, Value) {
+        let tasks = self.tasks.lock().expect("Health lock is poisoned");
+        let alive = tasks
+            .iter()
+            .all(|(_, running)| running.load(Ordering::Relaxed));
+        let detail: serde_json::Map<String, Value> = tasks
+            .iter()
+            .map(|(name, running)| (name.clone(), json!(running.load(Ordering::Relaxed))))
+            .collect();
+
+        (alive, json!({ "alive": alive, "tasks": detail }))
+    }
+
+    pub async fn readiness(&self) -> (bool, Value) {
+        let (kafka_ready, kafka) = self.kafka_readiness();
+        let (lag_ready, lag) = self.lag_readiness();
+        let (queues_ready, queues) = self.queue_readiness();
+        let (database_ready, database) = self.database_readiness().await;
+
+        let ready = kafka_ready && lag_ready && queues_ready && database_ready;
+        (
+            ready,
+            json!({
+                "ready": ready,
+                "kafka": kafka,
+                "kafka_lag": lag,
+                "queues": queues,
+                "database": database,
+            }),
+        )
+    }
+
+    fn kafka_readiness(&self) -> (bool, Value) {
+        let assigned_partitions = self
+            .assigned_partitions
+            .lock()
+            .expect("Health lock is poisoned");
+        let ready = assigned_partitions.values().all(|count| *count > 0);
+
+        (
+            ready,
+            json!({ "ready": ready, "assigned_partitions": *assigned_partitions }),
+        )
+    }
+
+    fn lag_readiness(&self) -> (bool, Value) {
+        let kafka_lag = self.kafka_lag.lock().expect("Health lock is poisoned");
+        let ready = kafka_lag.values().all(|lag| *lag <= self.max_kafka_lag);
+
+        (
+            ready,
+            json!({ "ready": ready, "max": self.max_kafka_lag, "lag": *kafka_lag }),
+        )
+    }
+
+    fn queue_readiness(&self) -> (bool, Value) {
+        let sink_queues = self.sink_queues.lock().expect("Health lock is poisoned");
+        let depths: AHashMap<&str, usize> = sink_queues
+            .iter()
+            .map(|(sink, queues)| (sink.as_str(), queues.len()))
+            .collect();
+        let ready = depths.values().all(|depth| *depth <= self.max_queue_depth);
+
+        (
+            ready,
+            json!({ "ready": ready, "max": self.max_queue_depth, "depth": depths }),
+        )
+    }
+
+    async fn database_readiness(&self) -> (bool, Value) {
+        let config = self
+            .database
+            .lock()
+            .expect("Health lock is poisoned")
+            .clone();
+        let Some(config) = config else {
+            return (true, Value::Null);
+        };
+
+        match tokio::time::timeout(DATABASE_TIMEOUT, self.ping_database(config)).await {
+            Ok(Ok(())) => (true, json!({ "ready": true })),
+            Ok(Err(e)) => (false, json!({ "ready": false, "error": e.to_string() })),
+            Err(_) => (false, json!({ "ready": false, "error": "Timed out" })),
+        }
+    }
+
+    async fn ping_database(&self, config: Arc<FilterConfig>) -> anyhow::Result<()> {
+        let mut client = self.database_client.lock().await;
+        let client = match client.as_ref() {
+            Some(current) if !current.is_closed() => current.clone(),
+            _ => client.insert(connect_to_db(config).await?).clone(),
+        };
+
+        client.simple_query("SELECT 1").await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::Arc;
+
+use ahash::AHashMap;
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use tokio_postgres::{Client, Row};
+
+use crate::{
+    config::FilterConfig,
+    db::connect_to_db,
+    rpc::{encode_data, DataSlice, Encoding, RpcResult},
+    sqlite_sink::SQLITE_SCHEME,
+};
+
+// The newest version of every pubkey at the slot, served by the account_audit_pubkey_slot_wv index
+const ACCOUNTS_AT_SLOT_SELECT: &str = "SELECT DISTINCT ON (pubkey) \
+    pubkey, owner, lamports, slot, executable, rent_epoch, data, write_version, txn_signature \
+    FROM account_audit \
+    WHERE pubkey = ANY($1) AND slot <= $2 \
+    ORDER BY pubkey, slot DESC, write_version DESC";
+
+// The state of every pubkey at the slot, in the order of the pubkeys.
+// None for the pubkeys without a version at or before the slot, also if it was removed by the retention.
+// A pubkey requested more than once gets the same row every time.
+pub async fn accounts_at_slot(
+    client: &Client,
+    pubkeys: &[Vec<u8>],
+    slot: u64,
+) -> Result<Vec<Option<Arc<Row>>>> {
+    let rows: AHashMap<Vec<u8>, Arc<Row>> = client
+        .query(ACCOUNTS_AT_SLOT_SELECT, &[&pubkeys, &(slot as i64)])
+        .await?
+        .into_iter()
+        .map(|row| (row.get("pubkey"), Arc::new(row)))
+        .collect();
+
+    Ok(pubkeys
+        .iter()
+        .map(|pubkey| rows.get(pubkey).cloned())
+        .collect())
+}
+
+// An account version with the fields needed to tell versions apart
+pub fn account_version_json(
+    row: &Row,
+    encoding: Encoding,
+    slice: Option<DataSlice>,
+) -> RpcResult<Value> {
+    let pubkey: Vec<u8> = row.try_get("pubkey")?;
+    let owner: Vec<u8> = row
+        .try_get::<_, Option<Vec<u8>>>("owner")?
+        .unwrap_or_default();
+    let data: Vec<u8> = row
+        .try_get::<_, Option<Vec<u8>>>("data")?
+        .unwrap_or_default();
+    let txn_signature: Option<Vec<u8>> = row.try_get("txn_signature")?;
+
+    Ok(json!({
+        "pubkey": bs58::encode(pubkey).into_string(),
+        "slot": row.try_get::<_, i64>("slot")?,
+        "writeVersion": row.try_get::<_, i64>("write_version")?,
+        "txnSignature": txn_signature.map(|signature| bs58::encode(signature).into_string()),
+        "data": encode_data(&data, encoding, slice)?,
+        "executable": row.try_get::<_, bool>("executable")?,
+        "lamports": row.try_get::<_, i64>("lamports")?,
+        "owner": bs58::encode(owner).into_string(),
+        "rentEpoch": row.try_get::<_, i64>("rent_epoch")?,
+    }))
+}
+
+// Prints the state of the pubkeys at the slot as a JSON array, null for the unknown ones
+pub async fn print_accounts_at_slot(
+    config: FilterConfig,
+    slot: u64,
+    pubkeys: &[String],
+) -> Result<()> {
+    if config.postgres_connection_str.starts_with(SQLITE_SCHEME) {
+        return Err(anyhow!("Account history is read from Postgres only"));
+    }
+    if !config.persistence_mode.writes_history() {
+        return Err(anyhow!(
+            "account_audit is not written in the {:?} persistence mode",
+            config.persistence_mode
+        ));
+    }
+
+    let pubkeys = pubkeys
+        .iter()
+        .map(|pubkey| {
+            bs58::decode(pubkey)
+                .into_vec()
+                .ok()
+                .filter(|pubkey| pubkey.len() == 32)
+                .ok_or_else(|| anyhow!("Invalid pubkey: {pubkey}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let client = connect_to_db(Arc::new(config)).await?;
+    let accounts = accounts_at_slot(&client, &pubkeys, slot)
+        .await?
+        .iter()
+        .map(|row| match row {
+            Some(row) => account_version_json(row, Encoding::Base64, None)
+                .map_err(|e| anyhow!("Failed to read an account, error: {}", e.message)),
+            None => Ok(Value::Null),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    println!("{}", serde_json::to_string_pretty(&accounts)?);
+    Ok(())
+}
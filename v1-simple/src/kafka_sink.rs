@@ -0,0 +1,170 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use async_trait::async_trait;
+use kafka_common::kafka_structs::NotifyBlockMetaData;
+use kafka_common::kafka_structs::UpdateSlotStatus;
+use rdkafka::error::KafkaError;
+use rdkafka::error::RDKafkaErrorCode;
+use rdkafka::producer::FutureProducer;
+use rdkafka::producer::FutureRecord;
+use rdkafka::ClientConfig;
+
+use crate::config::FilterConfig;
+use crate::config::KafkaSinkConfig;
+use crate::db::DbAccountInfo;
+use crate::db::DbBlockInfo;
+use crate::db_retry::ErrorKind;
+use crate::sink::Sink;
+
+// Publishes the filtered events to the output topics as JSON, the same way the geyser plugin does.
+// Account updates are produced exactly as they were consumed.
+pub struct KafkaSink {
+    config: KafkaSinkConfig,
+    producer: FutureProducer,
+}
+
+impl KafkaSink {
+    pub fn new(filter_config: &FilterConfig, config: KafkaSinkConfig) -> Self {
+        let bootstrap_servers = config
+            .bootstrap_servers
+            .as_ref()
+            .unwrap_or(&filter_config.bootstrap_servers);
+
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", bootstrap_servers)
+            .set("security.protocol", &filter_config.security_protocol)
+            .set("sasl.mechanism", &filter_config.sasl_mechanism)
+            .set("sasl.username", &filter_config.sasl_username)
+            .set("sasl.password", &filter_config.sasl_password)
+            .set("message.timeout.ms", &config.message_timeout_ms)
+            .set("compression.codec", &config.compression_codec)
+            // Keeps the order of the events of an account when librdkafka retries
+            .set("enable.idempotence", "true")
+            .set_log_level((&filter_config.kafka_log_level).into())
+            .create()
+            .expect("Kafka sink producer creation failed");
+
+        Self { config, producer }
+    }
+
+    // Waits until every event is acknowledged, the first error fails the whole batch
+    async fn produce<P: AsRef<str>>(&self, topic: &str, events: Vec<(Vec<u8>, P)>) -> Result<()> {
+        let mut deliveries = Vec::with_capacity(events.len());
+        let mut result = Ok(());
+
+        for (key, payload) in &events {
+            let record = FutureRecord::to(topic).key(key).payload(payload.as_ref());
+            match self.producer.send_result(record) {
+                Ok(delivery) => deliveries.push(delivery),
+                Err((e, _)) => {
+                    result = Err(anyhow!(e).context(format!("Failed to enqueue event to {topic}")));
+                    break;
+                }
+            }
+        }
+
+        for delivery in deliveries {
+            let error = match delivery.await {
+                Ok(Ok(_)) => continue,
+                Ok(Err((e, _))) => {
+                    anyhow!(e).context(format!("Failed to deliver event to {topic}"))
+                }
+                Err(_) => anyhow!("Delivery to {topic} was canceled"),
+            };
+            if result.is_ok() {
+                result = Err(error);
+            }
+        }
+
+        result
+    }
+}
+
+#[async_trait]
+impl Sink for KafkaSink {
+    fn name(&self) -> &str {
+        "kafka"
+    }
+
+    fn batch_size(&self) -> usize {
+        self.config.batch_size
+    }
+
+    fn republishes_accounts(&self) -> bool {
+        self.config.update_account_topic.is_some()
+    }
+
+    fn classify_error(&self, error: &anyhow::Error) -> ErrorKind {
+        match error.chain().find_map(|e| e.downcast_ref::<KafkaError>()) {
+            Some(KafkaError::MessageProduction(RDKafkaErrorCode::MessageSizeTooLarge)) => {
+                ErrorKind::Permanent
+            }
+            _ => ErrorKind::Transient,
+        }
+    }
+
+    async fn write_accounts(&self, accounts: &[DbAccountInfo]) -> Result<()> {
+        let topic = match &self.config.update_account_topic {
+            Some(topic) => topic,
+            None => return Ok(()),
+        };
+
+        // Keyed by pubkey, so all events of an account go to the same partition.
+        // Delivery is at least once and unordered on retries: a failed batch waits for its backoff while newer
+        // events go on, and a partly delivered batch is sent again as a whole.
+        let events = accounts
+            .iter()
+            .map(|account| {
+                let source = account.source.clone().ok_or_else(|| {
+                    anyhow!(
+                        "Account {} at slot {} has no source event",
+                        bs58::encode(&account.pubkey).into_string(),
+                        account.slot
+                    )
+                })?;
+                Ok((account.pubkey.clone(), source))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        self.produce(topic, events).await
+    }
+
+    async fn write_blocks(&self, blocks: &[DbBlockInfo]) -> Result<()> {
+        let topic = match &self.config.notify_block_topic {
+            Some(topic) => topic,
+            None => return Ok(()),
+        };
+
+        let events = blocks
+            .iter()
+            .map(|block| {
+                let event = NotifyBlockMetaData::from(block);
+                Ok((
+                    block.blockhash.as_bytes().to_vec(),
+                    serde_json::to_string(&event)?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        self.produce(topic, events).await
+    }
+
+    async fn write_slots(&self, slots: &[UpdateSlotStatus]) -> Result<()> {
+        let topic = match &self.config.update_slot_topic {
+            Some(topic) => topic,
+            None => return Ok(()),
+        };
+
+        let events = slots
+            .iter()
+            .map(|slot| {
+                Ok((
+                    slot.slot.to_string().into_bytes(),
+                    serde_json::to_string(slot)?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        self.produce(topic, events).await
+    }
+}
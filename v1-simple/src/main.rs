@@ -1,36 +1,82 @@
 mod build_info;
+mod clickhouse_sink;
+mod coalescer;
 mod config;
 mod consumer;
 mod consumer_stats;
 mod db;
 mod db_inserts;
+mod db_maintenance;
+mod db_retry;
 mod db_statements;
+mod exactly_once;
 mod filter;
+mod filter_metrics;
+mod grpc;
+mod health;
+mod history;
+mod kafka_sink;
+mod parquet_sink;
+mod postgres_sink;
 mod prometheus;
+mod pubsub;
+mod rpc;
+mod sink;
+mod slot_atomic;
+mod slot_progress;
+mod spill_queue;
+mod sqlite_sink;
+mod telemetry;
+mod webhook_sink;
 
-use std::sync::Arc;
+use std::{
+    sync::{atomic::AtomicU64, Arc},
+    time::Duration,
+};
 
 use crate::{
     build_info::get_build_info,
-    consumer::consumer,
-    consumer_stats::ContextWithStats,
-    db::DbBlockInfo,
+    coalescer::account_coalescer,
+    consumer::{consumer, Consumed},
+    consumer_stats::{sample_queue_lengths, ContextWithStats, QueueGauge},
     filter::{block_filter, slot_filter},
 };
 use clap::{Arg, Command};
-use config::{env_build_config, FilterConfig};
+use clickhouse_sink::ClickHouseSink;
+use config::{env_build_config, FilterConfig, SinkConfig};
 use crossbeam_queue::SegQueue;
-use db::{db_stmt_executor, initialize_db_client, DbAccountInfo};
+use db_maintenance::db_maintenance;
+use db_maintenance::MissingPartitions;
+use db_retry::Quarantine;
+use exactly_once::{check_exactly_once_config, exactly_once_consumers};
 use fast_log::{
     consts::LogSize,
     plugin::{file_split::RollingType, packer::LogPacker},
     Config, Logger,
 };
 use filter::account_filter;
+use grpc::{start_grpc_server, GrpcStreams};
+use health::Health;
+use history::print_accounts_at_slot;
 use kafka_common::kafka_structs::{NotifyBlockMetaData, UpdateAccount, UpdateSlotStatus};
+use kafka_sink::KafkaSink;
 use log::{error, info};
+use parquet_sink::ParquetSink;
+use postgres_sink::PostgresSink;
 use prometheus::start_prometheus;
-use tokio::fs;
+use pubsub::{start_pubsub_server, PubSub};
+use rpc::start_rpc_server;
+use sink::{sink_executor, Sink, SinkQueues, Sinks};
+use slot_atomic::SlotAtomicSink;
+use slot_progress::sample_slot_lag;
+use spill_queue::spawn_spill_workers;
+use sqlite_sink::{SqliteSink, SQLITE_SCHEME};
+use telemetry::{init_tracing, shutdown_tracing};
+use tokio::{
+    fs,
+    signal::unix::{signal, SignalKind},
+};
+use webhook_sink::WebhookSink;
 
 async fn run(mut config: FilterConfig) {
     let logger: &'static Logger = fast_log::init(Config::new().console().file_split(
@@ -43,15 +89,23 @@ async fn run(mut config: FilterConfig) {
 
     info!("{}", get_build_info());
 
+    init_tracing(&config).unwrap_or_else(|e| panic!("Failed to initialize tracing, error: {e}"));
+
     let prometheus_port = config
         .prometheus_port
         .parse()
         .unwrap_or_else(|e| panic!("Wrong prometheus port number, error: {e}"));
 
-    let ctx_stats = ContextWithStats::default();
+    let health = Arc::new(Health::new(
+        config.ready_max_queue_depth,
+        config.ready_max_kafka_lag,
+    ));
+    let ctx_stats = ContextWithStats::new(&config, health.clone());
 
     let prometheus = tokio::spawn(start_prometheus(
         ctx_stats.stats.clone(),
+        health.clone(),
+        ctx_stats.filter_metrics.clone(),
         config.update_account_topic.clone(),
         config.update_slot_topic.clone(),
         config.notify_block_topic.clone(),
@@ -75,67 +129,396 @@ async fn run(mut config: FilterConfig) {
 
     let config = Arc::new(config);
 
-    let db_account_queue: Arc<SegQueue<DbAccountInfo>> = Arc::new(SegQueue::new());
-    let db_block_queue: Arc<SegQueue<DbBlockInfo>> = Arc::new(SegQueue::new());
-    let db_slot_queue: Arc<SegQueue<UpdateSlotStatus>> = Arc::new(SegQueue::new());
-
     logger.set_level((&config.global_log_level).into());
 
-    let client = initialize_db_client(config.clone()).await;
+    let quarantine = Arc::new(Quarantine::new(config.quarantine_path.clone()));
+    let missing_partitions = Arc::new(MissingPartitions::default());
+
+    if let Some(rpc_port) = config.rpc_port {
+        tokio::spawn(start_rpc_server(config.clone(), rpc_port));
+    }
+
+    // Rows and offsets share Postgres transactions, the filter tasks and sink queues are bypassed
+    if config.exactly_once {
+        check_exactly_once_config(&config);
+
+        let chain_tip = Arc::new(AtomicU64::new(0));
+        health.check_database(config.clone());
+        let db_maintenance = tokio::spawn(health.watch(
+            "db_maintenance",
+            db_maintenance(
+                config.clone(),
+                ctx_stats.stats.clone(),
+                chain_tip.clone(),
+                missing_partitions.clone(),
+            ),
+        ));
+
+        let consumers = tokio::spawn(health.watch(
+            "exactly_once_consumers",
+            exactly_once_consumers(
+                config.clone(),
+                update_account_topic,
+                update_slot_topic,
+                notify_block_topic,
+                ctx_stats,
+                chain_tip,
+                quarantine,
+                missing_partitions,
+            ),
+        ));
 
-    let (filter_tx_account, filter_rx_account) = flume::unbounded::<UpdateAccount>();
-    let (filter_tx_slots, filter_rx_slots) = flume::unbounded::<UpdateSlotStatus>();
-    let (filter_tx_block, filter_rx_block) = flume::unbounded::<NotifyBlockMetaData>();
+        tokio::select! {
+            _ = async { let _ = tokio::join!(consumers, db_maintenance, prometheus); } => (),
+            _ = shutdown_signal() => {
+                info!("Shutting down, uncommitted batches will be read again on startup");
+            }
+        }
+        // Flushing the exporter blocks
+        let _ = tokio::task::spawn_blocking(shutdown_tracing).await;
+        return;
+    }
 
-    let account_filter = tokio::spawn(account_filter(
-        config.clone(),
-        db_account_queue.clone(),
-        filter_rx_account,
+    let mut sink_queues = Vec::new();
+    let mut sink_executors = Vec::new();
+    let mut sink_list = Vec::new();
+    let mut postgres_enabled = false;
+    let mut queue_gauges = Vec::new();
+
+    for sink_config in &config.sinks {
+        let sink: Arc<dyn Sink> = match sink_config {
+            // A sqlite:// connection string selects the embedded backend
+            SinkConfig::Postgres if config.postgres_connection_str.starts_with(SQLITE_SCHEME) => {
+                Arc::new(
+                    SqliteSink::open(&config.postgres_connection_str, config.persistence_mode)
+                        .unwrap_or_else(|e| {
+                            panic!("Failed to open the SQLite database, error: {e}")
+                        }),
+                )
+            }
+            SinkConfig::Postgres if config.slot_atomic => {
+                postgres_enabled = true;
+                Arc::new(
+                    SlotAtomicSink::new(
+                        config.clone(),
+                        quarantine.clone(),
+                        missing_partitions.clone(),
+                    )
+                    .await,
+                )
+            }
+            SinkConfig::Postgres => {
+                postgres_enabled = true;
+                Arc::new(PostgresSink::new(config.clone(), missing_partitions.clone()).await)
+            }
+            SinkConfig::ClickHouse(clickhouse_config) => {
+                Arc::new(ClickHouseSink::new(clickhouse_config.clone()))
+            }
+            SinkConfig::Kafka(kafka_config) => {
+                Arc::new(KafkaSink::new(&config, kafka_config.clone()))
+            }
+            SinkConfig::Parquet(parquet_config) => {
+                Arc::new(ParquetSink::new(parquet_config.clone()))
+            }
+            SinkConfig::Webhook(webhook_config) => {
+                Arc::new(WebhookSink::new(webhook_config.clone()))
+            }
+        };
+
+        if sink_list
+            .iter()
+            .any(|s: &Arc<dyn Sink>| s.name() == sink.name())
+        {
+            panic!("There is more than one sink named {}", sink.name());
+        }
+
+        let queues = Arc::new(SinkQueues::new(
+            &ctx_stats.stats,
+            sink.name(),
+            sink.republishes_accounts(),
+        ));
+        if let Some(spill_queue_path) = &config.spill_queue_path {
+            spawn_spill_workers(&config, spill_queue_path, sink.name(), &queues);
+        }
+
+        info!("Writing the filtered rows to the {} sink", sink.name());
+        health.add_sink_queues(sink.name(), queues.clone());
+        queue_gauges.extend(sink_queue_gauges(sink.name(), &queues));
+        sink_list.push(sink.clone());
+        sink_executors.push(tokio::spawn(health.watch(
+            format!("sink_executor_{}", sink.name()),
+            sink_executor(config.clone(), sink, queues.clone(), quarantine.clone()),
+        )));
+        sink_queues.push(queues);
+    }
+
+    if sink_queues.is_empty() {
+        panic!("No sinks are configured");
+    }
+
+    let (filter_tx_account, filter_rx_account) = flume::unbounded::<Consumed<UpdateAccount>>();
+    let (filter_tx_slots, filter_rx_slots) = flume::unbounded::<Consumed<UpdateSlotStatus>>();
+    let (filter_tx_block, filter_rx_block) = flume::unbounded::<Consumed<NotifyBlockMetaData>>();
+
+    // With coalescing enabled the account filter feeds the coalescer instead of the sink queues
+    let coalescer_queue =
+        (config.account_coalesce_window_ms > 0).then(|| Arc::new(SegQueue::new()));
+
+    let (account_tx, slot_tx, block_tx) = (
+        filter_tx_account.clone(),
+        filter_tx_slots.clone(),
+        filter_tx_block.clone(),
+    );
+    queue_gauges.push(QueueGauge::new(
+        &[("queue", "filter"), ("table", "account")],
+        move || account_tx.len(),
+    ));
+    queue_gauges.push(QueueGauge::new(
+        &[("queue", "filter"), ("table", "slot")],
+        move || slot_tx.len(),
+    ));
+    queue_gauges.push(QueueGauge::new(
+        &[("queue", "filter"), ("table", "block")],
+        move || block_tx.len(),
     ));
+    if let Some(coalescer_queue) = coalescer_queue.clone() {
+        queue_gauges.push(QueueGauge::new(
+            &[("queue", "coalescer"), ("table", "account")],
+            move || coalescer_queue.len(),
+        ));
+    }
+    tokio::spawn(sample_queue_lengths(ctx_stats.stats.clone(), queue_gauges));
+    tokio::spawn(sample_slot_lag(
+        ctx_stats.stats.clone(),
+        sink_queues.clone(),
+    ));
+    let sinks = Arc::new(Sinks::new(sink_queues, coalescer_queue.clone()));
 
-    let block_filter = tokio::spawn(block_filter(db_block_queue.clone(), filter_rx_block));
+    if let Some(coalescer_queue) = coalescer_queue {
+        tokio::spawn(health.watch(
+            "account_coalescer",
+            account_coalescer(
+                Duration::from_millis(config.account_coalesce_window_ms),
+                coalescer_queue,
+                sinks.clone(),
+                config.account_coalesce_keep_history,
+            ),
+        ));
+    }
 
-    let slot_filter = tokio::spawn(slot_filter(db_slot_queue.clone(), filter_rx_slots));
+    // Subscriptions and streams are fed by the filters, the exactly once mode has neither
+    let pubsub = config.pubsub_port.map(|pubsub_port| {
+        let pubsub = Arc::new(PubSub::new(config.pubsub_connection_buffer));
+        tokio::spawn(start_pubsub_server(pubsub.clone(), pubsub_port));
+        pubsub
+    });
 
-    let consumer_update_account = tokio::spawn(consumer(
-        config.clone(),
-        update_account_topic,
-        filter_tx_account,
-        ctx_stats.clone(),
+    let grpc = config.grpc_port.map(|grpc_port| {
+        let streams = Arc::new(GrpcStreams::new(config.grpc_stream_buffer));
+        tokio::spawn(start_grpc_server(
+            config.clone(),
+            streams.clone(),
+            grpc_port,
+        ));
+        streams
+    });
+
+    let account_filter = tokio::spawn(health.watch(
+        "account_filter",
+        account_filter(
+            config.clone(),
+            sinks.clone(),
+            pubsub.clone(),
+            grpc.clone(),
+            ctx_stats.stats.clone(),
+            ctx_stats.filter_metrics.clone(),
+            filter_rx_account,
+        ),
+    ));
+
+    let block_filter = tokio::spawn(health.watch(
+        "block_filter",
+        block_filter(
+            sinks.clone(),
+            grpc.clone(),
+            ctx_stats.stats.clone(),
+            filter_rx_block,
+        ),
+    ));
+
+    // The highest slot received from Kafka
+    let chain_tip = Arc::new(AtomicU64::new(0));
+
+    let slot_filter = tokio::spawn(health.watch(
+        "slot_filter",
+        slot_filter(
+            sinks,
+            pubsub,
+            grpc,
+            chain_tip.clone(),
+            ctx_stats.stats.clone(),
+            filter_rx_slots,
+        ),
     ));
 
-    let consumer_update_slot = tokio::spawn(consumer(
-        config.clone(),
-        update_slot_topic,
-        filter_tx_slots,
-        ctx_stats.clone(),
+    // Partitions and retention are managed only for the Postgres sink
+    let db_maintenance = postgres_enabled.then(|| {
+        health.check_database(config.clone());
+        tokio::spawn(health.watch(
+            "db_maintenance",
+            db_maintenance(
+                config.clone(),
+                ctx_stats.stats.clone(),
+                chain_tip,
+                missing_partitions,
+            ),
+        ))
+    });
+
+    let consumer_update_account = tokio::spawn(health.watch(
+        "consumer_update_account",
+        consumer(
+            config.clone(),
+            update_account_topic,
+            filter_tx_account,
+            ctx_stats.clone(),
+        ),
     ));
 
-    let consumer_notify_block = tokio::spawn(consumer(
-        config.clone(),
-        notify_block_topic,
-        filter_tx_block,
-        ctx_stats,
+    let consumer_update_slot = tokio::spawn(health.watch(
+        "consumer_update_slot",
+        consumer(
+            config.clone(),
+            update_slot_topic,
+            filter_tx_slots,
+            ctx_stats.clone(),
+        ),
     ));
 
-    let db_stmt_executor = tokio::spawn(db_stmt_executor(
-        config.clone(),
-        client,
-        db_account_queue,
-        db_block_queue,
-        db_slot_queue,
+    let consumer_notify_block = tokio::spawn(health.watch(
+        "consumer_notify_block",
+        consumer(
+            config.clone(),
+            notify_block_topic,
+            filter_tx_block,
+            ctx_stats,
+        ),
     ));
 
-    let _ = tokio::join!(
-        consumer_update_account,
-        consumer_update_slot,
-        consumer_notify_block,
-        account_filter,
-        block_filter,
-        slot_filter,
-        db_stmt_executor,
-        prometheus
+    let sink_executors = tokio::spawn(async move {
+        for sink_executor in sink_executors {
+            let _ = sink_executor.await;
+        }
+    });
+
+    let db_maintenance = tokio::spawn(async move {
+        if let Some(db_maintenance) = db_maintenance {
+            let _ = db_maintenance.await;
+        }
+    });
+
+    let tasks = async {
+        let _ = tokio::join!(
+            consumer_update_account,
+            consumer_update_slot,
+            consumer_notify_block,
+            account_filter,
+            block_filter,
+            slot_filter,
+            sink_executors,
+            db_maintenance,
+            prometheus
+        );
+    };
+
+    tokio::select! {
+        _ = tasks => (),
+        _ = shutdown_signal() => {
+            info!("Shutting down, closing the sinks");
+            for sink in &sink_list {
+                sink.close().await;
+            }
+        }
+    }
+    // Flushing the exporter blocks
+    let _ = tokio::task::spawn_blocking(shutdown_tracing).await;
+}
+
+// The spilled rows and the rows waiting for a retry count towards the length of the queue
+fn sink_queue_gauges(sink: &str, queues: &Arc<SinkQueues>) -> Vec<QueueGauge> {
+    let (accounts, account_history, blocks, slots) = (
+        queues.accounts.clone(),
+        queues.account_history.clone(),
+        queues.blocks.clone(),
+        queues.slots.clone(),
     );
+    let metrics = &queues.metrics;
+    let (accounts_metrics, account_history_metrics, blocks_metrics, slots_metrics) = (
+        metrics.accounts.clone(),
+        metrics.account_history.clone(),
+        metrics.blocks.clone(),
+        metrics.slots.clone(),
+    );
+
+    vec![
+        QueueGauge::new(
+            &[("queue", "sink"), ("sink", sink), ("table", "account")],
+            move || accounts.len() + accounts_metrics.off_queue(),
+        ),
+        QueueGauge::new(
+            &[
+                ("queue", "sink"),
+                ("sink", sink),
+                ("table", "account_audit"),
+            ],
+            move || account_history.len() + account_history_metrics.off_queue(),
+        ),
+        QueueGauge::new(
+            &[("queue", "sink"), ("sink", sink), ("table", "block")],
+            move || blocks.len() + blocks_metrics.off_queue(),
+        ),
+        QueueGauge::new(
+            &[("queue", "sink"), ("sink", sink), ("table", "slot")],
+            move || slots.len() + slots_metrics.off_queue(),
+        ),
+    ]
+}
+
+async fn shutdown_signal() {
+    let mut terminate =
+        signal(SignalKind::terminate()).expect("Failed to install the SIGTERM handler");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+}
+
+// The config file if one is given, the environment otherwise
+async fn read_config(config_path: Option<&String>, to_stderr: bool) -> Option<FilterConfig> {
+    let Some(config_path) = config_path else {
+        return Some(env_build_config());
+    };
+
+    if to_stderr {
+        eprintln!("Trying to read the config file: {config_path}");
+    } else {
+        println!("Trying to read the config file: {config_path}");
+    }
+
+    let contents = fs::read_to_string(config_path)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to read config: {config_path}, error: {e}"));
+
+    let result: serde_json::Result<FilterConfig> = serde_json::from_str(&contents);
+    match result {
+        Ok(config) => Some(config),
+        Err(e) => {
+            eprintln!("Failed to parse filter config, error {e}");
+            error!("Failed to parse filter config, error {e}");
+            None
+        }
+    }
 }
 
 #[tokio::main]
@@ -151,28 +534,51 @@ async fn main() {
                 .value_name("Config path")
                 .help("Sets the path to the config file"),
         )
+        .subcommand(
+            Command::new("accounts-at-slot")
+                .about("Prints the state of accounts at a slot from account_audit")
+                .arg(
+                    Arg::new("slot")
+                        .short('s')
+                        .long("slot")
+                        .required(true)
+                        .value_parser(clap::value_parser!(u64))
+                        .help("The slot the latest versions at or before are printed"),
+                )
+                .arg(
+                    Arg::new("pubkeys")
+                        .required(true)
+                        .num_args(1..)
+                        .value_name("Pubkey")
+                        .help("Base58 pubkeys of the accounts"),
+                ),
+        )
         .get_matches();
 
-    println!("{}", get_build_info());
+    // stderr keeps the output of the commands clean
+    let to_stderr = app.subcommand().is_some();
+    if !to_stderr {
+        println!("{}", get_build_info());
+    }
 
-    if let Some(config_path) = app.get_one::<String>("config") {
-        println!("Trying to read the config file: {config_path}");
+    let Some(config) = read_config(app.get_one::<String>("config"), to_stderr).await else {
+        return;
+    };
 
-        let contents = fs::read_to_string(config_path)
-            .await
-            .unwrap_or_else(|e| panic!("Failed to read config: {config_path}, error: {e}"));
+    match app.subcommand() {
+        Some(("accounts-at-slot", command)) => {
+            let slot = *command.get_one::<u64>("slot").expect("slot is required");
+            let pubkeys: Vec<String> = command
+                .get_many::<String>("pubkeys")
+                .expect("pubkeys are required")
+                .cloned()
+                .collect();
 
-        let result: serde_json::Result<FilterConfig> = serde_json::from_str(&contents);
-        match result {
-            Ok(config) => {
-                run(config).await;
-            }
-            Err(e) => {
-                eprintln!("Failed to parse filter config, error {e}");
-                error!("Failed to parse filter config, error {e}");
+            if let Err(e) = print_accounts_at_slot(config, slot, &pubkeys).await {
+                eprintln!("Failed to read the accounts, error: {e}");
+                std::process::exit(1);
             }
         }
-    } else {
-        run(env_build_config()).await;
+        _ => run(config).await,
     }
 }
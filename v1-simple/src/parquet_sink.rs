@@ -0,0 +1,493 @@
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Result;
+use arrow_array::ArrayRef;
+use arrow_array::BinaryArray;
+use arrow_array::BooleanArray;
+use arrow_array::Int64Array;
+use arrow_array::RecordBatch;
+use arrow_array::StringArray;
+use arrow_array::TimestampMillisecondArray;
+use arrow_schema::DataType;
+use arrow_schema::Field;
+use arrow_schema::Schema;
+use arrow_schema::SchemaRef;
+use arrow_schema::TimeUnit;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use chrono::Utc;
+use kafka_common::kafka_structs::UpdateSlotStatus;
+use log::error;
+use log::info;
+use log::warn;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::basic::ZstdLevel;
+use parquet::file::properties::WriterProperties;
+
+use crate::config::ParquetCompression;
+use crate::config::ParquetPartitioning;
+use crate::config::ParquetSinkConfig;
+use crate::db::DbAccountInfo;
+use crate::db::DbBlockInfo;
+use crate::sink::Sink;
+use crate::sink::WriteHold;
+
+fn timestamp_field(name: &str) -> Field {
+    Field::new(
+        name,
+        DataType::Timestamp(TimeUnit::Millisecond, None),
+        false,
+    )
+}
+
+fn account_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("pubkey", DataType::Binary, false),
+        Field::new("lamports", DataType::Int64, false),
+        Field::new("owner", DataType::Binary, false),
+        Field::new("executable", DataType::Boolean, false),
+        Field::new("rent_epoch", DataType::Int64, false),
+        Field::new("data", DataType::Binary, false),
+        Field::new("slot", DataType::Int64, false),
+        Field::new("write_version", DataType::Int64, false),
+        Field::new("txn_signature", DataType::Binary, true),
+        Field::new("is_startup", DataType::Boolean, false),
+        timestamp_field("retrieved_time"),
+    ]))
+}
+
+fn block_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("slot", DataType::Int64, false),
+        Field::new("blockhash", DataType::Utf8, false),
+        // JSON array of the rewards
+        Field::new("rewards", DataType::Utf8, false),
+        Field::new("block_time", DataType::Int64, true),
+        Field::new("block_height", DataType::Int64, true),
+        timestamp_field("retrieved_time"),
+    ]))
+}
+
+fn slot_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("slot", DataType::Int64, false),
+        Field::new("parent", DataType::Int64, true),
+        Field::new("status", DataType::Utf8, false),
+        timestamp_field("retrieved_time"),
+    ]))
+}
+
+fn account_batch(schema: &SchemaRef, accounts: &[DbAccountInfo]) -> Result<RecordBatch> {
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(BinaryArray::from_iter_values(
+            accounts.iter().map(|a| &a.pubkey),
+        )),
+        Arc::new(Int64Array::from_iter_values(
+            accounts.iter().map(|a| a.lamports),
+        )),
+        Arc::new(BinaryArray::from_iter_values(
+            accounts.iter().map(|a| &a.owner),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            accounts.iter().map(|a| Some(a.executable)),
+        )),
+        Arc::new(Int64Array::from_iter_values(
+            accounts.iter().map(|a| a.rent_epoch),
+        )),
+        Arc::new(BinaryArray::from_iter_values(
+            accounts.iter().map(|a| &a.data),
+        )),
+        Arc::new(Int64Array::from_iter_values(
+            accounts.iter().map(|a| a.slot),
+        )),
+        Arc::new(Int64Array::from_iter_values(
+            accounts.iter().map(|a| a.write_version),
+        )),
+        Arc::new(BinaryArray::from_iter(
+            accounts.iter().map(|a| a.txn_signature.as_ref()),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            accounts.iter().map(|a| Some(a.is_startup)),
+        )),
+        Arc::new(TimestampMillisecondArray::from_iter_values(
+            accounts
+                .iter()
+                .map(|a| a.retrieved_time.and_utc().timestamp_millis()),
+        )),
+    ];
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+fn block_batch(schema: &SchemaRef, blocks: &[DbBlockInfo]) -> Result<RecordBatch> {
+    let rewards = blocks
+        .iter()
+        .map(|b| serde_json::to_string(&b.rewards))
+        .collect::<serde_json::Result<Vec<_>>>()?;
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(Int64Array::from_iter_values(blocks.iter().map(|b| b.slot))),
+        Arc::new(StringArray::from_iter_values(
+            blocks.iter().map(|b| &b.blockhash),
+        )),
+        Arc::new(StringArray::from_iter_values(rewards)),
+        Arc::new(Int64Array::from_iter(blocks.iter().map(|b| b.block_time))),
+        Arc::new(Int64Array::from_iter(blocks.iter().map(|b| b.block_height))),
+        Arc::new(TimestampMillisecondArray::from_iter_values(
+            blocks
+                .iter()
+                .map(|b| b.retrieved_time.and_utc().timestamp_millis()),
+        )),
+    ];
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+fn slot_batch(schema: &SchemaRef, slots: &[UpdateSlotStatus]) -> Result<RecordBatch> {
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(Int64Array::from_iter_values(
+            slots.iter().map(|s| s.slot as i64),
+        )),
+        Arc::new(Int64Array::from_iter(
+            slots.iter().map(|s| s.parent.map(|p| p as i64)),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            slots.iter().map(|s| s.status.to_string()),
+        )),
+        Arc::new(TimestampMillisecondArray::from_iter_values(
+            slots
+                .iter()
+                .map(|s| s.retrieved_time.and_utc().timestamp_millis()),
+        )),
+    ];
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+const FILE_AGE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Files left under their temporary name by a crash, their rows were never acknowledged
+// so they are read again from Kafka or the spill queue
+fn remove_incomplete_files(dir: &Path) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            remove_incomplete_files(&path);
+        } else if path.to_string_lossy().ends_with(".parquet.tmp") {
+            warn!("Removing incomplete parquet file {}", path.display());
+            if let Err(e) = fs::remove_file(&path) {
+                error!("Failed to remove {}, error: {e}", path.display());
+            }
+        }
+    }
+}
+
+struct OpenFile {
+    partition: String,
+    // The file is written under a temporary name and renamed once it is complete
+    tmp_path: PathBuf,
+    path: PathBuf,
+    writer: ArrowWriter<File>,
+    rows: usize,
+    opened: Instant,
+    // The writer buffers the rows until the file is closed, so are their acknowledgements
+    holds: Vec<Arc<WriteHold>>,
+}
+
+impl OpenFile {
+    fn close(self) -> Result<()> {
+        let result = Self::complete(self.writer, &self.tmp_path, &self.path);
+        match &result {
+            Ok(()) => info!(
+                "Closed parquet file {} with {} rows",
+                self.path.display(),
+                self.rows
+            ),
+            Err(_) => self.holds.iter().for_each(|hold| hold.abandon()),
+        }
+        result
+    }
+
+    fn complete(writer: ArrowWriter<File>, tmp_path: &Path, path: &Path) -> Result<()> {
+        let file = writer.into_inner()?;
+        file.sync_data()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+}
+
+// Rolling files of one table, there is at most one open file at a time
+struct TableWriter {
+    table: &'static str,
+    schema: SchemaRef,
+    file: Option<OpenFile>,
+}
+
+impl TableWriter {
+    fn new(table: &'static str, schema: SchemaRef) -> Self {
+        Self {
+            table,
+            schema,
+            file: None,
+        }
+    }
+
+    fn open(&self, config: &ParquetSinkConfig, partition: &str) -> Result<OpenFile> {
+        let dir = Path::new(&config.path).join(self.table).join(partition);
+        fs::create_dir_all(&dir)?;
+
+        let name = format!("{}-{}", self.table, Utc::now().timestamp_micros());
+        let path = dir.join(format!("{name}.parquet"));
+        let tmp_path = dir.join(format!("{name}.parquet.tmp"));
+
+        let compression = match config.compression {
+            ParquetCompression::Uncompressed => Compression::UNCOMPRESSED,
+            ParquetCompression::Snappy => Compression::SNAPPY,
+            ParquetCompression::Zstd => Compression::ZSTD(ZstdLevel::default()),
+        };
+        let properties = WriterProperties::builder()
+            .set_max_row_group_size(config.row_group_size)
+            .set_compression(compression)
+            .build();
+
+        let writer = ArrowWriter::try_new(
+            File::create(&tmp_path)?,
+            self.schema.clone(),
+            Some(properties),
+        )?;
+
+        Ok(OpenFile {
+            partition: partition.to_string(),
+            tmp_path,
+            path,
+            writer,
+            rows: 0,
+            opened: Instant::now(),
+            holds: Vec::new(),
+        })
+    }
+
+    fn close(&mut self) -> Result<()> {
+        match self.file.take() {
+            Some(file) => file.close(),
+            None => Ok(()),
+        }
+    }
+
+    // Closes the file once it reaches its maximum age, even if no rows arrive anymore
+    fn roll_expired(&mut self, max_age: Duration) -> Result<()> {
+        match &self.file {
+            Some(file) if file.opened.elapsed() >= max_age => self.close(),
+            _ => Ok(()),
+        }
+    }
+
+    fn write(
+        &mut self,
+        config: &ParquetSinkConfig,
+        partition: &str,
+        batch: &RecordBatch,
+        hold: &Arc<WriteHold>,
+    ) -> Result<()> {
+        let max_age = Duration::from_secs(config.max_file_age_secs);
+        let roll = match &self.file {
+            Some(file) => {
+                file.partition != partition
+                    || file.rows >= config.max_file_rows
+                    || file.opened.elapsed() >= max_age
+            }
+            None => false,
+        };
+        if roll {
+            self.close()?;
+        }
+
+        if self.file.is_none() {
+            self.file = Some(self.open(config, partition)?);
+        }
+
+        if let Some(file) = self.file.as_mut() {
+            if let Err(e) = file.writer.write(batch) {
+                // Keep what was written before, the failed rows are retried in a new file
+                if let Err(e) = self.close() {
+                    warn!("Failed to close {} parquet file, error: {e}", self.table);
+                }
+                return Err(e.into());
+            }
+            file.rows += batch.num_rows();
+            file.holds.push(hold.clone());
+        }
+
+        Ok(())
+    }
+}
+
+// Writes the rows to rolling Parquet files partitioned by slot range or date
+pub struct ParquetSink {
+    config: ParquetSinkConfig,
+    accounts: Arc<Mutex<TableWriter>>,
+    blocks: Arc<Mutex<TableWriter>>,
+    slots: Arc<Mutex<TableWriter>>,
+}
+
+impl ParquetSink {
+    pub fn new(config: ParquetSinkConfig) -> Self {
+        remove_incomplete_files(Path::new(&config.path));
+
+        let sink = Self {
+            config,
+            accounts: Arc::new(Mutex::new(TableWriter::new("account", account_schema()))),
+            blocks: Arc::new(Mutex::new(TableWriter::new("block", block_schema()))),
+            slots: Arc::new(Mutex::new(TableWriter::new("slot", slot_schema()))),
+        };
+
+        let max_age = Duration::from_secs(sink.config.max_file_age_secs);
+        let writers = [
+            sink.accounts.clone(),
+            sink.blocks.clone(),
+            sink.slots.clone(),
+        ];
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FILE_AGE_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                for writer in &writers {
+                    tokio::task::block_in_place(|| {
+                        let mut writer = writer.lock().expect("Parquet writer lock is poisoned");
+                        if let Err(e) = writer.roll_expired(max_age) {
+                            error!("Failed to close {} parquet file, error: {e}", writer.table);
+                        }
+                    });
+                }
+            }
+        });
+
+        sink
+    }
+
+    fn partition(&self, slot: i64, retrieved_time: &NaiveDateTime) -> String {
+        match self.config.partitioning {
+            ParquetPartitioning::SlotRange => {
+                let size = self.config.partition_size_slots.max(1) as i64;
+                format!("slots={}", slot / size * size)
+            }
+            ParquetPartitioning::Date => format!("date={}", retrieved_time.format("%Y-%m-%d")),
+        }
+    }
+
+    // Consecutive rows of the same partition are written as one record batch
+    fn write<T>(
+        &self,
+        writer: &Mutex<TableWriter>,
+        rows: &[T],
+        hold: &Arc<WriteHold>,
+        partition_of: impl Fn(&T) -> String,
+        to_batch: impl Fn(&SchemaRef, &[T]) -> Result<RecordBatch>,
+    ) -> Result<()> {
+        tokio::task::block_in_place(|| {
+            let mut writer = writer.lock().expect("Parquet writer lock is poisoned");
+            let mut start = 0;
+
+            while start < rows.len() {
+                let partition = partition_of(&rows[start]);
+                let end = rows[start..]
+                    .iter()
+                    .position(|row| partition_of(row) != partition)
+                    .map_or(rows.len(), |len| start + len);
+
+                let batch = to_batch(&writer.schema, &rows[start..end])?;
+                writer.write(&self.config, &partition, &batch, hold)?;
+                start = end;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for ParquetSink {
+    fn name(&self) -> &str {
+        "parquet"
+    }
+
+    fn batch_size(&self) -> usize {
+        self.config.row_group_size
+    }
+
+    async fn write_accounts(&self, accounts: &[DbAccountInfo]) -> Result<()> {
+        self.write_accounts_held(accounts, &Arc::default()).await
+    }
+
+    async fn write_blocks(&self, blocks: &[DbBlockInfo]) -> Result<()> {
+        self.write_blocks_held(blocks, &Arc::default()).await
+    }
+
+    async fn write_slots(&self, slots: &[UpdateSlotStatus]) -> Result<()> {
+        self.write_slots_held(slots, &Arc::default()).await
+    }
+
+    async fn write_accounts_held(
+        &self,
+        accounts: &[DbAccountInfo],
+        hold: &Arc<WriteHold>,
+    ) -> Result<()> {
+        self.write(
+            &self.accounts,
+            accounts,
+            hold,
+            |a| self.partition(a.slot, &a.retrieved_time),
+            account_batch,
+        )
+    }
+
+    async fn write_account_history_held(
+        &self,
+        accounts: &[DbAccountInfo],
+        hold: &Arc<WriteHold>,
+    ) -> Result<()> {
+        self.write_accounts_held(accounts, hold).await
+    }
+
+    async fn write_blocks_held(&self, blocks: &[DbBlockInfo], hold: &Arc<WriteHold>) -> Result<()> {
+        self.write(
+            &self.blocks,
+            blocks,
+            hold,
+            |b| self.partition(b.slot, &b.retrieved_time),
+            block_batch,
+        )
+    }
+
+    async fn write_slots_held(
+        &self,
+        slots: &[UpdateSlotStatus],
+        hold: &Arc<WriteHold>,
+    ) -> Result<()> {
+        self.write(
+            &self.slots,
+            slots,
+            hold,
+            |s| self.partition(s.slot as i64, &s.retrieved_time),
+            slot_batch,
+        )
+    }
+
+    async fn close(&self) {
+        for writer in [&self.accounts, &self.blocks, &self.slots] {
+            let mut writer = writer.lock().expect("Parquet writer lock is poisoned");
+            if let Err(e) = writer.close() {
+                error!("Failed to close {} parquet file, error: {e}", writer.table);
+            }
+        }
+    }
+}
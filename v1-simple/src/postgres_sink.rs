@@ -0,0 +1,206 @@
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use ahash::AHashMap;
+use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::future::try_join_all;
+use kafka_common::kafka_structs::UpdateSlotStatus;
+use log::warn;
+use serde::Serialize;
+use tokio_postgres::Client;
+use tokio_postgres::Row;
+
+use crate::config::FilterConfig;
+use crate::db::initialize_db_client;
+use crate::db::DbAccountInfo;
+use crate::db::DbBlockInfo;
+use crate::db_inserts::insert_account_batch;
+use crate::db_inserts::insert_into_block_metadata;
+use crate::db_inserts::insert_slot_status_internal;
+use crate::db_maintenance::MissingPartitions;
+use crate::db_retry::classify_postgres_error;
+use crate::db_retry::ErrorKind;
+use crate::db_statements::create_account_batch_statement;
+use crate::db_statements::create_account_history_batch_statement;
+use crate::db_statements::create_block_metadata_insert_statement;
+use crate::db_statements::create_slot_insert_statement_with_parent;
+use crate::db_statements::create_slot_insert_statement_without_parent;
+use crate::db_statements::ACCOUNT_NOTIFY;
+use crate::sink::Sink;
+
+// NOTIFY payloads must be shorter than 8000 bytes
+const NOTIFY_PAYLOAD_LIMIT: usize = 7900;
+
+#[derive(Serialize)]
+struct AccountChange {
+    pubkey: String,
+    slot: i64,
+    write_version: i64,
+}
+
+// Sends the latest version of every changed account of a batch as JSON arrays,
+// split into as many notifications as the payload limit requires.
+// `changed` are the pubkey, slot and write_version rows returned by the batch statement.
+async fn notify_account_changes(client: &Client, channel: &str, changed: &[Row]) -> Result<()> {
+    let mut latest: AHashMap<&[u8], (i64, i64)> = AHashMap::with_capacity(changed.len());
+    for row in changed {
+        let version = (row.try_get(1)?, row.try_get(2)?);
+        latest
+            .entry(row.try_get(0)?)
+            .and_modify(|current| *current = version.max(*current))
+            .or_insert(version);
+    }
+
+    let mut payloads = Vec::new();
+    let mut payload = String::from("[");
+    for (pubkey, (slot, write_version)) in latest {
+        let change = serde_json::to_string(&AccountChange {
+            pubkey: bs58::encode(pubkey).into_string(),
+            slot,
+            write_version,
+        })?;
+
+        if payload.len() > 1 && payload.len() + change.len() + 2 > NOTIFY_PAYLOAD_LIMIT {
+            payload.push(']');
+            payloads.push(std::mem::replace(&mut payload, String::from("[")));
+        }
+        if payload.len() > 1 {
+            payload.push(',');
+        }
+        payload.push_str(&change);
+    }
+    payload.push(']');
+    payloads.push(payload);
+
+    for payload in payloads {
+        client
+            .execute(ACCOUNT_NOTIFY, &[&channel, &payload])
+            .await?;
+    }
+    Ok(())
+}
+
+// Writes the accounts of a batch with a single statement.
+// Blocks and slots get a statement per row, executed concurrently, their upserts can be repeated safely.
+pub struct PostgresSink {
+    config: Arc<FilterConfig>,
+    client: RwLock<Arc<Client>>,
+    missing_partitions: Arc<MissingPartitions>,
+}
+
+impl PostgresSink {
+    pub async fn new(
+        config: Arc<FilterConfig>,
+        missing_partitions: Arc<MissingPartitions>,
+    ) -> Self {
+        let client = initialize_db_client(config.clone()).await;
+        Self {
+            config,
+            client: RwLock::new(client),
+            missing_partitions,
+        }
+    }
+
+    fn client(&self) -> Arc<Client> {
+        self.client
+            .read()
+            .expect("Postgres client lock is poisoned")
+            .clone()
+    }
+}
+
+#[async_trait]
+impl Sink for PostgresSink {
+    fn name(&self) -> &str {
+        "postgres"
+    }
+
+    fn batch_size(&self) -> usize {
+        self.config.postgres_batch_size
+    }
+
+    fn max_concurrent_writes(&self) -> Option<usize> {
+        None
+    }
+
+    async fn ensure_connected(&self) {
+        if self.client().is_closed() {
+            warn!("Postgres client was unexpectedly closed");
+            let client = initialize_db_client(self.config.clone()).await;
+            *self
+                .client
+                .write()
+                .expect("Postgres client lock is poisoned") = client;
+        }
+    }
+
+    fn classify_error(&self, error: &anyhow::Error) -> ErrorKind {
+        let kind = classify_postgres_error(error);
+        if kind == ErrorKind::MissingPartition {
+            self.missing_partitions.report(error);
+        }
+        kind
+    }
+
+    async fn write_accounts(&self, accounts: &[DbAccountInfo]) -> Result<()> {
+        let client = self.client();
+        let statement =
+            create_account_batch_statement(client.clone(), self.config.persistence_mode).await?;
+
+        let changed = insert_account_batch(accounts, &statement, client.clone()).await?;
+
+        // The rows are already written and a retry would find nothing to notify about,
+        // so a failed notification is lost, as documented for account_notify_channel
+        if let Some(channel) = &self.config.account_notify_channel {
+            if !changed.is_empty() {
+                if let Err(e) = notify_account_changes(&client, channel, &changed).await {
+                    warn!("Failed to notify channel {channel} about {} changed account(s), the notification is lost, error: {e}", changed.len());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_account_history(&self, accounts: &[DbAccountInfo]) -> Result<()> {
+        if !self.config.persistence_mode.writes_history() {
+            return Ok(());
+        }
+
+        let client = self.client();
+        let statement = create_account_history_batch_statement(client.clone()).await?;
+
+        insert_account_batch(accounts, &statement, client).await?;
+        Ok(())
+    }
+
+    async fn write_blocks(&self, blocks: &[DbBlockInfo]) -> Result<()> {
+        let client = self.client();
+        let statement = create_block_metadata_insert_statement(client.clone()).await?;
+
+        try_join_all(
+            blocks
+                .iter()
+                .map(|block| insert_into_block_metadata(block, &statement, client.clone())),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn write_slots(&self, slots: &[UpdateSlotStatus]) -> Result<()> {
+        let client = self.client();
+
+        try_join_all(slots.iter().map(|slot| {
+            let client = client.clone();
+            async move {
+                let statement = match slot.parent {
+                    Some(_) => create_slot_insert_statement_with_parent(client.clone()).await?,
+                    None => create_slot_insert_statement_without_parent(client.clone()).await?,
+                };
+                insert_slot_status_internal(slot, &statement, client).await
+            }
+        }))
+        .await?;
+        Ok(())
+    }
+}
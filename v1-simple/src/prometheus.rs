@@ -9,15 +9,20 @@ use std::{
 
 use hyper::{
     service::{make_service_fn, service_fn},
-    Body, Request, Response, Server,
+    Body, Request, Response, Server, StatusCode,
 };
 use prometheus_client::{encoding::text::encode, registry::Registry};
+use serde_json::{json, Value};
 use tokio::signal::unix::{signal, SignalKind};
 
-use crate::consumer_stats::Stats;
+use crate::{consumer_stats::Stats, filter_metrics::FilterMetrics, health::Health};
+
+const DEFAULT_HOT_PUBKEYS_LIMIT: usize = 20;
 
 pub async fn start_prometheus(
     stats: Arc<Stats>,
+    health: Arc<Health>,
+    filter_metrics: Arc<FilterMetrics>,
     update_account_topic: Option<String>,
     update_slot_topic: Option<String>,
     notify_block_topic: Option<String>,
@@ -43,6 +48,132 @@ pub async fn start_prometheus(
         Box::new(stats.kafka_errors_deserialize.clone()),
     );
 
+    registry.register(
+        "db_partitions_created",
+        "How many slot range partitions were created",
+        Box::new(stats.db_partitions_created.clone()),
+    );
+
+    registry.register(
+        "db_partitions_removed",
+        "How many partitions were dropped or detached by the retention policy",
+        Box::new(stats.db_partitions_removed.clone()),
+    );
+
+    registry.register(
+        "db_maintenance_errors",
+        "How many partition maintenance errors occurred",
+        Box::new(stats.db_maintenance_errors.clone()),
+    );
+
+    registry.register(
+        "db_retention_cutoff_slot",
+        "Partitions ending at or below this slot are removed",
+        Box::new(stats.db_retention_cutoff_slot.clone()),
+    );
+
+    registry.register(
+        "queue_length",
+        "How many items are waiting in a channel or queue of the pipeline",
+        Box::new(stats.queue_length.clone()),
+    );
+
+    registry.register(
+        "queue_pushed",
+        "How many rows were pushed to the queue of a sink",
+        Box::new(stats.queue_pushed.clone()),
+    );
+
+    registry.register(
+        "queue_popped",
+        "How many rows were taken from the queue of a sink for writing, retries included",
+        Box::new(stats.queue_popped.clone()),
+    );
+
+    registry.register(
+        "queue_requeued",
+        "How many rows were put back for a retry after a failed write",
+        Box::new(stats.queue_requeued.clone()),
+    );
+
+    registry.register(
+        "sink_rows_written",
+        "How many rows were written to a sink",
+        Box::new(stats.sink_rows_written.clone()),
+    );
+
+    registry.register(
+        "sink_rows_failed",
+        "How many rows were given up on after failed writes",
+        Box::new(stats.sink_rows_failed.clone()),
+    );
+
+    registry.register(
+        "latency_kafka_to_filter_seconds",
+        "Time from the Kafka message timestamp to the filter receiving the message",
+        Box::new(stats.latency.kafka_to_filter.clone()),
+    );
+
+    registry.register(
+        "latency_filter_to_queue_seconds",
+        "Time from the filter receiving a message to its row being queued",
+        Box::new(stats.latency.filter_to_queue.clone()),
+    );
+
+    registry.register(
+        "latency_queue_wait_seconds",
+        "Time a row waited in the queue of a sink",
+        Box::new(stats.latency.queue_wait.clone()),
+    );
+
+    registry.register(
+        "latency_db_execute_seconds",
+        "Time a sink took to write a batch of rows",
+        Box::new(stats.latency.db_execute.clone()),
+    );
+
+    registry.register(
+        "latency_validator_to_commit_seconds",
+        "Time from the validator sending a row to the sink writing it",
+        Box::new(stats.latency.validator_to_commit.clone()),
+    );
+
+    registry.register(
+        "slot_seen",
+        "The highest slot of every status received by the slot filter",
+        Box::new(stats.slot_seen.clone()),
+    );
+
+    registry.register(
+        "slot_written",
+        "The highest slot written to a table of a sink",
+        Box::new(stats.slot_written.clone()),
+    );
+
+    registry.register(
+        "slot_lag",
+        "How many slots a table of a sink is behind the highest slot of a status",
+        Box::new(stats.slot_lag.clone()),
+    );
+
+    registry.register(
+        "slot_rooted_gaps",
+        "How many rooted slots were never received, detected by the parents of the rooted slots",
+        Box::new(stats.slot_rooted_gaps.clone()),
+    );
+
+    registry.register(
+        "filter_evaluated",
+        "How many account updates were checked against the filter rules",
+        Box::new(stats.filter_evaluated.clone()),
+    );
+
+    registry.register(
+        "filter_rule_matched",
+        "How many account updates matched a filter rule",
+        Box::new(stats.filter_rule_matched.clone()),
+    );
+
     let registry_with_label = registry.sub_registry_with_label((
         Cow::Borrowed("topic"),
         Cow::from(
@@ -99,10 +230,15 @@ pub async fn start_prometheus(
     );
 
     let metrics_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
-    start_metrics_server(metrics_addr, registry).await
+    start_metrics_server(metrics_addr, registry, health, filter_metrics).await
 }
 
-async fn start_metrics_server(metrics_addr: SocketAddr, registry: Registry) {
+async fn start_metrics_server(
+    metrics_addr: SocketAddr,
+    registry: Registry,
+    health: Arc<Health>,
+    filter_metrics: Arc<FilterMetrics>,
+) {
     let mut shutdown_stream = signal(SignalKind::terminate()).unwrap();
 
     println!("Starting metrics server on {metrics_addr}");
@@ -111,8 +247,10 @@ async fn start_metrics_server(metrics_addr: SocketAddr, registry: Registry) {
     Server::bind(&metrics_addr)
         .serve(make_service_fn(move |_conn| {
             let registry = registry.clone();
+            let health = health.clone();
+            let filter_metrics = filter_metrics.clone();
             async move {
-                let handler = make_handler(registry);
+                let handler = make_handler(registry, health, filter_metrics);
                 Ok::<_, io::Error>(service_fn(handler))
             }
         }))
@@ -123,16 +261,65 @@ async fn start_metrics_server(metrics_addr: SocketAddr, registry: Registry) {
         .expect("Failed to bind hyper server with graceful_shutdown");
 }
 
+// 200 with the JSON detail if the check passed, 503 otherwise
+fn health_response((ok, detail): (bool, Value)) -> Response<Body> {
+    let status = match ok {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(detail.to_string()))
+        .unwrap()
+}
+
+// The matched pubkeys with the most matches, `limit` of them if the query has it
+fn hot_pubkeys_response(filter_metrics: &FilterMetrics, query: Option<&str>) -> Response<Body> {
+    let limit = query
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|pair| pair.strip_prefix("limit="))
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(DEFAULT_HOT_PUBKEYS_LIMIT);
+
+    let body = json!({
+        "capacity": filter_metrics.capacity(),
+        "pubkeys": filter_metrics.hot_pubkeys(limit),
+    });
+
+    Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+type ResponseFuture = Pin<Box<dyn Future<Output = io::Result<Response<Body>>> + Send>>;
+
 fn make_handler(
     registry: Arc<Registry>,
-) -> impl Fn(Request<Body>) -> Pin<Box<dyn Future<Output = io::Result<Response<Body>>> + Send>> {
-    // This closure accepts a request and responds with the OpenMetrics encoding of our metrics.
-    move |_req: Request<Body>| {
+    health: Arc<Health>,
+    filter_metrics: Arc<FilterMetrics>,
+) -> impl Fn(Request<Body>) -> ResponseFuture {
+    // This closure accepts a request and responds with the health checks or the OpenMetrics encoding of our metrics.
+    move |req: Request<Body>| {
         let reg = registry.clone();
+        let health = health.clone();
+        let filter_metrics = filter_metrics.clone();
         Box::pin(async move {
+            match req.uri().path() {
+                "/healthz" => return Ok(health_response(health.liveness())),
+                "/readyz" => return Ok(health_response(health.readiness().await)),
+                "/filter/hot-pubkeys" => {
+                    return Ok(hot_pubkeys_response(&filter_metrics, req.uri().query()))
+                }
+                _ => (),
+            }
+
             let mut buf = Vec::new();
             encode(&mut buf, &reg.clone())
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+                .map_err(std::io::Error::other)
                 .map(|_| {
                     let body = Body::from(buf);
                     Response::builder()
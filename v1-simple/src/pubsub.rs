@@ -0,0 +1,605 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use ahash::AHashMap;
+use flume::{Receiver, Sender, TrySendError};
+use futures_util::{SinkExt, StreamExt};
+use kafka_common::kafka_structs::{KafkaSlotStatus, UpdateSlotStatus};
+use log::{error, info, trace, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+
+use crate::{
+    db::DbAccountInfo,
+    rpc::{
+        encode_data, optional_param, param, parse_pubkey, Commitment, DataSlice, Encoding,
+        ProgramAccountFilter, RpcError, RpcResult,
+    },
+};
+
+const MAX_PROGRAM_FILTERS: usize = 4;
+
+// Accounts of slots this far behind the newest one are dropped if the slot never gets confirmed or rooted
+const MAX_PENDING_SLOTS: u64 = 512;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AccountSubscribeConfig {
+    #[serde(default)]
+    encoding: Encoding,
+    commitment: Option<Commitment>,
+    data_slice: Option<DataSlice>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProgramSubscribeConfig {
+    #[serde(default)]
+    encoding: Encoding,
+    commitment: Option<Commitment>,
+    data_slice: Option<DataSlice>,
+    #[serde(default)]
+    filters: Vec<ProgramAccountFilter>,
+}
+
+// A programSubscribe filter with the memcmp bytes already decoded
+enum DataFilter {
+    Size(u64),
+    Memcmp { offset: usize, bytes: Vec<u8> },
+}
+
+impl DataFilter {
+    fn new(filter: &ProgramAccountFilter) -> RpcResult<Self> {
+        Ok(match filter {
+            ProgramAccountFilter::DataSize(size) => DataFilter::Size(*size),
+            ProgramAccountFilter::Memcmp(memcmp) => DataFilter::Memcmp {
+                offset: memcmp.offset,
+                bytes: memcmp.decode()?,
+            },
+        })
+    }
+
+    fn matches(&self, data: &[u8]) -> bool {
+        match self {
+            DataFilter::Size(size) => data.len() as u64 == *size,
+            DataFilter::Memcmp { offset, bytes } => data
+                .get(*offset..offset.saturating_add(bytes.len()))
+                .is_some_and(|data| data == bytes.as_slice()),
+        }
+    }
+}
+
+enum SubscriptionKind {
+    Account(Vec<u8>),
+    Program {
+        owner: Vec<u8>,
+        filters: Vec<DataFilter>,
+    },
+    Slot,
+}
+
+impl SubscriptionKind {
+    fn unsubscribe_method(&self) -> &'static str {
+        match self {
+            SubscriptionKind::Account(_) => "accountUnsubscribe",
+            SubscriptionKind::Program { .. } => "programUnsubscribe",
+            SubscriptionKind::Slot => "slotUnsubscribe",
+        }
+    }
+}
+
+struct Subscription {
+    connection: u64,
+    kind: SubscriptionKind,
+    commitment: Commitment,
+    encoding: Encoding,
+    data_slice: Option<DataSlice>,
+}
+
+impl Subscription {
+    fn matches(&self, account: &DbAccountInfo) -> bool {
+        match &self.kind {
+            SubscriptionKind::Account(pubkey) => *pubkey == account.pubkey,
+            SubscriptionKind::Program { owner, filters } => {
+                *owner == account.owner
+                    && filters.iter().all(|filter| filter.matches(&account.data))
+            }
+            SubscriptionKind::Slot => false,
+        }
+    }
+
+    // The notification for an account change, None if the account doesn't match
+    fn account_notification(&self, id: u64, account: &DbAccountInfo) -> Option<String> {
+        if !self.matches(account) {
+            return None;
+        }
+
+        let (method, value) = match &self.kind {
+            SubscriptionKind::Account(_) => ("accountNotification", self.account_json(account)?),
+            SubscriptionKind::Program { .. } => (
+                "programNotification",
+                json!({
+                    "pubkey": bs58::encode(&account.pubkey).into_string(),
+                    "account": self.account_json(account)?,
+                }),
+            ),
+            SubscriptionKind::Slot => return None,
+        };
+
+        Some(notification(
+            method,
+            id,
+            json!({ "context": { "slot": account.slot }, "value": value }),
+        ))
+    }
+
+    fn account_json(&self, account: &DbAccountInfo) -> Option<Value> {
+        // Accounts too large for the requested encoding are not sent
+        let data = match encode_data(&account.data, self.encoding, self.data_slice) {
+            Ok(data) => data,
+            Err(e) => {
+                trace!("Skipping a notification, error: {}", e.message);
+                return None;
+            }
+        };
+
+        Some(json!({
+            "data": data,
+            "executable": account.executable,
+            "lamports": account.lamports,
+            "owner": bs58::encode(&account.owner).into_string(),
+            "rentEpoch": account.rent_epoch,
+        }))
+    }
+}
+
+fn notification(method: &str, subscription: u64, result: Value) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "params": { "result": result, "subscription": subscription },
+    })
+    .to_string()
+}
+
+// Accounts of a slot waiting for the slot to reach the commitment of their subscribers
+#[derive(Default)]
+struct PendingSlot {
+    confirmed: bool,
+    rooted: bool,
+    accounts: Vec<Arc<DbAccountInfo>>,
+}
+
+#[derive(Default)]
+struct PubSubState {
+    next_id: u64,
+    connections: AHashMap<u64, Sender<String>>,
+    subscriptions: AHashMap<u64, Subscription>,
+    slots: BTreeMap<u64, PendingSlot>,
+    newest_slot: u64,
+    root: u64,
+}
+
+impl PubSubState {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    // Sends the account to the subscriptions with the given commitment.
+    // Connections whose queue is full are dropped instead of slowing down the filters.
+    fn send_account(&mut self, account: &DbAccountInfo, commitment: Commitment) {
+        let mut overflowed = Vec::new();
+
+        for (id, subscription) in &self.subscriptions {
+            if subscription.commitment != commitment {
+                continue;
+            }
+
+            if let Some(message) = subscription.account_notification(*id, account) {
+                if !self.send(subscription.connection, message) {
+                    overflowed.push(subscription.connection);
+                }
+            }
+        }
+
+        for connection in overflowed {
+            self.disconnect(connection);
+        }
+    }
+
+    fn send_slot(&mut self, update_slot: &UpdateSlotStatus) {
+        let mut overflowed = Vec::new();
+
+        for (id, subscription) in &self.subscriptions {
+            if !matches!(subscription.kind, SubscriptionKind::Slot) {
+                continue;
+            }
+
+            let message = notification(
+                "slotNotification",
+                *id,
+                json!({
+                    "parent": update_slot.parent,
+                    "root": self.root,
+                    "slot": update_slot.slot,
+                }),
+            );
+            if !self.send(subscription.connection, message) {
+                overflowed.push(subscription.connection);
+            }
+        }
+
+        for connection in overflowed {
+            self.disconnect(connection);
+        }
+    }
+
+    // False if the connection can't keep up
+    fn send(&self, connection: u64, message: String) -> bool {
+        match self.connections.get(&connection) {
+            Some(sender) => !matches!(sender.try_send(message), Err(TrySendError::Full(_))),
+            None => true,
+        }
+    }
+
+    // Dropping the last sender closes the connection
+    fn disconnect(&mut self, connection: u64) {
+        if self.connections.remove(&connection).is_some() {
+            self.subscriptions
+                .retain(|_, subscription| subscription.connection != connection);
+        }
+    }
+}
+
+// Account and slot subscriptions fed by the filters, before anything is written to the sinks.
+// Processed subscriptions are notified right away, the confirmed and finalized ones once the slot
+// of the account reaches their commitment.
+pub struct PubSub {
+    state: Mutex<PubSubState>,
+    connection_buffer: usize,
+}
+
+impl PubSub {
+    pub fn new(connection_buffer: usize) -> Self {
+        Self {
+            state: Mutex::new(PubSubState::default()),
+            connection_buffer,
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, PubSubState> {
+        self.state.lock().expect("PubSub lock is poisoned")
+    }
+
+    pub fn notify_account(&self, account: &DbAccountInfo) {
+        let mut state = self.state();
+        if state.subscriptions.is_empty() {
+            return;
+        }
+
+        state.send_account(account, Commitment::Processed);
+
+        let slot = account.slot as u64;
+        if slot + MAX_PENDING_SLOTS < state.newest_slot {
+            return;
+        }
+
+        let (confirmed, rooted) = match state.slots.get(&slot) {
+            Some(pending) => (pending.confirmed, pending.rooted),
+            None => (false, false),
+        };
+        // Only kept for the subscriptions that still wait for the slot
+        let waiting = !rooted
+            && state.subscriptions.values().any(|subscription| {
+                let waits = match subscription.commitment {
+                    Commitment::Processed => false,
+                    Commitment::Confirmed => !confirmed,
+                    Commitment::Finalized => true,
+                };
+                waits && subscription.matches(account)
+            });
+        if waiting {
+            state
+                .slots
+                .entry(slot)
+                .or_default()
+                .accounts
+                .push(Arc::new(account.clone()));
+        }
+
+        if confirmed {
+            state.send_account(account, Commitment::Confirmed);
+        }
+        if rooted {
+            state.send_account(account, Commitment::Finalized);
+        }
+    }
+
+    pub fn notify_slot(&self, update_slot: &UpdateSlotStatus) {
+        let mut state = self.state();
+        state.newest_slot = state.newest_slot.max(update_slot.slot);
+
+        let (accounts, commitments) = {
+            let pending = state.slots.entry(update_slot.slot).or_default();
+            match update_slot.status {
+                KafkaSlotStatus::Confirmed if !pending.confirmed => {
+                    pending.confirmed = true;
+                    (pending.accounts.clone(), vec![Commitment::Confirmed])
+                }
+                KafkaSlotStatus::Rooted if !pending.rooted => {
+                    // A slot may be rooted without a confirmed update having been received
+                    let commitments = match pending.confirmed {
+                        true => vec![Commitment::Finalized],
+                        false => vec![Commitment::Confirmed, Commitment::Finalized],
+                    };
+                    pending.confirmed = true;
+                    pending.rooted = true;
+                    (std::mem::take(&mut pending.accounts), commitments)
+                }
+                _ => (Vec::new(), Vec::new()),
+            }
+        };
+
+        for commitment in commitments {
+            for account in &accounts {
+                state.send_account(account, commitment);
+            }
+        }
+
+        match update_slot.status {
+            KafkaSlotStatus::Processed => state.send_slot(update_slot),
+            KafkaSlotStatus::Rooted => state.root = state.root.max(update_slot.slot),
+            KafkaSlotStatus::Confirmed => (),
+        }
+
+        let oldest_slot = state.newest_slot.saturating_sub(MAX_PENDING_SLOTS);
+        state.slots = state.slots.split_off(&oldest_slot);
+    }
+
+    fn connect(&self) -> (u64, Receiver<String>) {
+        let (sender, receiver) = flume::bounded(self.connection_buffer);
+        let mut state = self.state();
+        let connection = state.next_id();
+        state.connections.insert(connection, sender);
+        (connection, receiver)
+    }
+
+    fn disconnect(&self, connection: u64) {
+        self.state().disconnect(connection);
+    }
+
+    fn subscribe(
+        &self,
+        connection: u64,
+        kind: SubscriptionKind,
+        commitment: Option<Commitment>,
+        encoding: Encoding,
+        data_slice: Option<DataSlice>,
+    ) -> RpcResult<Value> {
+        let mut state = self.state();
+        if !state.connections.contains_key(&connection) {
+            return Err(RpcError::invalid_request());
+        }
+
+        let id = state.next_id();
+        state.subscriptions.insert(
+            id,
+            Subscription {
+                connection,
+                kind,
+                commitment: commitment.unwrap_or(Commitment::Finalized),
+                encoding,
+                data_slice,
+            },
+        );
+        Ok(json!(id))
+    }
+
+    fn unsubscribe(&self, connection: u64, method: &str, params: &[Value]) -> RpcResult<Value> {
+        let id: u64 = param(params, 0)?;
+        let mut state = self.state();
+
+        match state.subscriptions.get(&id) {
+            Some(subscription)
+                if subscription.connection == connection
+                    && subscription.kind.unsubscribe_method() == method =>
+            {
+                state.subscriptions.remove(&id);
+                Ok(json!(true))
+            }
+            _ => Err(RpcError::invalid_params("Invalid subscription id.")),
+        }
+    }
+
+    fn call(&self, connection: u64, method: &str, params: &[Value]) -> RpcResult<Value> {
+        match method {
+            "accountSubscribe" => {
+                let pubkey = parse_pubkey(&param::<String>(params, 0)?)?;
+                let config: AccountSubscribeConfig = optional_param(params, 1)?;
+                self.subscribe(
+                    connection,
+                    SubscriptionKind::Account(pubkey),
+                    config.commitment,
+                    config.encoding,
+                    config.data_slice,
+                )
+            }
+            "programSubscribe" => {
+                let owner = parse_pubkey(&param::<String>(params, 0)?)?;
+                let config: ProgramSubscribeConfig = optional_param(params, 1)?;
+                if config.filters.len() > MAX_PROGRAM_FILTERS {
+                    return Err(RpcError::invalid_params(format!(
+                        "Too many filters provided; max {MAX_PROGRAM_FILTERS}"
+                    )));
+                }
+
+                let filters = config
+                    .filters
+                    .iter()
+                    .map(DataFilter::new)
+                    .collect::<RpcResult<Vec<_>>>()?;
+                self.subscribe(
+                    connection,
+                    SubscriptionKind::Program { owner, filters },
+                    config.commitment,
+                    config.encoding,
+                    config.data_slice,
+                )
+            }
+            "slotSubscribe" => self.subscribe(
+                connection,
+                SubscriptionKind::Slot,
+                None,
+                Encoding::default(),
+                None,
+            ),
+            "accountUnsubscribe" | "programUnsubscribe" | "slotUnsubscribe" => {
+                self.unsubscribe(connection, method, params)
+            }
+            _ => Err(RpcError::method_not_found()),
+        }
+    }
+
+    fn handle_request(&self, connection: u64, text: &str) -> String {
+        let request = match serde_json::from_str::<Value>(text) {
+            Ok(request) => request,
+            Err(_) => return RpcError::parse_error().response(Value::Null).to_string(),
+        };
+
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let params = match request.get("params") {
+            None | Some(Value::Null) => Some(&[][..]),
+            Some(Value::Array(params)) => Some(params.as_slice()),
+            Some(_) => None,
+        };
+
+        let result = match (
+            request.get("jsonrpc").and_then(Value::as_str),
+            request.get("method").and_then(Value::as_str),
+            params,
+        ) {
+            (Some("2.0"), Some(method), Some(params)) => self.call(connection, method, params),
+            _ => Err(RpcError::invalid_request()),
+        };
+
+        match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(e) => e.response(id),
+        }
+        .to_string()
+    }
+}
+
+async fn handle_connection(pubsub: Arc<PubSub>, stream: TcpStream, peer: SocketAddr) {
+    let websocket = match tokio_tungstenite::accept_async(stream).await {
+        Ok(websocket) => websocket,
+        Err(e) => {
+            warn!("WebSocket handshake with {peer} failed, error: {e}");
+            return;
+        }
+    };
+
+    let (mut write, mut read) = websocket.split();
+    let (connection, notifications) = pubsub.connect();
+    trace!("WebSocket client {peer} connected");
+
+    loop {
+        let result = tokio::select! {
+            message = read.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let response = pubsub.handle_request(connection, &text);
+                    write.send(Message::Text(response)).await
+                }
+                Some(Ok(Message::Ping(payload))) => write.send(Message::Pong(payload)).await,
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => Ok(()),
+                Some(Err(e)) => Err(e),
+            },
+            notification = notifications.recv_async() => match notification {
+                Ok(notification) => write.send(Message::Text(notification)).await,
+                Err(_) => {
+                    warn!("WebSocket client {peer} is too slow, closing the connection");
+                    let _ = write
+                        .send(Message::Close(Some(CloseFrame {
+                            code: CloseCode::Again,
+                            reason: "Notification queue is full".into(),
+                        })))
+                        .await;
+                    break;
+                }
+            },
+        };
+
+        if let Err(e) = result {
+            trace!("WebSocket client {peer} failed, error: {e}");
+            break;
+        }
+    }
+
+    pubsub.disconnect(connection);
+    trace!("WebSocket client {peer} disconnected");
+}
+
+pub async fn start_pubsub_server(pubsub: Arc<PubSub>, port: u16) {
+    let pubsub_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+    let listener = match TcpListener::bind(pubsub_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind the WebSocket server to {pubsub_addr}, error: {e}");
+            return;
+        }
+    };
+
+    info!("Starting WebSocket server on {pubsub_addr}");
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tokio::spawn(handle_connection(pubsub.clone(), stream, peer));
+            }
+            Err(e) => error!("Failed to accept a WebSocket connection, error: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(value: Value) -> DataFilter {
+        DataFilter::new(&serde_json::from_value(value).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn matches_the_data_size() {
+        let filter = filter(json!({ "dataSize": 3 }));
+        assert!(filter.matches(&[1, 2, 3]));
+        assert!(!filter.matches(&[1, 2]));
+    }
+
+    #[test]
+    fn matches_memcmp_bytes_at_their_offset() {
+        // [2, 3] in base58
+        let filter = filter(json!({ "memcmp": { "offset": 1, "bytes": "9t" } }));
+        assert!(filter.matches(&[1, 2, 3]));
+        assert!(filter.matches(&[1, 2, 3, 4]));
+        assert!(!filter.matches(&[2, 3]));
+        // The data ends before the bytes do
+        assert!(!filter.matches(&[1, 2]));
+    }
+
+    #[test]
+    fn memcmp_beyond_the_data_does_not_match() {
+        let filter = filter(json!({ "memcmp": { "offset": usize::MAX, "bytes": "2" } }));
+        assert!(!filter.matches(&[1, 2, 3]));
+    }
+}
@@ -0,0 +1,866 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, RwLock},
+};
+
+use ahash::AHashMap;
+use anyhow::anyhow;
+use hyper::{
+    body::HttpBody,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_postgres::{types::ToSql, Client, Row};
+
+use crate::{
+    config::{FilterConfig, PersistenceMode},
+    db::{initialize_db_client, DbReward},
+    history::{account_version_json, accounts_at_slot},
+    sqlite_sink::SQLITE_SCHEME,
+};
+
+// Solana refuses to encode more than this many bytes as base58
+const MAX_BASE58_BYTES: usize = 128;
+
+const MAX_MULTIPLE_ACCOUNTS: usize = 100;
+
+const MAX_PROGRAM_ACCOUNT_FILTERS: usize = 4;
+
+// Solana limits the memcmp bytes to 175 base58 characters
+const MAX_MEMCMP_BYTES: usize = 128;
+
+const ACCOUNT_COLUMNS: &str = "pubkey, owner, lamports, slot, executable, rent_epoch, data";
+
+// The newest slot having at least the given status
+const SLOT_SELECT: &str =
+    "SELECT slot FROM slot WHERE lower(status) = ANY($1) ORDER BY slot DESC LIMIT 1";
+
+const BLOCK_SELECT: &str = "SELECT b.slot, b.blockhash, b.rewards, b.block_time, b.block_height, s.parent, p.blockhash AS parent_blockhash \
+    FROM block b \
+    LEFT JOIN slot s ON s.slot = b.slot \
+    LEFT JOIN block p ON p.slot = s.parent \
+    WHERE b.slot = $1";
+
+#[derive(Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn parse_error() -> Self {
+        Self {
+            code: -32700,
+            message: "Parse error".to_string(),
+        }
+    }
+
+    pub fn invalid_request() -> Self {
+        Self {
+            code: -32600,
+            message: "Invalid request".to_string(),
+        }
+    }
+
+    pub fn method_not_found() -> Self {
+        Self {
+            code: -32601,
+            message: "Method not found".to_string(),
+        }
+    }
+
+    pub fn invalid_params(message: impl Into<String>) -> Self {
+        Self {
+            code: -32602,
+            message: message.into(),
+        }
+    }
+
+    fn block_not_available(slot: u64) -> Self {
+        Self {
+            code: -32004,
+            message: format!("Block not available for slot {slot}"),
+        }
+    }
+
+    pub fn response(&self, id: Value) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "error": { "code": self.code, "message": self.message },
+            "id": id,
+        })
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(error: anyhow::Error) -> Self {
+        error!("RPC request failed, error: {error:?}");
+        Self {
+            code: -32603,
+            message: "Internal error".to_string(),
+        }
+    }
+}
+
+impl From<tokio_postgres::Error> for RpcError {
+    fn from(error: tokio_postgres::Error) -> Self {
+        anyhow!(error).into()
+    }
+}
+
+pub type RpcResult<T> = Result<T, RpcError>;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Commitment {
+    Processed,
+    Confirmed,
+    Finalized,
+}
+
+impl Commitment {
+    // Lowercase statuses of the slot table that satisfy the commitment
+    fn statuses(&self) -> Vec<&'static str> {
+        match self {
+            Commitment::Processed => vec!["processed", "confirmed", "rooted"],
+            Commitment::Confirmed => vec!["confirmed", "rooted"],
+            Commitment::Finalized => vec!["rooted"],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+pub enum Encoding {
+    // Legacy base58 string without the encoding tag
+    #[default]
+    #[serde(rename = "binary")]
+    Binary,
+    #[serde(rename = "base58")]
+    Base58,
+    #[serde(rename = "base64")]
+    Base64,
+    // Nothing is parsed, the data is returned as base64 like validators do for unknown programs
+    #[serde(rename = "jsonParsed")]
+    JsonParsed,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct DataSlice {
+    offset: usize,
+    length: usize,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AccountConfig {
+    #[serde(default)]
+    encoding: Encoding,
+    commitment: Option<Commitment>,
+    data_slice: Option<DataSlice>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AccountsAtSlotConfig {
+    #[serde(default)]
+    encoding: Encoding,
+    data_slice: Option<DataSlice>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BlockConfig {
+    transaction_details: Option<String>,
+    rewards: Option<bool>,
+    commitment: Option<Commitment>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum MemcmpEncoding {
+    #[default]
+    Base58,
+    Base64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Memcmp {
+    pub offset: usize,
+    bytes: String,
+    #[serde(default)]
+    encoding: MemcmpEncoding,
+}
+
+impl Memcmp {
+    pub fn decode(&self) -> RpcResult<Vec<u8>> {
+        let bytes = match self.encoding {
+            MemcmpEncoding::Base58 => bs58::decode(&self.bytes).into_vec().ok(),
+            MemcmpEncoding::Base64 => base64::decode(&self.bytes).ok(),
+        }
+        .ok_or_else(|| RpcError::invalid_params("Invalid param: invalid memcmp bytes"))?;
+
+        if bytes.len() > MAX_MEMCMP_BYTES {
+            return Err(RpcError::invalid_params(
+                "Invalid param: memcmp bytes too long",
+            ));
+        }
+        Ok(bytes)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ProgramAccountFilter {
+    DataSize(u64),
+    Memcmp(Memcmp),
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProgramAccountsConfig {
+    #[serde(default)]
+    encoding: Encoding,
+    commitment: Option<Commitment>,
+    data_slice: Option<DataSlice>,
+    #[serde(default)]
+    filters: Vec<ProgramAccountFilter>,
+    #[serde(default)]
+    with_context: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct CommitmentConfig {
+    commitment: Option<Commitment>,
+}
+
+pub fn param<T: for<'de> Deserialize<'de>>(params: &[Value], index: usize) -> RpcResult<T> {
+    let value = params
+        .get(index)
+        .ok_or_else(|| RpcError::invalid_params(format!("Missing parameter {index}")))?;
+    serde_json::from_value(value.clone())
+        .map_err(|e| RpcError::invalid_params(format!("Invalid params: {e}")))
+}
+
+pub fn optional_param<T: for<'de> Deserialize<'de> + Default>(
+    params: &[Value],
+    index: usize,
+) -> RpcResult<T> {
+    match params.get(index) {
+        None | Some(Value::Null) => Ok(T::default()),
+        Some(_) => param(params, index),
+    }
+}
+
+pub fn parse_pubkey(pubkey: &str) -> RpcResult<Vec<u8>> {
+    match bs58::decode(pubkey).into_vec() {
+        Ok(bytes) if bytes.len() == 32 => Ok(bytes),
+        Ok(_) => Err(RpcError::invalid_params("Invalid param: WrongSize")),
+        Err(_) => Err(RpcError::invalid_params("Invalid param: Invalid")),
+    }
+}
+
+pub fn encode_data(data: &[u8], encoding: Encoding, slice: Option<DataSlice>) -> RpcResult<Value> {
+    let data = match slice {
+        Some(slice) => {
+            let start = slice.offset.min(data.len());
+            let end = start.saturating_add(slice.length).min(data.len());
+            &data[start..end]
+        }
+        None => data,
+    };
+
+    match encoding {
+        Encoding::Binary | Encoding::Base58 if data.len() > MAX_BASE58_BYTES => {
+            Err(RpcError::invalid_params(format!(
+                "Encoded binary (base 58) data should be less than {MAX_BASE58_BYTES} bytes, please use Base64 encoding."
+            )))
+        }
+        Encoding::Binary => Ok(json!(bs58::encode(data).into_string())),
+        Encoding::Base58 => Ok(json!([bs58::encode(data).into_string(), "base58"])),
+        Encoding::Base64 | Encoding::JsonParsed => Ok(json!([base64::encode(data), "base64"])),
+    }
+}
+
+fn account_json(row: &Row, encoding: Encoding, slice: Option<DataSlice>) -> RpcResult<Value> {
+    let owner: Vec<u8> = row.try_get("owner")?;
+    let data: Vec<u8> = row
+        .try_get::<_, Option<Vec<u8>>>("data")?
+        .unwrap_or_default();
+
+    Ok(json!({
+        "data": encode_data(&data, encoding, slice)?,
+        "executable": row.try_get::<_, bool>("executable")?,
+        "lamports": row.try_get::<_, i64>("lamports")?,
+        "owner": bs58::encode(owner).into_string(),
+        "rentEpoch": row.try_get::<_, i64>("rent_epoch")?,
+    }))
+}
+
+fn reward_json(reward: &DbReward) -> Value {
+    json!({
+        "pubkey": reward.pubkey,
+        "lamports": reward.lamports,
+        "postBalance": reward.post_balance,
+        "rewardType": reward.reward_type.as_ref().map(|t| format!("{t:?}")),
+        "commission": reward.commission,
+    })
+}
+
+// Answers the read methods of the Solana JSON-RPC API from the tables written by the Postgres sink
+struct RpcHandler {
+    config: Arc<FilterConfig>,
+    client: RwLock<Arc<Client>>,
+}
+
+impl RpcHandler {
+    async fn client(&self) -> Arc<Client> {
+        let client = self
+            .client
+            .read()
+            .expect("RPC client lock is poisoned")
+            .clone();
+        if !client.is_closed() {
+            return client;
+        }
+
+        warn!("RPC Postgres client was unexpectedly closed");
+        let client = initialize_db_client(self.config.clone()).await;
+        *self.client.write().expect("RPC client lock is poisoned") = client.clone();
+        client
+    }
+
+    async fn slot(&self, client: &Client, commitment: Option<Commitment>) -> RpcResult<u64> {
+        let commitment = commitment.unwrap_or(Commitment::Finalized);
+        let row = client
+            .query_opt(SLOT_SELECT, &[&commitment.statuses()])
+            .await?;
+        Ok(row.map_or(0, |row| row.get::<_, i64>("slot") as u64))
+    }
+
+    // The newest version at or before the slot from the history
+    async fn audit_account(
+        &self,
+        client: &Client,
+        pubkey: &Vec<u8>,
+        context_slot: u64,
+        config: &AccountConfig,
+    ) -> RpcResult<Option<Value>> {
+        client
+            .query_opt(
+                &format!(
+                    "SELECT {ACCOUNT_COLUMNS} FROM account_audit \
+                    WHERE pubkey = $1 AND slot <= $2 \
+                    ORDER BY slot DESC, write_version DESC LIMIT 1"
+                ),
+                &[pubkey, &(context_slot as i64)],
+            )
+            .await?
+            .map(|row| account_json(&row, config.encoding, config.data_slice))
+            .transpose()
+    }
+
+    // The stored versions of the accounts. The version at the slot of the commitment is read from
+    // account_audit if the newest one is past it, or if only the history is written.
+    async fn accounts(
+        &self,
+        client: &Client,
+        pubkeys: &[Vec<u8>],
+        context_slot: u64,
+        config: &AccountConfig,
+    ) -> RpcResult<Vec<Value>> {
+        let mode = self.config.persistence_mode;
+        let rows: AHashMap<Vec<u8>, Row> = match mode {
+            PersistenceMode::History => AHashMap::new(),
+            _ => client
+                .query(
+                    &format!("SELECT {ACCOUNT_COLUMNS} FROM account WHERE pubkey = ANY($1)"),
+                    &[&pubkeys],
+                )
+                .await?
+                .into_iter()
+                .map(|row| (row.get("pubkey"), row))
+                .collect(),
+        };
+
+        let mut accounts = Vec::with_capacity(pubkeys.len());
+        for pubkey in pubkeys {
+            let account = match rows.get(pubkey) {
+                Some(row)
+                    if row.get::<_, i64>("slot") as u64 > context_slot && mode.writes_history() =>
+                {
+                    self.audit_account(client, pubkey, context_slot, config)
+                        .await?
+                }
+                Some(row) => Some(account_json(row, config.encoding, config.data_slice)?),
+                None if mode == PersistenceMode::History => {
+                    self.audit_account(client, pubkey, context_slot, config)
+                        .await?
+                }
+                None => None,
+            };
+            accounts.push(account.unwrap_or(Value::Null));
+        }
+        Ok(accounts)
+    }
+
+    async fn get_slot(&self, params: &[Value]) -> RpcResult<Value> {
+        let config: CommitmentConfig = optional_param(params, 0)?;
+        let client = self.client().await;
+        Ok(json!(self.slot(&client, config.commitment).await?))
+    }
+
+    async fn get_account_info(&self, params: &[Value]) -> RpcResult<Value> {
+        let pubkey = parse_pubkey(&param::<String>(params, 0)?)?;
+        let config: AccountConfig = optional_param(params, 1)?;
+
+        let client = self.client().await;
+        let slot = self.slot(&client, config.commitment).await?;
+        let value = self
+            .accounts(&client, &[pubkey], slot, &config)
+            .await?
+            .pop()
+            .unwrap_or(Value::Null);
+
+        Ok(json!({ "context": { "slot": slot }, "value": value }))
+    }
+
+    async fn get_multiple_accounts(&self, params: &[Value]) -> RpcResult<Value> {
+        let pubkeys = param::<Vec<String>>(params, 0)?;
+        if pubkeys.len() > MAX_MULTIPLE_ACCOUNTS {
+            return Err(RpcError::invalid_params(format!(
+                "Too many inputs provided; max {MAX_MULTIPLE_ACCOUNTS}"
+            )));
+        }
+        let pubkeys = pubkeys
+            .iter()
+            .map(|pubkey| parse_pubkey(pubkey))
+            .collect::<RpcResult<Vec<_>>>()?;
+        let config: AccountConfig = optional_param(params, 1)?;
+
+        let client = self.client().await;
+        let slot = self.slot(&client, config.commitment).await?;
+        let value = self.accounts(&client, &pubkeys, slot, &config).await?;
+
+        Ok(json!({ "context": { "slot": slot }, "value": value }))
+    }
+
+    // The versions of the accounts at a past slot, for replaying what a transaction saw
+    async fn get_accounts_at_slot(&self, params: &[Value]) -> RpcResult<Value> {
+        if !self.config.persistence_mode.writes_history() {
+            return Err(RpcError::invalid_params(
+                "Account history is not written in the LatestState mode",
+            ));
+        }
+
+        let pubkeys = param::<Vec<String>>(params, 0)?;
+        if pubkeys.len() > MAX_MULTIPLE_ACCOUNTS {
+            return Err(RpcError::invalid_params(format!(
+                "Too many inputs provided; max {MAX_MULTIPLE_ACCOUNTS}"
+            )));
+        }
+        let pubkeys = pubkeys
+            .iter()
+            .map(|pubkey| parse_pubkey(pubkey))
+            .collect::<RpcResult<Vec<_>>>()?;
+        let slot: u64 = param(params, 1)?;
+        let config: AccountsAtSlotConfig = optional_param(params, 2)?;
+
+        let client = self.client().await;
+        let value = accounts_at_slot(&client, &pubkeys, slot)
+            .await?
+            .iter()
+            .map(|row| match row {
+                Some(row) => account_version_json(row, config.encoding, config.data_slice),
+                None => Ok(Value::Null),
+            })
+            .collect::<RpcResult<Vec<_>>>()?;
+
+        Ok(json!({ "context": { "slot": slot }, "value": value }))
+    }
+
+    // Only the block metadata is stored, so blocks never have transactions
+    async fn get_block(&self, params: &[Value]) -> RpcResult<Value> {
+        let slot: u64 = param(params, 0)?;
+        let config: BlockConfig = optional_param(params, 1)?;
+
+        if config.commitment == Some(Commitment::Processed) {
+            return Err(RpcError::invalid_params(
+                "Method does not support commitment below `confirmed`",
+            ));
+        }
+        if matches!(config.transaction_details.as_deref(), Some(details) if details != "none") {
+            return Err(RpcError::invalid_params(
+                "Transactions are not stored, only transactionDetails `none` is supported",
+            ));
+        }
+
+        let client = self.client().await;
+        if slot > self.slot(&client, config.commitment).await? {
+            return Err(RpcError::block_not_available(slot));
+        }
+
+        let row = client
+            .query_opt(BLOCK_SELECT, &[&(slot as i64)])
+            .await?
+            .ok_or_else(|| RpcError::block_not_available(slot))?;
+
+        let mut block = Map::new();
+        block.insert(
+            "blockhash".to_string(),
+            json!(row.try_get::<_, Option<String>>("blockhash")?),
+        );
+        block.insert(
+            "previousBlockhash".to_string(),
+            json!(row.try_get::<_, Option<String>>("parent_blockhash")?),
+        );
+        block.insert(
+            "parentSlot".to_string(),
+            json!(row.try_get::<_, Option<i64>>("parent")?),
+        );
+        block.insert(
+            "blockTime".to_string(),
+            json!(row.try_get::<_, Option<i64>>("block_time")?),
+        );
+        block.insert(
+            "blockHeight".to_string(),
+            json!(row.try_get::<_, Option<i64>>("block_height")?),
+        );
+        if config.rewards.unwrap_or(true) {
+            let rewards: Vec<DbReward> = row
+                .try_get::<_, Option<Vec<DbReward>>>("rewards")?
+                .unwrap_or_default();
+            block.insert(
+                "rewards".to_string(),
+                Value::Array(rewards.iter().map(reward_json).collect()),
+            );
+        }
+
+        Ok(Value::Object(block))
+    }
+
+    // The filters are translated to SQL, so only the matching rows of the owner are read
+    async fn get_program_accounts(&self, params: &[Value]) -> RpcResult<Value> {
+        let owner = parse_pubkey(&param::<String>(params, 0)?)?;
+        let config: ProgramAccountsConfig = optional_param(params, 1)?;
+
+        if config.filters.len() > MAX_PROGRAM_ACCOUNT_FILTERS {
+            return Err(RpcError::invalid_params(format!(
+                "Too many filters provided; max {MAX_PROGRAM_ACCOUNT_FILTERS}"
+            )));
+        }
+
+        let client = self.client().await;
+        let slot = self.slot(&client, config.commitment).await?;
+
+        let mut query_params: Vec<Box<dyn ToSql + Sync + Send>> = vec![Box::new(owner)];
+        let mut conditions = vec!["owner = $1".to_string()];
+
+        for filter in &config.filters {
+            match filter {
+                ProgramAccountFilter::DataSize(size) => {
+                    query_params.push(Box::new(i64::try_from(*size).unwrap_or(i64::MAX)));
+                    conditions.push(format!(
+                        "octet_length(data)::BIGINT = ${}::BIGINT",
+                        query_params.len()
+                    ));
+                }
+                ProgramAccountFilter::Memcmp(memcmp) => {
+                    let bytes = memcmp.decode()?;
+                    let offset = i32::try_from(memcmp.offset)
+                        .map_err(|_| RpcError::invalid_params("Invalid param: memcmp offset"))?;
+
+                    // SQL substrings start at 1, the data must be long enough to hold the bytes
+                    query_params.push(Box::new(offset));
+                    let offset_param = query_params.len();
+                    query_params.push(Box::new(bytes.len() as i32));
+                    let length_param = query_params.len();
+                    query_params.push(Box::new(bytes));
+                    conditions.push(format!(
+                        "octet_length(data) >= ${offset_param}::INT + ${length_param}::INT \
+                        AND substring(data FROM ${offset_param}::INT + 1 FOR ${length_param}::INT) = ${}",
+                        query_params.len()
+                    ));
+                }
+            }
+        }
+
+        // Only the history is written in this mode, the newest version of every account that
+        // ever had the owner is looked up in it
+        let source = match self.config.persistence_mode {
+            PersistenceMode::History => {
+                query_params.push(Box::new(slot as i64));
+                format!(
+                    "(SELECT DISTINCT ON (pubkey) {ACCOUNT_COLUMNS} FROM account_audit \
+                    WHERE slot <= ${} AND pubkey IN (SELECT pubkey FROM account_audit WHERE owner = $1) \
+                    ORDER BY pubkey, slot DESC, write_version DESC) latest",
+                    query_params.len()
+                )
+            }
+            _ => "account".to_string(),
+        };
+
+        // One row more than allowed tells that there are too many
+        let max_accounts = self.config.rpc_max_program_accounts;
+        let query = format!(
+            "SELECT {ACCOUNT_COLUMNS} FROM {source} WHERE {} LIMIT {}",
+            conditions.join(" AND "),
+            max_accounts + 1
+        );
+        let query_params: Vec<&(dyn ToSql + Sync)> = query_params
+            .iter()
+            .map(|p| p.as_ref() as &(dyn ToSql + Sync))
+            .collect();
+
+        let rows = client.query(&query, &query_params).await?;
+        if rows.len() > max_accounts {
+            return Err(RpcError::invalid_params(format!(
+                "More than {max_accounts} accounts match, narrow the filters"
+            )));
+        }
+
+        let accounts = rows
+            .iter()
+            .map(|row| {
+                Ok(json!({
+                    "pubkey": bs58::encode(row.try_get::<_, Vec<u8>>("pubkey")?).into_string(),
+                    "account": account_json(row, config.encoding, config.data_slice)?,
+                }))
+            })
+            .collect::<RpcResult<Vec<_>>>()?;
+
+        Ok(match config.with_context {
+            true => json!({ "context": { "slot": slot }, "value": accounts }),
+            false => Value::Array(accounts),
+        })
+    }
+
+    async fn call(&self, method: &str, params: &[Value]) -> RpcResult<Value> {
+        match method {
+            "getSlot" => self.get_slot(params).await,
+            "getAccountInfo" => self.get_account_info(params).await,
+            "getMultipleAccounts" => self.get_multiple_accounts(params).await,
+            "getAccountsAtSlot" => self.get_accounts_at_slot(params).await,
+            "getBlock" => self.get_block(params).await,
+            "getProgramAccounts" => self.get_program_accounts(params).await,
+            _ => Err(RpcError::method_not_found()),
+        }
+    }
+
+    async fn handle_request(&self, request: Value) -> Value {
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let params = match request.get("params") {
+            None | Some(Value::Null) => Some(&[][..]),
+            Some(Value::Array(params)) => Some(params.as_slice()),
+            Some(_) => None,
+        };
+
+        let result = match (
+            request.get("jsonrpc").and_then(Value::as_str),
+            request.get("method").and_then(Value::as_str),
+            params,
+        ) {
+            (Some("2.0"), Some(method), Some(params)) => self.call(method, params).await,
+            _ => Err(RpcError::invalid_request()),
+        };
+
+        match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(e) => e.response(id),
+        }
+    }
+
+    // A single request or a batch of them
+    async fn handle_body(&self, body: &[u8]) -> Value {
+        match serde_json::from_slice::<Value>(body) {
+            Ok(Value::Array(requests)) if !requests.is_empty() => {
+                let mut responses = Vec::with_capacity(requests.len());
+                for request in requests {
+                    responses.push(self.handle_request(request).await);
+                }
+                Value::Array(responses)
+            }
+            Ok(request @ Value::Object(_)) => self.handle_request(request).await,
+            Ok(_) => RpcError::invalid_request().response(Value::Null),
+            Err(_) => RpcError::parse_error().response(Value::Null),
+        }
+    }
+}
+
+// None if the body is larger than the limit, the rest of it is not read
+async fn read_body(body: &mut Body, limit: usize) -> Result<Option<Vec<u8>>, hyper::Error> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if bytes.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Some(bytes))
+}
+
+async fn serve(handler: Arc<RpcHandler>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::POST {
+        return Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    let limit = handler.config.rpc_max_body_size;
+    let too_large = || {
+        Ok(Response::builder()
+            .status(StatusCode::PAYLOAD_TOO_LARGE)
+            .body(Body::empty())
+            .unwrap())
+    };
+    // The declared length is checked first, so such bodies are not read at all
+    let declared = req
+        .headers()
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok());
+    if declared.is_some_and(|length| length > limit) {
+        return too_large();
+    }
+
+    let body = match read_body(&mut req.into_body(), limit).await {
+        Ok(Some(body)) => body,
+        Ok(None) => return too_large(),
+        Err(e) => {
+            warn!("Failed to read an RPC request, error: {e}");
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())
+                .unwrap());
+        }
+    };
+
+    let response = handler.handle_body(&body).await;
+    Ok(Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(response.to_string()))
+        .unwrap())
+}
+
+pub async fn start_rpc_server(config: Arc<FilterConfig>, port: u16) {
+    if config.postgres_connection_str.starts_with(SQLITE_SCHEME) {
+        error!("The RPC server reads from Postgres only, it is not started for SQLite");
+        return;
+    }
+
+    let mut shutdown_stream = signal(SignalKind::terminate()).unwrap();
+    let handler = Arc::new(RpcHandler {
+        client: RwLock::new(initialize_db_client(config.clone()).await),
+        config,
+    });
+
+    let rpc_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+    info!("Starting RPC server on {rpc_addr}");
+
+    Server::bind(&rpc_addr)
+        .serve(make_service_fn(move |_conn| {
+            let handler = handler.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| serve(handler.clone(), req))) }
+        }))
+        .with_graceful_shutdown(async move {
+            shutdown_stream.recv().await;
+        })
+        .await
+        .expect("Failed to bind hyper server with graceful_shutdown");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slice(offset: usize, length: usize) -> Option<DataSlice> {
+        Some(DataSlice { offset, length })
+    }
+
+    fn memcmp(value: Value) -> RpcResult<Vec<u8>> {
+        serde_json::from_value::<Memcmp>(value).unwrap().decode()
+    }
+
+    #[test]
+    fn encodes_data_like_solana() {
+        let data = [1, 2, 3, 4];
+        assert_eq!(
+            encode_data(&data, Encoding::Base64, None).unwrap(),
+            json!(["AQIDBA==", "base64"])
+        );
+        assert_eq!(
+            encode_data(&data, Encoding::Base58, None).unwrap(),
+            json!(["2VfUX", "base58"])
+        );
+        assert_eq!(
+            encode_data(&data, Encoding::Binary, None).unwrap(),
+            json!("2VfUX")
+        );
+    }
+
+    #[test]
+    fn slices_the_data_within_its_bounds() {
+        let data = [1, 2, 3, 4];
+        assert_eq!(
+            encode_data(&data, Encoding::Base64, slice(1, 2)).unwrap(),
+            json!([base64::encode([2, 3]), "base64"])
+        );
+        assert_eq!(
+            encode_data(&data, Encoding::Base64, slice(3, usize::MAX)).unwrap(),
+            json!([base64::encode([4]), "base64"])
+        );
+        assert_eq!(
+            encode_data(&data, Encoding::Base64, slice(10, 2)).unwrap(),
+            json!(["", "base64"])
+        );
+    }
+
+    #[test]
+    fn refuses_base58_for_large_data() {
+        let data = vec![0; MAX_BASE58_BYTES + 1];
+        assert!(encode_data(&data, Encoding::Base58, None).is_err());
+        assert!(encode_data(&data, Encoding::Base58, slice(0, MAX_BASE58_BYTES)).is_ok());
+        assert!(encode_data(&data, Encoding::Base64, None).is_ok());
+    }
+
+    #[test]
+    fn decodes_memcmp_bytes() {
+        assert_eq!(
+            memcmp(json!({ "offset": 0, "bytes": "2VfUX" })).unwrap(),
+            vec![1, 2, 3, 4]
+        );
+        assert_eq!(
+            memcmp(json!({ "offset": 0, "bytes": "AQIDBA==", "encoding": "base64" })).unwrap(),
+            vec![1, 2, 3, 4]
+        );
+        assert!(memcmp(json!({ "offset": 0, "bytes": "0OIl" })).is_err());
+
+        let too_long = bs58::encode(vec![1; MAX_MEMCMP_BYTES + 1]).into_string();
+        assert!(memcmp(json!({ "offset": 0, "bytes": too_long })).is_err());
+    }
+
+    #[test]
+    fn parses_program_account_filters() {
+        let filters: Vec<ProgramAccountFilter> = serde_json::from_value(json!([
+            { "dataSize": 165 },
+            { "memcmp": { "offset": 32, "bytes": "2VfUX" } },
+        ]))
+        .unwrap();
+        assert!(matches!(filters[0], ProgramAccountFilter::DataSize(165)));
+        assert!(matches!(&filters[1], ProgramAccountFilter::Memcmp(m) if m.offset == 32));
+    }
+
+    #[test]
+    fn accepts_only_32_byte_pubkeys() {
+        let pubkey = bs58::encode([7; 32]).into_string();
+        assert_eq!(parse_pubkey(&pubkey).unwrap(), vec![7; 32]);
+        assert!(parse_pubkey(&bs58::encode([7; 31]).into_string()).is_err());
+        assert!(parse_pubkey("not a pubkey").is_err());
+    }
+}
@@ -0,0 +1,702 @@
+use std::future::Future;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use chrono::Utc;
+use crossbeam_queue::SegQueue;
+use kafka_common::kafka_structs::UpdateSlotStatus;
+use log::error;
+use opentelemetry::global::BoxedSpan;
+use opentelemetry::trace::Span;
+use opentelemetry::trace::SpanContext;
+use opentelemetry::trace::SpanKind;
+use opentelemetry::trace::Status;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::trace::Tracer;
+use opentelemetry::Context;
+use opentelemetry::KeyValue;
+use serde::Serialize;
+use tokio::sync::Semaphore;
+
+use crate::config::FilterConfig;
+use crate::consumer::MessageAck;
+use crate::consumer_stats::Stats;
+use crate::consumer_stats::TableMetrics;
+use crate::db::DbAccountInfo;
+use crate::db::DbBlockInfo;
+use crate::db_retry::ErrorKind;
+use crate::db_retry::Quarantine;
+use crate::db_retry::RetryPolicy;
+use crate::db_retry::RetryQueue;
+use crate::spill_queue::SegmentAck;
+use crate::telemetry::tracer;
+
+// An output of the filtered stream.
+// Every sink gets its own queues, so a slow or unavailable sink doesn't hold back the others.
+#[async_trait]
+pub trait Sink: Send + Sync {
+    // Used in logs, metrics, spill queue directories and quarantined rows
+    fn name(&self) -> &str;
+
+    // The maximum number of rows passed to a single write
+    fn batch_size(&self) -> usize {
+        1
+    }
+
+    // The maximum number of writes in flight, None means unbounded
+    fn max_concurrent_writes(&self) -> Option<usize> {
+        Some(1)
+    }
+
+    // Whether the sink produces the Kafka payload of the account updates,
+    // it is dropped from the rows queued for the other sinks
+    fn republishes_accounts(&self) -> bool {
+        false
+    }
+
+    // Called by the executor before every round of writes
+    async fn ensure_connected(&self) {}
+
+    fn classify_error(&self, _error: &anyhow::Error) -> ErrorKind {
+        ErrorKind::Transient
+    }
+
+    async fn write_accounts(&self, accounts: &[DbAccountInfo]) -> Result<()>;
+
+    // Versions superseded within a coalescing window, they are ordinary account updates for most sinks
+    async fn write_account_history(&self, accounts: &[DbAccountInfo]) -> Result<()> {
+        self.write_accounts(accounts).await
+    }
+
+    async fn write_blocks(&self, blocks: &[DbBlockInfo]) -> Result<()>;
+
+    async fn write_slots(&self, slots: &[UpdateSlotStatus]) -> Result<()>;
+
+    // The writes called by the executor, a sink that buffers the rows after a successful write
+    // keeps a clone of the hold until they are durable. By default the rows are durable once written.
+    async fn write_accounts_held(
+        &self,
+        accounts: &[DbAccountInfo],
+        _hold: &Arc<WriteHold>,
+    ) -> Result<()> {
+        self.write_accounts(accounts).await
+    }
+
+    async fn write_account_history_held(
+        &self,
+        accounts: &[DbAccountInfo],
+        _hold: &Arc<WriteHold>,
+    ) -> Result<()> {
+        self.write_account_history(accounts).await
+    }
+
+    async fn write_blocks_held(
+        &self,
+        blocks: &[DbBlockInfo],
+        _hold: &Arc<WriteHold>,
+    ) -> Result<()> {
+        self.write_blocks(blocks).await
+    }
+
+    async fn write_slots_held(
+        &self,
+        slots: &[UpdateSlotStatus],
+        _hold: &Arc<WriteHold>,
+    ) -> Result<()> {
+        self.write_slots(slots).await
+    }
+
+    // Called once on shutdown to flush whatever the sink buffers
+    async fn close(&self) {}
+}
+
+// Released once a row is durable: written or quarantined by the sink, or spilled to disk
+#[derive(Clone, Default)]
+pub struct RowAck {
+    // Set for the rows replayed from the spill queue
+    pub segment: Option<Arc<SegmentAck>>,
+    // Set for the rows read from Kafka, the offset of their message is committed once every copy is released
+    pub message: Option<Arc<MessageAck>>,
+}
+
+impl RowAck {
+    pub fn message(message: Arc<MessageAck>) -> Self {
+        Self {
+            segment: None,
+            message: Some(message),
+        }
+    }
+
+    pub fn done(self) {
+        if let Some(segment) = self.segment {
+            segment.done();
+        }
+        // The offset moves on once the copies for the other sinks are released too
+        drop(self.message);
+    }
+}
+
+// The rows of a successful write are acknowledged and counted as written when the last clone of its hold is dropped
+#[derive(Default)]
+pub struct WriteHold {
+    on_durable: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+    abandoned: AtomicBool,
+}
+
+impl WriteHold {
+    fn on_durable(&self, callback: impl FnOnce() + Send + 'static) {
+        let mut on_durable = self.on_durable.lock().expect("Write hold lock is poisoned");
+        if self.abandoned.load(Ordering::Acquire) {
+            // Dropping the callback would release the acknowledgements it holds
+            std::mem::forget(callback);
+            return;
+        }
+        *on_durable = Some(Box::new(callback));
+    }
+
+    // For rows that were lost after the write, their offsets stay uncommitted and they are read again after a restart
+    pub fn abandon(&self) {
+        self.abandoned.store(true, Ordering::Release);
+        if let Some(callback) = self
+            .on_durable
+            .lock()
+            .expect("Write hold lock is poisoned")
+            .take()
+        {
+            std::mem::forget(callback);
+        }
+    }
+}
+
+impl Drop for WriteHold {
+    fn drop(&mut self) {
+        if let Some(callback) = self
+            .on_durable
+            .get_mut()
+            .expect("Write hold lock is poisoned")
+            .take()
+        {
+            callback();
+        }
+    }
+}
+
+// A row in the queue of a sink, the moment it was pushed and the span that produced it
+#[derive(Clone)]
+pub struct Queued<T> {
+    pub item: T,
+    pub queued_at: Instant,
+    pub span: SpanContext,
+    pub ack: RowAck,
+}
+
+impl<T> Queued<T> {
+    pub fn new(item: T, span: SpanContext) -> Self {
+        Self {
+            item,
+            queued_at: Instant::now(),
+            span,
+            ack: RowAck::default(),
+        }
+    }
+}
+
+// What the metrics need to know about a row
+pub trait SinkRow {
+    fn slot(&self) -> u64;
+
+    // The time the validator sent the row, the start of the end to end latency
+    fn retrieved_time(&self) -> NaiveDateTime;
+}
+
+impl SinkRow for DbAccountInfo {
+    fn slot(&self) -> u64 {
+        self.slot as u64
+    }
+
+    fn retrieved_time(&self) -> NaiveDateTime {
+        self.retrieved_time
+    }
+}
+
+impl SinkRow for DbBlockInfo {
+    fn slot(&self) -> u64 {
+        self.slot as u64
+    }
+
+    fn retrieved_time(&self) -> NaiveDateTime {
+        self.retrieved_time
+    }
+}
+
+impl SinkRow for UpdateSlotStatus {
+    fn slot(&self) -> u64 {
+        self.slot
+    }
+
+    fn retrieved_time(&self) -> NaiveDateTime {
+        self.retrieved_time
+    }
+}
+
+pub struct SinkQueues {
+    // The account rows keep their Kafka payload
+    pub keeps_source: bool,
+    pub accounts: Arc<SegQueue<Queued<DbAccountInfo>>>,
+    pub account_history: Arc<SegQueue<Queued<DbAccountInfo>>>,
+    pub blocks: Arc<SegQueue<Queued<DbBlockInfo>>>,
+    pub slots: Arc<SegQueue<Queued<UpdateSlotStatus>>>,
+    pub metrics: SinkMetrics,
+}
+
+pub struct SinkMetrics {
+    pub accounts: TableMetrics,
+    pub account_history: TableMetrics,
+    pub blocks: TableMetrics,
+    pub slots: TableMetrics,
+}
+
+impl SinkMetrics {
+    pub fn tables(&self) -> [&TableMetrics; 4] {
+        [
+            &self.accounts,
+            &self.account_history,
+            &self.blocks,
+            &self.slots,
+        ]
+    }
+}
+
+impl SinkQueues {
+    pub fn new(stats: &Stats, sink: &str, keeps_source: bool) -> Self {
+        Self {
+            keeps_source,
+            accounts: Arc::default(),
+            account_history: Arc::default(),
+            blocks: Arc::default(),
+            slots: Arc::default(),
+            metrics: SinkMetrics {
+                accounts: TableMetrics::new(stats, sink, "account"),
+                account_history: TableMetrics::new(stats, sink, "account_audit"),
+                blocks: TableMetrics::new(stats, sink, "block"),
+                slots: TableMetrics::new(stats, sink, "slot"),
+            },
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+            && self.account_history.is_empty()
+            && self.blocks.is_empty()
+            && self.slots.is_empty()
+    }
+
+    // Rows of all tables waiting for the sink, the spilled ones and the ones waiting for a retry included
+    pub fn len(&self) -> usize {
+        let off_queue: usize = self.metrics.tables().iter().map(|t| t.off_queue()).sum();
+        self.accounts.len()
+            + self.account_history.len()
+            + self.blocks.len()
+            + self.slots.len()
+            + off_queue
+    }
+}
+
+#[inline(always)]
+fn fan_out<T: Clone + ForSink>(
+    queues: &[Arc<SinkQueues>],
+    item: T,
+    span: SpanContext,
+    message: Arc<MessageAck>,
+    queue: fn(&SinkQueues) -> (&SegQueue<Queued<T>>, &TableMetrics),
+) {
+    // Every sink sees the same push time and holds the message until its copy is durable
+    let item = Queued {
+        ack: RowAck::message(message),
+        ..Queued::new(item, span)
+    };
+    let push = |q: &SinkQueues, mut item: Queued<T>| {
+        item.item.for_sink(q);
+        let (queue, metrics) = queue(q);
+        queue.push(item);
+        metrics.pushed.inc();
+    };
+
+    if let Some((last, rest)) = queues.split_last() {
+        rest.iter().for_each(|q| push(q, item.clone()));
+        push(last, item);
+    }
+}
+
+// Adjusts the copy of a row queued for a sink
+trait ForSink {
+    fn for_sink(&mut self, _queues: &SinkQueues) {}
+}
+
+impl ForSink for DbAccountInfo {
+    fn for_sink(&mut self, queues: &SinkQueues) {
+        if !queues.keeps_source {
+            self.source = None;
+        }
+    }
+}
+
+impl ForSink for DbBlockInfo {}
+
+impl ForSink for UpdateSlotStatus {}
+
+// Distributes the filtered rows to the queues of every configured sink
+pub struct Sinks {
+    queues: Vec<Arc<SinkQueues>>,
+    // Accounts go through the coalescer first when it is enabled
+    coalescer_queue: Option<Arc<SegQueue<CoalescerInput>>>,
+}
+
+// An account update waiting for the coalescer, with the span and the message it came from
+pub type CoalescerInput = (DbAccountInfo, SpanContext, Arc<MessageAck>);
+
+impl Sinks {
+    pub fn new(
+        queues: Vec<Arc<SinkQueues>>,
+        coalescer_queue: Option<Arc<SegQueue<CoalescerInput>>>,
+    ) -> Self {
+        Self {
+            queues,
+            coalescer_queue,
+        }
+    }
+
+    pub fn push_account(
+        &self,
+        account: DbAccountInfo,
+        span: SpanContext,
+        message: Arc<MessageAck>,
+    ) {
+        match &self.coalescer_queue {
+            Some(coalescer_queue) => coalescer_queue.push((account, span, message)),
+            None => self.publish_account(account, span, message),
+        }
+    }
+
+    // Bypasses the coalescer
+    pub fn publish_account(
+        &self,
+        account: DbAccountInfo,
+        span: SpanContext,
+        message: Arc<MessageAck>,
+    ) {
+        fan_out(&self.queues, account, span, message, |q| {
+            (&q.accounts, &q.metrics.accounts)
+        });
+    }
+
+    pub fn publish_account_history(
+        &self,
+        account: DbAccountInfo,
+        span: SpanContext,
+        message: Arc<MessageAck>,
+    ) {
+        fan_out(&self.queues, account, span, message, |q| {
+            (&q.account_history, &q.metrics.account_history)
+        });
+    }
+
+    pub fn push_block(&self, block: DbBlockInfo, span: SpanContext, message: Arc<MessageAck>) {
+        fan_out(&self.queues, block, span, message, |q| {
+            (&q.blocks, &q.metrics.blocks)
+        });
+    }
+
+    pub fn push_slot(&self, slot: UpdateSlotStatus, span: SpanContext, message: Arc<MessageAck>) {
+        fan_out(&self.queues, slot, span, message, |q| {
+            (&q.slots, &q.metrics.slots)
+        });
+    }
+}
+
+// Takes the next batch of one table and writes it in a separate task, returns false if there was nothing to do
+fn dispatch<T, F, Fut>(
+    sink: &Arc<dyn Sink>,
+    queue: &Arc<SegQueue<Queued<T>>>,
+    retry: &Arc<RetryQueue<T>>,
+    semaphore: &Option<Arc<Semaphore>>,
+    metrics: &TableMetrics,
+    table: &'static str,
+    write: F,
+) -> bool
+where
+    T: Serialize + SinkRow + Send + Sync + 'static,
+    F: FnOnce(Arc<dyn Sink>, Vec<T>, Arc<WriteHold>) -> Fut,
+    Fut: Future<Output = (Vec<T>, Result<()>)> + Send + 'static,
+{
+    let permit = match semaphore {
+        Some(semaphore) => match semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => return false,
+        },
+        None => None,
+    };
+
+    let mut batch = match retry.next_batch(queue, sink.batch_size()) {
+        Some(batch) => batch,
+        None => return false,
+    };
+
+    let items = std::mem::take(&mut batch.items);
+    metrics.popped.inc_by(items.len() as u64);
+    batch
+        .queue_wait
+        .iter()
+        .for_each(|wait| metrics.queue_wait.observe(wait.as_secs_f64()));
+    // Every traced row gets a span of its own for the write, in the trace of the message it came from
+    let tracer = tracer();
+    let mut spans: Vec<BoxedSpan> = batch
+        .spans
+        .iter()
+        .filter(|span| span.is_sampled())
+        .map(|span| {
+            tracer
+                .span_builder(format!("{} {table} write", sink.name()))
+                .with_kind(SpanKind::Client)
+                .with_attributes(vec![
+                    KeyValue::new("sink", sink.name().to_string()),
+                    KeyValue::new("table", table),
+                    KeyValue::new("batch.rows", items.len() as i64),
+                ])
+                .start_with_context(
+                    &tracer,
+                    &Context::new().with_remote_span_context(span.clone()),
+                )
+        })
+        .collect();
+    let started = Instant::now();
+    let hold = Arc::new(WriteHold::default());
+    let write = write(sink.clone(), items, hold.clone());
+    let sink = sink.clone();
+    let queue = queue.clone();
+    let retry = retry.clone();
+    let metrics = metrics.clone();
+
+    tokio::spawn(async move {
+        let (items, result) = write.await;
+        drop(permit);
+        metrics.db_execute.observe(started.elapsed().as_secs_f64());
+
+        if let Err(error) = &result {
+            let status = Status::error(format!("{error:#}"));
+            spans
+                .iter_mut()
+                .for_each(|span| span.set_status(status.clone()));
+        }
+        spans.iter_mut().for_each(|span| span.end());
+
+        let error = match result {
+            Ok(()) => {
+                let acks = std::mem::take(&mut batch.acks);
+                let rows = items.len() as u64;
+                let max_slot = items.iter().map(SinkRow::slot).max();
+                let retrieved: Vec<NaiveDateTime> =
+                    items.iter().map(SinkRow::retrieved_time).collect();
+                hold.on_durable(move || {
+                    acks.into_iter().for_each(RowAck::done);
+                    metrics.written.inc_by(rows);
+                    if let Some(slot) = max_slot {
+                        metrics
+                            .slot_written
+                            .inner()
+                            .fetch_max(slot, Ordering::Relaxed);
+                    }
+                    let committed = Utc::now().naive_utc();
+                    for retrieved_time in retrieved {
+                        // A validator clock ahead of ours is not a latency
+                        if let Ok(latency) = (committed - retrieved_time).to_std() {
+                            metrics.validator_to_commit.observe(latency.as_secs_f64());
+                        }
+                    }
+                });
+                return;
+            }
+            Err(error) => error,
+        };
+
+        let name = sink.name();
+        let kind = sink.classify_error(&error);
+        let rows = items.len();
+        error!("Failed to write {rows} row(s) to {name} {table}, error: {error:#}");
+        batch.items = items;
+        // Schedule the rows for a retry or quarantine them
+        let given_up = retry.failed(batch, kind, &error, &queue);
+        metrics.requeued.inc_by((rows - given_up) as u64);
+        metrics.failed.inc_by(given_up as u64);
+    });
+
+    true
+}
+
+pub async fn sink_executor(
+    config: Arc<FilterConfig>,
+    sink: Arc<dyn Sink>,
+    queues: Arc<SinkQueues>,
+    quarantine: Arc<Quarantine>,
+) {
+    let mut idle_interval = tokio::time::interval(Duration::from_millis(500));
+
+    let name = sink.name().to_string();
+    let policy = RetryPolicy::from_config(&config);
+    let account_retry = Arc::new(RetryQueue::new(
+        &name,
+        "account",
+        policy.clone(),
+        quarantine.clone(),
+        queues.metrics.accounts.retrying.clone(),
+    ));
+    let account_history_retry = Arc::new(RetryQueue::new(
+        &name,
+        "account_audit",
+        policy.clone(),
+        quarantine.clone(),
+        queues.metrics.account_history.retrying.clone(),
+    ));
+    let block_retry = Arc::new(RetryQueue::new(
+        &name,
+        "block",
+        policy.clone(),
+        quarantine.clone(),
+        queues.metrics.blocks.retrying.clone(),
+    ));
+    let slot_retry = Arc::new(RetryQueue::new(
+        &name,
+        "slot",
+        policy,
+        quarantine,
+        queues.metrics.slots.retrying.clone(),
+    ));
+
+    // Every table has its own limit so that one busy table doesn't starve the others
+    let semaphore = || {
+        sink.max_concurrent_writes()
+            .map(|permits| Arc::new(Semaphore::new(permits.max(1))))
+    };
+    let account_semaphore = semaphore();
+    let account_history_semaphore = semaphore();
+    let block_semaphore = semaphore();
+    let slot_semaphore = semaphore();
+
+    loop {
+        sink.ensure_connected().await;
+
+        if queues.is_empty()
+            && !account_retry.has_due()
+            && !account_history_retry.has_due()
+            && !block_retry.has_due()
+            && !slot_retry.has_due()
+        {
+            idle_interval.tick().await;
+        }
+
+        let mut dispatched = dispatch(
+            &sink,
+            &queues.accounts,
+            &account_retry,
+            &account_semaphore,
+            &queues.metrics.accounts,
+            "account",
+            |sink, rows, hold| async move {
+                let result = sink.write_accounts_held(&rows, &hold).await;
+                (rows, result)
+            },
+        );
+        dispatched |= dispatch(
+            &sink,
+            &queues.account_history,
+            &account_history_retry,
+            &account_history_semaphore,
+            &queues.metrics.account_history,
+            "account_audit",
+            |sink, rows, hold| async move {
+                let result = sink.write_account_history_held(&rows, &hold).await;
+                (rows, result)
+            },
+        );
+        dispatched |= dispatch(
+            &sink,
+            &queues.blocks,
+            &block_retry,
+            &block_semaphore,
+            &queues.metrics.blocks,
+            "block",
+            |sink, rows, hold| async move {
+                let result = sink.write_blocks_held(&rows, &hold).await;
+                (rows, result)
+            },
+        );
+        dispatched |= dispatch(
+            &sink,
+            &queues.slots,
+            &slot_retry,
+            &slot_semaphore,
+            &queues.metrics.slots,
+            "slot",
+            |sink, rows, hold| async move {
+                let result = sink.write_slots_held(&rows, &hold).await;
+                (rows, result)
+            },
+        );
+
+        if !dispatched {
+            // All writes are in flight or the rows are waiting for their backoff
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    fn durable_counter(hold: &WriteHold) -> Arc<AtomicUsize> {
+        let durable = Arc::new(AtomicUsize::new(0));
+        let counter = durable.clone();
+        hold.on_durable(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        durable
+    }
+
+    #[test]
+    fn runs_the_callback_once_the_last_hold_is_dropped() {
+        let hold = Arc::new(WriteHold::default());
+        let buffered = hold.clone();
+        let durable = durable_counter(&hold);
+
+        drop(hold);
+        assert_eq!(durable.load(Ordering::Relaxed), 0);
+        drop(buffered);
+        assert_eq!(durable.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn never_runs_the_callback_of_an_abandoned_write() {
+        let hold = Arc::new(WriteHold::default());
+        let durable = durable_counter(&hold);
+        hold.abandon();
+        drop(hold);
+        assert_eq!(durable.load(Ordering::Relaxed), 0);
+
+        // Also when the rows are lost before the write returns
+        let hold = Arc::new(WriteHold::default());
+        hold.abandon();
+        let durable = durable_counter(&hold);
+        drop(hold);
+        assert_eq!(durable.load(Ordering::Relaxed), 0);
+    }
+}
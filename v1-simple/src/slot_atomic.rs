@@ -0,0 +1,671 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use kafka_common::kafka_structs::UpdateSlotStatus;
+use log::error;
+use log::warn;
+use tokio_postgres::Client;
+use tokio_postgres::Statement;
+
+use crate::config::FilterConfig;
+use crate::db::initialize_db_client;
+use crate::db::DbAccountInfo;
+use crate::db::DbBlockInfo;
+use crate::db_inserts::insert_account_info;
+use crate::db_inserts::insert_into_block_metadata;
+use crate::db_inserts::insert_slot_status_internal;
+use crate::db_maintenance::MissingPartitions;
+use crate::db_retry::classify_postgres_error;
+use crate::db_retry::ErrorKind;
+use crate::db_retry::Quarantine;
+use crate::db_retry::RetryPolicy;
+use crate::db_statements::create_account_history_insert_statement;
+use crate::db_statements::create_account_insert_statement;
+use crate::db_statements::create_block_metadata_insert_statement;
+use crate::db_statements::create_slot_insert_statement_with_parent;
+use crate::db_statements::create_slot_insert_statement_without_parent;
+use crate::sink::Sink;
+use crate::sink::WriteHold;
+
+const FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct PendingSlot {
+    accounts: Vec<DbAccountInfo>,
+    account_history: Vec<DbAccountInfo>,
+    blocks: Vec<DbBlockInfo>,
+    slots: Vec<UpdateSlotStatus>,
+    block_received: Option<Instant>,
+    // The writes the rows came from, they are acknowledged once the slot is committed or quarantined
+    holds: Vec<Arc<WriteHold>>,
+}
+
+impl PendingSlot {
+    fn len(&self) -> usize {
+        self.accounts.len() + self.account_history.len() + self.blocks.len() + self.slots.len()
+    }
+
+    fn release(mut self) {
+        self.holds.clear();
+    }
+}
+
+// A slot dropped without being released was never written, e.g. on a shutdown timeout
+impl Drop for PendingSlot {
+    fn drop(&mut self) {
+        self.holds.iter().for_each(|hold| hold.abandon());
+    }
+}
+
+#[derive(Default)]
+struct SlotBuffer {
+    pending: BTreeMap<u64, PendingSlot>,
+    // Slots written recently, rows arriving for them later are written without waiting
+    flushed: BTreeSet<u64>,
+    newest_slot: u64,
+    rows: usize,
+}
+
+impl SlotBuffer {
+    // The pending slot a row of the write is added to
+    fn slot(&mut self, slot: u64, hold: &Arc<WriteHold>) -> &mut PendingSlot {
+        self.newest_slot = self.newest_slot.max(slot);
+        self.rows += 1;
+        let pending = self.pending.entry(slot).or_default();
+        if !pending
+            .holds
+            .last()
+            .is_some_and(|last| Arc::ptr_eq(last, hold))
+        {
+            pending.holds.push(hold.clone());
+        }
+        pending
+    }
+
+    // Removes the slots that can be written, in slot order
+    fn take_ready(
+        &mut self,
+        grace: Duration,
+        max_pending_slots: u64,
+        max_pending_rows: usize,
+        force: bool,
+    ) -> Vec<(u64, PendingSlot)> {
+        let now = Instant::now();
+        let oldest_pending = self.newest_slot.saturating_sub(max_pending_slots);
+
+        let mut ready: BTreeSet<u64> = self
+            .pending
+            .iter()
+            .filter(|(slot, pending)| {
+                force
+                    || **slot < oldest_pending
+                    || self.flushed.contains(slot)
+                    || pending
+                        .block_received
+                        .is_some_and(|received| now.duration_since(received) >= grace)
+            })
+            .map(|(slot, _)| *slot)
+            .collect();
+
+        // The oldest slots are written early while too many rows are pending
+        let mut remaining = self.rows
+            - ready
+                .iter()
+                .map(|slot| self.pending[slot].len())
+                .sum::<usize>();
+        for (slot, pending) in &self.pending {
+            if remaining < max_pending_rows {
+                break;
+            }
+            if ready.insert(*slot) {
+                remaining -= pending.len();
+            }
+        }
+        self.rows = remaining;
+
+        self.flushed = self.flushed.split_off(&oldest_pending);
+        ready
+            .into_iter()
+            .map(|slot| {
+                self.flushed.insert(slot);
+                (slot, self.pending.remove(&slot).unwrap_or_default())
+            })
+            .collect()
+    }
+}
+
+struct SlotStatements {
+    account: Statement,
+    account_history: Option<Statement>,
+    block: Statement,
+    slot_with_parent: Statement,
+    slot_without_parent: Statement,
+}
+
+impl SlotStatements {
+    async fn prepare(client: Arc<Client>, config: &FilterConfig) -> Result<Self> {
+        let account_history = match config.persistence_mode.writes_history() {
+            true => Some(create_account_history_insert_statement(client.clone()).await?),
+            false => None,
+        };
+
+        Ok(Self {
+            account: create_account_insert_statement(client.clone(), config.persistence_mode)
+                .await?,
+            account_history,
+            block: create_block_metadata_insert_statement(client.clone()).await?,
+            slot_with_parent: create_slot_insert_statement_with_parent(client.clone()).await?,
+            slot_without_parent: create_slot_insert_statement_without_parent(client).await?,
+        })
+    }
+}
+
+// A single row of a slot, rows are written in the order of this enum
+enum SlotRow<'a> {
+    AccountHistory(&'a DbAccountInfo),
+    Account(&'a DbAccountInfo),
+    Block(&'a DbBlockInfo),
+    Slot(&'a UpdateSlotStatus),
+}
+
+impl<'a> SlotRow<'a> {
+    fn collect(pending: &'a PendingSlot) -> Vec<SlotRow<'a>> {
+        pending
+            .account_history
+            .iter()
+            .map(SlotRow::AccountHistory)
+            .chain(pending.accounts.iter().map(SlotRow::Account))
+            .chain(pending.blocks.iter().map(SlotRow::Block))
+            .chain(pending.slots.iter().map(SlotRow::Slot))
+            .collect()
+    }
+
+    async fn write(&self, client: Arc<Client>, statements: &SlotStatements) -> Result<()> {
+        match self {
+            SlotRow::AccountHistory(account) => {
+                if let Some(statement) = &statements.account_history {
+                    insert_account_info(account, statement, client).await?;
+                }
+            }
+            SlotRow::Account(account) => {
+                insert_account_info(account, &statements.account, client).await?;
+            }
+            SlotRow::Block(block) => {
+                insert_into_block_metadata(block, &statements.block, client).await?;
+            }
+            SlotRow::Slot(slot) => {
+                let statement = match slot.parent {
+                    Some(_) => &statements.slot_with_parent,
+                    None => &statements.slot_without_parent,
+                };
+                insert_slot_status_internal(slot, statement, client).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn quarantine(&self, quarantine: &Quarantine, attempts: u32, error: &str) -> Result<()> {
+        match self {
+            SlotRow::AccountHistory(row) => {
+                quarantine.write("postgres", "account_audit", attempts, error, row)
+            }
+            SlotRow::Account(row) => quarantine.write("postgres", "account", attempts, error, row),
+            SlotRow::Block(row) => quarantine.write("postgres", "block", attempts, error, row),
+            SlotRow::Slot(row) => quarantine.write("postgres", "slot", attempts, error, row),
+        }
+    }
+}
+
+struct SlotWriter {
+    config: Arc<FilterConfig>,
+    client: Arc<Client>,
+    // Prepared once per connection
+    statements: Option<SlotStatements>,
+    policy: RetryPolicy,
+    quarantine: Arc<Quarantine>,
+    missing_partitions: Arc<MissingPartitions>,
+}
+
+impl SlotWriter {
+    // Writes all rows of a slot in one transaction.
+    // In the isolated mode every row gets its own savepoint, so rows that can never be written are left out.
+    async fn write_slot(
+        &mut self,
+        rows: &[SlotRow<'_>],
+        isolated: bool,
+    ) -> Result<Vec<(usize, anyhow::Error)>> {
+        if self.statements.is_none() {
+            self.statements =
+                Some(SlotStatements::prepare(self.client.clone(), &self.config).await?);
+        }
+        let statements = self.statements.as_ref().expect("Statements are prepared");
+
+        let mut rejected = Vec::new();
+        self.client.batch_execute("BEGIN").await?;
+
+        for (index, row) in rows.iter().enumerate() {
+            if !isolated {
+                row.write(self.client.clone(), statements).await?;
+                continue;
+            }
+
+            self.client.batch_execute("SAVEPOINT row").await?;
+            match row.write(self.client.clone(), statements).await {
+                Ok(()) => self.client.batch_execute("RELEASE SAVEPOINT row").await?,
+                Err(e) if classify_postgres_error(&e) == ErrorKind::Permanent => {
+                    self.client
+                        .batch_execute("ROLLBACK TO SAVEPOINT row")
+                        .await?;
+                    rejected.push((index, e));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.client.batch_execute("COMMIT").await?;
+        Ok(rejected)
+    }
+
+    // Doesn't return until the slot is committed or quarantined, the next slots wait meanwhile
+    async fn commit(&mut self, slot: u64, pending: PendingSlot) {
+        self.write_or_quarantine(slot, &pending).await;
+        pending.release();
+    }
+
+    async fn write_or_quarantine(&mut self, slot: u64, pending: &PendingSlot) {
+        let rows = SlotRow::collect(pending);
+        let mut attempts = 0;
+        let mut isolated = false;
+        let mut stalled_since = None;
+
+        loop {
+            let error = match self.write_slot(&rows, isolated).await {
+                Ok(rejected) => {
+                    for (index, e) in rejected {
+                        self.quarantine(slot, &rows[index], attempts + 1, &e);
+                    }
+                    return;
+                }
+                Err(e) => e,
+            };
+
+            if self.client.is_closed() {
+                warn!("Postgres client was unexpectedly closed");
+                self.client = initialize_db_client(self.config.clone()).await;
+                self.statements = None;
+            } else if let Err(e) = self.client.batch_execute("ROLLBACK").await {
+                error!("Failed to roll back slot {slot}, error: {e}");
+            }
+
+            let kind = classify_postgres_error(&error);
+            if kind == ErrorKind::MissingPartition {
+                self.missing_partitions.report(&error);
+            }
+            if matches!(kind, ErrorKind::MissingPartition | ErrorKind::Misconfigured) {
+                stalled_since.get_or_insert_with(Instant::now);
+            }
+            let may_stall = stalled_since.is_none_or(|since| self.policy.may_stall(since));
+
+            let backoff = match kind {
+                // Does not count as an attempt, the slot waits for the reconnect
+                ErrorKind::Connection => self.policy.backoff,
+                // Does not count as an attempt either, the slot waits for the maintenance task
+                ErrorKind::MissingPartition if may_stall => self.policy.backoff,
+                ErrorKind::Misconfigured if may_stall => self.policy.max_backoff,
+                ErrorKind::Permanent if !isolated => {
+                    warn!("Writing slot {slot} failed permanently, retrying its rows one by one: {error:?}");
+                    isolated = true;
+                    continue;
+                }
+                ErrorKind::Transient if attempts < self.policy.max_retries => {
+                    attempts += 1;
+                    self.policy.backoff(attempts)
+                }
+                kind => {
+                    error!(
+                        "Giving up on slot {slot} after {} attempt(s), {kind:?} error: {error:#}",
+                        attempts + 1
+                    );
+                    for row in &rows {
+                        self.quarantine(slot, row, attempts + 1, &error);
+                    }
+                    return;
+                }
+            };
+
+            error!("Failed to write slot {slot}, attempt {attempts}, retrying in {backoff:?}: {error:?}");
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    fn quarantine(&self, slot: u64, row: &SlotRow<'_>, attempts: u32, error: &anyhow::Error) {
+        if let Err(e) = row.quarantine(&self.quarantine, attempts, &format!("{error:#}")) {
+            error!("Failed to quarantine a row of slot {slot}, error: {e}");
+        }
+    }
+}
+
+// Collects the rows of every slot and writes each slot to Postgres in a single transaction,
+// once its block has arrived and `slot_atomic_grace_ms` has passed for the rows of the other topics.
+// Slots that fall more than `slot_atomic_max_pending_slots` behind the newest one are written as they are.
+pub struct SlotAtomicSink {
+    max_pending_rows: usize,
+    buffer: Arc<Mutex<SlotBuffer>>,
+    writer: Arc<tokio::sync::Mutex<SlotWriter>>,
+}
+
+impl SlotAtomicSink {
+    pub async fn new(
+        config: Arc<FilterConfig>,
+        quarantine: Arc<Quarantine>,
+        missing_partitions: Arc<MissingPartitions>,
+    ) -> Self {
+        let buffer = Arc::new(Mutex::new(SlotBuffer::default()));
+        let writer = Arc::new(tokio::sync::Mutex::new(SlotWriter {
+            client: initialize_db_client(config.clone()).await,
+            statements: None,
+            policy: RetryPolicy::from_config(&config),
+            config: config.clone(),
+            quarantine,
+            missing_partitions,
+        }));
+
+        let max_pending_rows = config.slot_atomic_max_pending_rows;
+        tokio::spawn(slot_flusher(config, buffer.clone(), writer.clone()));
+
+        Self {
+            max_pending_rows,
+            buffer,
+            writer,
+        }
+    }
+
+    fn buffer(&self) -> std::sync::MutexGuard<'_, SlotBuffer> {
+        self.buffer.lock().expect("Slot buffer lock is poisoned")
+    }
+
+    // New rows wait while the flusher writes the oldest slots
+    async fn wait_for_room(&self) {
+        while self.buffer().rows >= self.max_pending_rows {
+            tokio::time::sleep(FLUSH_INTERVAL).await;
+        }
+    }
+}
+
+async fn slot_flusher(
+    config: Arc<FilterConfig>,
+    buffer: Arc<Mutex<SlotBuffer>>,
+    writer: Arc<tokio::sync::Mutex<SlotWriter>>,
+) {
+    let grace = Duration::from_millis(config.slot_atomic_grace_ms);
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let ready = buffer
+            .lock()
+            .expect("Slot buffer lock is poisoned")
+            .take_ready(
+                grace,
+                config.slot_atomic_max_pending_slots,
+                config.slot_atomic_max_pending_rows,
+                false,
+            );
+
+        if ready.is_empty() {
+            continue;
+        }
+
+        let mut writer = writer.lock().await;
+        for (slot, pending) in ready {
+            writer.commit(slot, pending).await;
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for SlotAtomicSink {
+    fn name(&self) -> &str {
+        "postgres"
+    }
+
+    fn batch_size(&self) -> usize {
+        1000
+    }
+
+    async fn write_accounts(&self, accounts: &[DbAccountInfo]) -> Result<()> {
+        self.write_accounts_held(accounts, &Arc::default()).await
+    }
+
+    async fn write_account_history(&self, accounts: &[DbAccountInfo]) -> Result<()> {
+        self.write_account_history_held(accounts, &Arc::default())
+            .await
+    }
+
+    async fn write_blocks(&self, blocks: &[DbBlockInfo]) -> Result<()> {
+        self.write_blocks_held(blocks, &Arc::default()).await
+    }
+
+    async fn write_slots(&self, slots: &[UpdateSlotStatus]) -> Result<()> {
+        self.write_slots_held(slots, &Arc::default()).await
+    }
+
+    async fn write_accounts_held(
+        &self,
+        accounts: &[DbAccountInfo],
+        hold: &Arc<WriteHold>,
+    ) -> Result<()> {
+        self.wait_for_room().await;
+        let mut buffer = self.buffer();
+        for account in accounts {
+            buffer
+                .slot(account.slot as u64, hold)
+                .accounts
+                .push(account.clone());
+        }
+        Ok(())
+    }
+
+    async fn write_account_history_held(
+        &self,
+        accounts: &[DbAccountInfo],
+        hold: &Arc<WriteHold>,
+    ) -> Result<()> {
+        self.wait_for_room().await;
+        let mut buffer = self.buffer();
+        for account in accounts {
+            buffer
+                .slot(account.slot as u64, hold)
+                .account_history
+                .push(account.clone());
+        }
+        Ok(())
+    }
+
+    async fn write_blocks_held(&self, blocks: &[DbBlockInfo], hold: &Arc<WriteHold>) -> Result<()> {
+        self.wait_for_room().await;
+        let mut buffer = self.buffer();
+        for block in blocks {
+            let pending = buffer.slot(block.slot as u64, hold);
+            pending.blocks.push(block.clone());
+            pending.block_received.get_or_insert_with(Instant::now);
+        }
+        Ok(())
+    }
+
+    async fn write_slots_held(
+        &self,
+        slots: &[UpdateSlotStatus],
+        hold: &Arc<WriteHold>,
+    ) -> Result<()> {
+        self.wait_for_room().await;
+        let mut buffer = self.buffer();
+        for slot in slots {
+            buffer.slot(slot.slot, hold).slots.push(slot.clone());
+        }
+        Ok(())
+    }
+
+    // Writes the slots still waiting for their blocks
+    async fn close(&self) {
+        let ready = self.buffer().take_ready(Duration::ZERO, 0, 0, true);
+
+        // The slots left unwritten are not acknowledged, they are read again after a restart
+        let flush = async {
+            let mut writer = self.writer.lock().await;
+            for (slot, pending) in ready {
+                writer.commit(slot, pending).await;
+            }
+        };
+
+        if tokio::time::timeout(CLOSE_TIMEOUT, flush).await.is_err() {
+            error!("Timed out writing the pending slots on shutdown");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn block(slot: u64) -> DbBlockInfo {
+        DbBlockInfo {
+            slot: slot as i64,
+            blockhash: String::new(),
+            rewards: Vec::new(),
+            block_time: None,
+            block_height: None,
+            retrieved_time: Utc::now().naive_utc(),
+        }
+    }
+
+    // A row of the slot without its block
+    fn add_row(buffer: &mut SlotBuffer, slot: u64) {
+        buffer
+            .slot(slot, &Arc::default())
+            .account_history
+            .push(DbAccountInfo {
+                pubkey: vec![0],
+                lamports: 0,
+                owner: vec![0],
+                executable: false,
+                rent_epoch: 0,
+                data: Vec::new(),
+                slot: slot as i64,
+                write_version: 0,
+                txn_signature: None,
+                is_startup: false,
+                retrieved_time: Utc::now().naive_utc(),
+                source: None,
+            });
+    }
+
+    fn add_block(buffer: &mut SlotBuffer, slot: u64) {
+        let pending = buffer.slot(slot, &Arc::default());
+        pending.blocks.push(block(slot));
+        pending.block_received.get_or_insert_with(Instant::now);
+    }
+
+    fn take_ready(buffer: &mut SlotBuffer, grace: Duration) -> Vec<u64> {
+        buffer
+            .take_ready(grace, 10, 1000, false)
+            .into_iter()
+            .map(|(slot, _)| slot)
+            .collect()
+    }
+
+    #[test]
+    fn waits_for_the_block_and_the_grace_period() {
+        let mut buffer = SlotBuffer::default();
+        add_row(&mut buffer, 100);
+        add_row(&mut buffer, 101);
+        assert!(take_ready(&mut buffer, Duration::ZERO).is_empty());
+
+        add_block(&mut buffer, 101);
+        assert!(take_ready(&mut buffer, Duration::from_secs(60)).is_empty());
+        assert_eq!(take_ready(&mut buffer, Duration::ZERO), vec![101]);
+        assert_eq!(buffer.rows, 1);
+    }
+
+    #[test]
+    fn writes_slots_far_behind_the_newest_one_without_their_blocks() {
+        let mut buffer = SlotBuffer::default();
+        add_row(&mut buffer, 100);
+        add_row(&mut buffer, 105);
+        add_row(&mut buffer, 110);
+        assert!(take_ready(&mut buffer, Duration::ZERO).is_empty());
+
+        add_row(&mut buffer, 112);
+        assert_eq!(take_ready(&mut buffer, Duration::ZERO), vec![100]);
+    }
+
+    #[test]
+    fn writes_late_rows_of_a_written_slot_at_once() {
+        let mut buffer = SlotBuffer::default();
+        add_block(&mut buffer, 100);
+        assert_eq!(take_ready(&mut buffer, Duration::ZERO), vec![100]);
+
+        add_row(&mut buffer, 100);
+        assert_eq!(take_ready(&mut buffer, Duration::from_secs(60)), vec![100]);
+    }
+
+    #[test]
+    fn writes_the_oldest_slots_early_when_too_many_rows_are_pending() {
+        let mut buffer = SlotBuffer::default();
+        for slot in [100, 100, 101, 102, 102] {
+            add_row(&mut buffer, slot);
+        }
+
+        let ready: Vec<u64> = buffer
+            .take_ready(Duration::ZERO, 10, 3, false)
+            .into_iter()
+            .map(|(slot, pending)| {
+                assert_eq!(pending.len(), if slot == 100 { 2 } else { 1 });
+                slot
+            })
+            .collect();
+        assert_eq!(ready, vec![100, 101]);
+        assert_eq!(buffer.rows, 2);
+    }
+
+    #[test]
+    fn takes_every_slot_in_order_when_forced() {
+        let mut buffer = SlotBuffer::default();
+        for slot in [103, 101, 102] {
+            add_row(&mut buffer, slot);
+        }
+
+        let ready: Vec<u64> = buffer
+            .take_ready(Duration::ZERO, 0, 0, true)
+            .into_iter()
+            .map(|(slot, _)| slot)
+            .collect();
+        assert_eq!(ready, vec![101, 102, 103]);
+        assert_eq!(buffer.rows, 0);
+    }
+
+    #[test]
+    fn holds_every_write_of_a_slot_once() {
+        let mut buffer = SlotBuffer::default();
+        let first = Arc::default();
+        let second = Arc::default();
+        buffer.slot(100, &first);
+        buffer.slot(100, &first);
+        buffer.slot(100, &second);
+
+        let (_, pending) = buffer.take_ready(Duration::ZERO, 0, 0, true).remove(0);
+        assert_eq!(pending.holds.len(), 2);
+        pending.release();
+        assert_eq!(Arc::strong_count(&first), 1);
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use kafka_common::kafka_structs::KafkaSlotStatus;
+use kafka_common::kafka_structs::UpdateSlotStatus;
+use log::warn;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::gauge::Gauge;
+
+use crate::consumer_stats::labels;
+use crate::consumer_stats::Stats;
+use crate::consumer_stats::SLOT_STATUSES;
+use crate::sink::SinkQueues;
+
+// The slot updates are not strictly ordered, a rooted slot waits this many slots for its parent
+const ROOTED_PARENT_WINDOW: u64 = 32;
+
+// The rooted slots form a chain, every root is the parent of the next one
+#[derive(Default)]
+struct RootedChain {
+    // The newest root of the unbroken chain
+    head: Option<u64>,
+    // Roots above the head, by slot, with their parents
+    pending: BTreeMap<u64, u64>,
+}
+
+impl RootedChain {
+    // Returns the parents of the roots that were never seen rooted
+    fn push(&mut self, slot: u64, parent: Option<u64>) -> Vec<(u64, u64)> {
+        match self.head {
+            // A late or repeated root
+            Some(head) if slot <= head => return Vec::new(),
+            Some(_) => (),
+            None => {
+                self.head = Some(slot);
+                return Vec::new();
+            }
+        }
+
+        match parent {
+            Some(parent) => {
+                self.pending.insert(slot, parent);
+            }
+            // Without the parent there is nothing to check
+            None => {
+                self.head = Some(slot);
+                self.pending.retain(|pending, _| *pending > slot);
+            }
+        }
+
+        let mut missing = Vec::new();
+        while let Some(head) = self.head {
+            let linked = self
+                .pending
+                .iter()
+                .find(|(_, parent)| **parent <= head)
+                .map(|(slot, _)| *slot);
+            if let Some(slot) = linked {
+                self.pending.remove(&slot);
+                self.head = Some(slot);
+                continue;
+            }
+
+            let (oldest, newest) =
+                match (self.pending.keys().next(), self.pending.keys().next_back()) {
+                    (Some(oldest), Some(newest)) => (*oldest, *newest),
+                    _ => break,
+                };
+            if newest - oldest <= ROOTED_PARENT_WINDOW {
+                break;
+            }
+
+            // The parent is not coming anymore, the chain continues from the orphan
+            if let Some(parent) = self.pending.remove(&oldest) {
+                missing.push((parent, oldest));
+            }
+            self.head = Some(oldest);
+        }
+        missing
+    }
+}
+
+// Tracks the slot updates seen by the slot filter
+pub struct SlotProgress {
+    processed: Gauge<u64, AtomicU64>,
+    confirmed: Gauge<u64, AtomicU64>,
+    rooted: Gauge<u64, AtomicU64>,
+    rooted_gaps: Counter<u64, AtomicU64>,
+    chain: RootedChain,
+}
+
+impl SlotProgress {
+    pub fn new(stats: &Stats) -> Self {
+        let seen = |status| {
+            stats
+                .slot_seen
+                .get_or_create(&labels(&[("status", status)]))
+                .clone()
+        };
+        Self {
+            processed: seen("processed"),
+            confirmed: seen("confirmed"),
+            rooted: seen("rooted"),
+            rooted_gaps: stats.slot_rooted_gaps.clone(),
+            chain: RootedChain::default(),
+        }
+    }
+
+    pub fn update(&mut self, update_slot: &UpdateSlotStatus) {
+        let seen = match update_slot.status {
+            KafkaSlotStatus::Processed => &self.processed,
+            KafkaSlotStatus::Confirmed => &self.confirmed,
+            KafkaSlotStatus::Rooted => &self.rooted,
+        };
+        seen.inner().fetch_max(update_slot.slot, Ordering::Relaxed);
+
+        if let KafkaSlotStatus::Rooted = update_slot.status {
+            for (parent, slot) in self.chain.push(update_slot.slot, update_slot.parent) {
+                warn!("Rooted slot {parent}, the parent of rooted slot {slot}, was never received");
+                self.rooted_gaps.inc();
+            }
+        }
+    }
+}
+
+// Every second sets how far every table of every sink is behind the slots seen by the slot filter
+pub async fn sample_slot_lag(stats: Arc<Stats>, sink_queues: Vec<Arc<SinkQueues>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let seen = SLOT_STATUSES.map(|status| {
+        stats
+            .slot_seen
+            .get_or_create(&labels(&[("status", status)]))
+            .clone()
+    });
+
+    loop {
+        interval.tick().await;
+        let seen: Vec<u64> = seen.iter().map(Gauge::get).collect();
+        for queues in &sink_queues {
+            for table in queues.metrics.tables() {
+                let written = table.slot_written.get();
+                // Nothing written yet, the lag is unknown
+                if written == 0 {
+                    continue;
+                }
+                for (lag, seen) in table.slot_lag.iter().zip(&seen) {
+                    lag.set(seen.saturating_sub(written));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(head: u64) -> RootedChain {
+        let mut chain = RootedChain::default();
+        assert!(chain.push(head, None).is_empty());
+        chain
+    }
+
+    #[test]
+    fn follows_consecutive_roots() {
+        let mut chain = chain(100);
+        assert!(chain.push(101, Some(100)).is_empty());
+        // A skipped slot is not a gap, the parent of the root is still the previous root
+        assert!(chain.push(103, Some(101)).is_empty());
+        assert_eq!(chain.head, Some(103));
+        assert!(chain.pending.is_empty());
+    }
+
+    #[test]
+    fn ignores_late_and_repeated_roots() {
+        let mut chain = chain(100);
+        assert!(chain.push(100, Some(99)).is_empty());
+        assert!(chain.push(90, Some(89)).is_empty());
+        assert_eq!(chain.head, Some(100));
+        assert!(chain.pending.is_empty());
+    }
+
+    #[test]
+    fn links_roots_that_arrive_out_of_order() {
+        let mut chain = chain(100);
+        assert!(chain.push(102, Some(101)).is_empty());
+        assert_eq!(chain.head, Some(100));
+
+        assert!(chain.push(101, Some(100)).is_empty());
+        assert_eq!(chain.head, Some(102));
+        assert!(chain.pending.is_empty());
+    }
+
+    #[test]
+    fn reports_a_root_whose_parent_never_came() {
+        let mut chain = chain(100);
+        assert!(chain.push(105, Some(103)).is_empty());
+        assert!(chain.push(120, Some(119)).is_empty());
+
+        // The orphan is given up on once the roots move past the window
+        assert_eq!(
+            chain.push(
+                100 + ROOTED_PARENT_WINDOW + 6,
+                Some(100 + ROOTED_PARENT_WINDOW + 5)
+            ),
+            vec![(103, 105)]
+        );
+        assert_eq!(chain.head, Some(105));
+        assert_eq!(chain.pending.len(), 2);
+    }
+
+    #[test]
+    fn a_root_without_a_parent_moves_the_head() {
+        let mut chain = chain(100);
+        chain.push(105, Some(103));
+        assert!(chain.push(110, None).is_empty());
+        assert_eq!(chain.head, Some(110));
+        assert!(chain.pending.is_empty());
+    }
+}
@@ -0,0 +1,696 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use crossbeam_queue::SegQueue;
+use log::error;
+use log::info;
+use log::warn;
+use opentelemetry::trace::SpanContext;
+use prometheus_client::metrics::gauge::Gauge;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::config::FilterConfig;
+use crate::consumer_stats::TableMetrics;
+use crate::sink::Queued;
+use crate::sink::RowAck;
+use crate::sink::SinkQueues;
+
+const SEGMENT_EXTENSION: &str = "seg";
+
+struct Segment {
+    id: u64,
+    bytes: u64,
+    rows: u64,
+}
+
+// The records of a segment left from a previous run, only their length prefixes are read
+fn count_records(path: &Path) -> Result<u64> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut offset = 0;
+    let mut rows = 0;
+    let mut prefix = [0u8; 4];
+
+    while offset + 4 <= len {
+        file.read_exact(&mut prefix)?;
+        offset += 4 + u32::from_le_bytes(prefix) as u64;
+        if offset > len {
+            break;
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        rows += 1;
+    }
+    Ok(rows)
+}
+
+// Records appended to the segment being written since it was last synced
+#[derive(Default)]
+struct Unsynced {
+    rows: u64,
+    bytes: u64,
+}
+
+struct SpillState {
+    // Oldest segment first, the last one is the segment currently being written
+    segments: VecDeque<Segment>,
+    writer: Option<BufWriter<File>>,
+    next_id: u64,
+}
+
+// The rows of a replayed segment that are not written yet.
+// The segment file is removed once the last of them is written or quarantined,
+// until then the whole segment is replayed again after a restart.
+pub struct SegmentAck {
+    path: PathBuf,
+    bytes: u64,
+    remaining: AtomicUsize,
+    total_bytes: Arc<AtomicU64>,
+}
+
+impl SegmentAck {
+    pub fn done(&self) {
+        if self.remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.remove();
+        }
+    }
+
+    fn remove(&self) {
+        match fs::remove_file(&self.path) {
+            Ok(()) => {
+                self.total_bytes.fetch_sub(self.bytes, Ordering::Relaxed);
+            }
+            Err(e) => error!(
+                "Failed to remove spill segment {}, error: {e}",
+                self.path.display()
+            ),
+        }
+    }
+}
+
+// Append-only queue of length-prefixed bincode records split into segment files.
+// Segments are consumed whole, oldest first, and removed from disk once all of their rows are written.
+// The consumers commit the offset of a message only once its rows are written, quarantined or synced here,
+// so the rows that were still in memory are read from Kafka again after a restart.
+pub struct SpillQueue<T> {
+    dir: PathBuf,
+    segment_max_bytes: u64,
+    max_bytes: u64,
+    state: Mutex<SpillState>,
+    // Includes the replayed segments that are not acknowledged yet
+    total_bytes: Arc<AtomicU64>,
+    // The rows on disk that were not replayed yet
+    spilled: Gauge<u64, AtomicU64>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> SpillQueue<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    pub fn open(
+        dir: impl AsRef<Path>,
+        segment_max_bytes: u64,
+        max_bytes: u64,
+        spilled: Gauge<u64, AtomicU64>,
+    ) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+
+            match path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                Some(id) => segments.push(Segment {
+                    id,
+                    bytes: fs::metadata(&path)?.len(),
+                    rows: count_records(&path)?,
+                }),
+                None => warn!(
+                    "Ignoring unexpected file in spill queue: {}",
+                    path.display()
+                ),
+            }
+        }
+        segments.sort_by_key(|s| s.id);
+
+        let total_bytes = segments.iter().map(|s| s.bytes).sum();
+        spilled.set(segments.iter().map(|s| s.rows).sum());
+        let next_id = segments.last().map(|s| s.id + 1).unwrap_or(0);
+
+        if !segments.is_empty() {
+            info!(
+                "Found {} spilled segment(s), {} bytes in {}, they will be replayed",
+                segments.len(),
+                total_bytes,
+                dir.display()
+            );
+        }
+
+        Ok(Self {
+            dir,
+            segment_max_bytes,
+            max_bytes,
+            state: Mutex::new(SpillState {
+                segments: segments.into(),
+                writer: None,
+                next_id,
+            }),
+            total_bytes: Arc::new(AtomicU64::new(total_bytes)),
+            spilled,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state
+            .lock()
+            .expect("Spill queue lock is poisoned")
+            .segments
+            .is_empty()
+    }
+
+    fn segment_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{id:020}.{SEGMENT_EXTENSION}"))
+    }
+
+    // Appends records until the size limit is reached.
+    // Returns the number of records that were synced to disk, the rest must be kept by the caller,
+    // and the error that stopped the batch early. The records after the last sync are cut off the segment then.
+    pub fn push_batch(&self, items: &[T]) -> (usize, Option<anyhow::Error>) {
+        let mut state = self.state.lock().expect("Spill queue lock is poisoned");
+        let mut unsynced = Unsynced::default();
+        let mut persisted = 0;
+
+        match self.append(&mut state, items, &mut unsynced, &mut persisted) {
+            Ok(()) => (persisted, None),
+            Err(e) => {
+                if let Err(e) = self.discard_unsynced(&mut state, &unsynced) {
+                    error!(
+                        "Failed to truncate the spill segment in {}, it may replay rows twice, error: {e}",
+                        self.dir.display()
+                    );
+                }
+                (persisted, Some(e))
+            }
+        }
+    }
+
+    fn append(
+        &self,
+        state: &mut SpillState,
+        items: &[T],
+        unsynced: &mut Unsynced,
+        persisted: &mut usize,
+    ) -> Result<()> {
+        let mut total_bytes = self.total_bytes.load(Ordering::Relaxed);
+
+        for item in items {
+            let record = bincode::serialize(item)?;
+            let record_len = (record.len() + 4) as u64;
+
+            if total_bytes + record_len > self.max_bytes {
+                break;
+            }
+
+            let rotate = match state.segments.back() {
+                Some(segment) => state.writer.is_none() || segment.bytes >= self.segment_max_bytes,
+                None => true,
+            };
+
+            if rotate {
+                if let Some(writer) = state.writer.as_mut() {
+                    writer.flush()?;
+                    writer.get_ref().sync_data()?;
+                }
+                state.writer = None;
+                *persisted += unsynced.rows as usize;
+                *unsynced = Unsynced::default();
+
+                let id = state.next_id;
+                let file = OpenOptions::new()
+                    .create_new(true)
+                    .append(true)
+                    .open(self.segment_path(id))?;
+
+                state.writer = Some(BufWriter::new(file));
+                state.segments.push_back(Segment {
+                    id,
+                    bytes: 0,
+                    rows: 0,
+                });
+                state.next_id += 1;
+            }
+
+            let writer = state
+                .writer
+                .as_mut()
+                .expect("Spill segment writer is not open");
+            writer.write_all(&(record.len() as u32).to_le_bytes())?;
+            writer.write_all(&record)?;
+
+            if let Some(segment) = state.segments.back_mut() {
+                segment.bytes += record_len;
+                segment.rows += 1;
+            }
+            self.spilled.inc();
+            self.total_bytes.fetch_add(record_len, Ordering::Relaxed);
+            total_bytes += record_len;
+            unsynced.rows += 1;
+            unsynced.bytes += record_len;
+        }
+
+        if let Some(writer) = state.writer.as_mut() {
+            writer.flush()?;
+            writer.get_ref().sync_data()?;
+        }
+        *persisted += unsynced.rows as usize;
+        *unsynced = Unsynced::default();
+
+        Ok(())
+    }
+
+    // Drops the buffered records and cuts the segment back to its last synced record,
+    // the next records go to a new segment
+    fn discard_unsynced(&self, state: &mut SpillState, unsynced: &Unsynced) -> Result<()> {
+        self.spilled.dec_by(unsynced.rows);
+        self.total_bytes
+            .fetch_sub(unsynced.bytes, Ordering::Relaxed);
+
+        let writer = match state.writer.take() {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        let (file, _) = writer.into_parts();
+
+        if let Some(segment) = state.segments.back_mut() {
+            segment.rows -= unsynced.rows;
+            segment.bytes -= unsynced.bytes;
+            file.set_len(segment.bytes)?;
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    // Reads the oldest segment, it stays on disk until every row is acknowledged
+    pub fn pop_segment(&self) -> Result<Option<(Vec<T>, Arc<SegmentAck>)>> {
+        let mut state = self.state.lock().expect("Spill queue lock is poisoned");
+
+        let segment = match state.segments.pop_front() {
+            Some(segment) => segment,
+            None => return Ok(None),
+        };
+
+        // The segment being written is sealed before it is read
+        if state.segments.is_empty() {
+            if let Some(mut writer) = state.writer.take() {
+                writer.flush()?;
+            }
+        }
+
+        self.spilled.dec_by(segment.rows);
+
+        let path = self.segment_path(segment.id);
+        let mut buf = Vec::with_capacity(segment.bytes as usize);
+        File::open(&path)?.read_to_end(&mut buf)?;
+
+        let mut items = Vec::new();
+        let mut offset = 0;
+        while offset + 4 <= buf.len() {
+            let len = u32::from_le_bytes(buf[offset..offset + 4].try_into()?) as usize;
+            offset += 4;
+
+            if offset + len > buf.len() {
+                break;
+            }
+
+            match bincode::deserialize(&buf[offset..offset + len]) {
+                Ok(item) => items.push(item),
+                Err(e) => error!(
+                    "Failed to decode spilled record in {}, error: {e}",
+                    path.display()
+                ),
+            }
+            offset += len;
+        }
+
+        if offset != buf.len() {
+            warn!(
+                "Spill segment {} has a truncated record at offset {offset}, the tail is dropped",
+                path.display()
+            );
+        }
+
+        let ack = Arc::new(SegmentAck {
+            path,
+            bytes: segment.bytes,
+            remaining: AtomicUsize::new(items.len()),
+            total_bytes: self.total_bytes.clone(),
+        });
+        if items.is_empty() {
+            ack.remove();
+        }
+
+        Ok(Some((items, ack)))
+    }
+}
+
+// Moves rows above `memory_limit` from the in-memory queue to disk and
+// brings them back once the queue has drained below half of the limit.
+// The push time and the span are not spilled, the queue wait of a replayed row starts at its replay.
+pub async fn spill_worker<T>(
+    name: String,
+    queue: Arc<SegQueue<Queued<T>>>,
+    spill: Arc<SpillQueue<T>>,
+    memory_limit: usize,
+) where
+    T: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+{
+    let mut interval = tokio::time::interval(Duration::from_millis(100));
+
+    loop {
+        interval.tick().await;
+
+        let queue_len = queue.len();
+
+        if queue_len > memory_limit {
+            let mut excess = Vec::new();
+            let mut kept = Vec::new();
+            for queued in (0..queue_len - memory_limit).map_while(|_| queue.pop()) {
+                match queued.ack.segment {
+                    // Replayed rows are still on disk, spilling them again would only move them around
+                    Some(_) => queue.push(queued),
+                    None => {
+                        excess.push(queued.item);
+                        kept.push((queued.queued_at, queued.span, queued.ack));
+                    }
+                }
+            }
+            if excess.is_empty() {
+                continue;
+            }
+
+            let excess = Arc::new(excess);
+            let result = {
+                let spill = spill.clone();
+                let excess = excess.clone();
+                tokio::task::spawn_blocking(move || spill.push_batch(&excess)).await
+            };
+            // The blocking task is over, so is its reference to the rows
+            let excess = Arc::try_unwrap(excess).unwrap_or_else(|excess| excess.as_ref().clone());
+
+            // The rows that stay in memory keep their push time, span and message,
+            // the messages of the spilled rows are released
+            let requeue = |excess: Vec<T>, skip: usize| {
+                excess.into_iter().zip(kept).skip(skip).for_each(
+                    |(item, (queued_at, span, ack))| {
+                        queue.push(Queued {
+                            item,
+                            queued_at,
+                            span,
+                            ack,
+                        })
+                    },
+                )
+            };
+
+            match result {
+                Ok((written, None)) => {
+                    if written < excess.len() {
+                        warn!(
+                            "The {name} spill queue is full, {} rows are kept in memory",
+                            excess.len() - written
+                        );
+                    }
+                    requeue(excess, written);
+                }
+                Ok((written, Some(e))) => {
+                    error!(
+                        "Failed to spill {name} rows to disk, {} rows are kept in memory, error: {e}",
+                        excess.len() - written
+                    );
+                    requeue(excess, written);
+                }
+                Err(e) => {
+                    error!("The {name} spill task failed, the rows are kept in memory, error: {e}");
+                    requeue(excess, 0);
+                }
+            }
+        } else if queue_len <= memory_limit / 2 && !spill.is_empty() {
+            let spill = spill.clone();
+            match tokio::task::spawn_blocking(move || spill.pop_segment()).await {
+                Ok(Ok(Some((items, ack)))) => {
+                    info!("Replaying {} spilled {name} rows", items.len());
+                    items.into_iter().for_each(|v| {
+                        queue.push(Queued {
+                            ack: RowAck {
+                                segment: Some(ack.clone()),
+                                message: None,
+                            },
+                            ..Queued::new(v, SpanContext::empty_context())
+                        })
+                    });
+                }
+                Ok(Ok(None)) => (),
+                Ok(Err(e)) => error!("Failed to read the {name} spill queue, error: {e}"),
+                Err(e) => error!("The {name} replay task failed, error: {e}"),
+            }
+        }
+    }
+}
+
+fn open_spill_queue<T>(
+    root: &str,
+    sink: &str,
+    table: &str,
+    segment_max_bytes: u64,
+    max_bytes: u64,
+    spilled: Gauge<u64, AtomicU64>,
+) -> Arc<SpillQueue<T>>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let dir = Path::new(root).join(sink).join(table);
+    Arc::new(
+        SpillQueue::open(&dir, segment_max_bytes, max_bytes, spilled).unwrap_or_else(|e| {
+            panic!(
+                "Failed to open the spill queue at {}, error: {e}",
+                dir.display()
+            )
+        }),
+    )
+}
+
+fn spawn_spill_worker<T>(
+    config: &FilterConfig,
+    root: &str,
+    sink: &str,
+    table: &str,
+    queue: Arc<SegQueue<Queued<T>>>,
+    metrics: &TableMetrics,
+) where
+    T: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+{
+    let spill = open_spill_queue(
+        root,
+        sink,
+        table,
+        config.spill_queue_segment_size,
+        config.spill_queue_max_size,
+        metrics.spilled.clone(),
+    );
+
+    tokio::spawn(spill_worker(
+        format!("{sink} {table}"),
+        queue,
+        spill,
+        config.spill_queue_memory_limit,
+    ));
+}
+
+// Every sink spills to its own directory under the spill queue path
+pub fn spawn_spill_workers(config: &FilterConfig, root: &str, sink: &str, queues: &SinkQueues) {
+    let metrics = &queues.metrics;
+    spawn_spill_worker(
+        config,
+        root,
+        sink,
+        "account",
+        queues.accounts.clone(),
+        &metrics.accounts,
+    );
+    spawn_spill_worker(
+        config,
+        root,
+        sink,
+        "account_audit",
+        queues.account_history.clone(),
+        &metrics.account_history,
+    );
+    spawn_spill_worker(
+        config,
+        root,
+        sink,
+        "block",
+        queues.blocks.clone(),
+        &metrics.blocks,
+    );
+    spawn_spill_worker(
+        config,
+        root,
+        sink,
+        "slot",
+        queues.slots.clone(),
+        &metrics.slots,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU32;
+
+    use super::*;
+
+    // A fresh directory under the system temp dir for every queue
+    fn temp_dir() -> PathBuf {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "spill-queue-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(dir: &Path, segment_max_bytes: u64, max_bytes: u64) -> SpillQueue<u64> {
+        SpillQueue::open(dir, segment_max_bytes, max_bytes, Gauge::default()).unwrap()
+    }
+
+    fn segment_files(dir: &Path) -> usize {
+        fs::read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    fn pops_the_pushed_rows_in_order() {
+        let dir = temp_dir();
+        let queue = open(&dir, 1 << 20, 1 << 20);
+
+        assert_eq!(queue.push_batch(&[1, 2, 3]).0, 3);
+        assert_eq!(queue.push_batch(&[4]).0, 1);
+        assert_eq!(queue.spilled.get(), 4);
+
+        let (items, _ack) = queue.pop_segment().unwrap().unwrap();
+        assert_eq!(items, vec![1, 2, 3, 4]);
+        assert_eq!(queue.spilled.get(), 0);
+        assert!(queue.is_empty());
+        assert!(queue.pop_segment().unwrap().is_none());
+    }
+
+    #[test]
+    fn rotates_segments_and_pops_the_oldest_first() {
+        let dir = temp_dir();
+        // A u64 record takes 12 bytes, two of them fill a segment
+        let queue = open(&dir, 24, 1 << 20);
+
+        assert_eq!(queue.push_batch(&[1, 2, 3, 4, 5]).0, 5);
+        assert_eq!(segment_files(&dir), 3);
+
+        assert_eq!(queue.pop_segment().unwrap().unwrap().0, vec![1, 2]);
+        assert_eq!(queue.pop_segment().unwrap().unwrap().0, vec![3, 4]);
+        assert_eq!(queue.pop_segment().unwrap().unwrap().0, vec![5]);
+    }
+
+    #[test]
+    fn stops_at_the_size_limit() {
+        let dir = temp_dir();
+        let queue = open(&dir, 1 << 20, 30);
+
+        let (written, error) = queue.push_batch(&[1, 2, 3]);
+        assert_eq!(written, 2);
+        assert!(error.is_none());
+        assert_eq!(queue.pop_segment().unwrap().unwrap().0, vec![1, 2]);
+    }
+
+    #[test]
+    fn removes_a_segment_once_every_row_is_acknowledged() {
+        let dir = temp_dir();
+        let queue = open(&dir, 1 << 20, 1 << 20);
+        queue.push_batch(&[1, 2]);
+
+        let (_, ack) = queue.pop_segment().unwrap().unwrap();
+        ack.done();
+        assert_eq!(segment_files(&dir), 1);
+        assert_eq!(queue.total_bytes.load(Ordering::Relaxed), 24);
+
+        ack.done();
+        assert_eq!(segment_files(&dir), 0);
+        assert_eq!(queue.total_bytes.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn replays_the_segments_of_a_previous_run() {
+        let dir = temp_dir();
+        {
+            let queue = open(&dir, 24, 1 << 20);
+            queue.push_batch(&[1, 2, 3, 4, 5]);
+            // A segment popped but not acknowledged is replayed again
+            let (_, ack) = queue.pop_segment().unwrap().unwrap();
+            ack.done();
+            let (_, ack) = queue.pop_segment().unwrap().unwrap();
+            ack.done();
+            ack.done();
+        }
+
+        let queue = open(&dir, 24, 1 << 20);
+        assert_eq!(queue.spilled.get(), 3);
+        assert_eq!(queue.pop_segment().unwrap().unwrap().0, vec![1, 2]);
+        assert_eq!(queue.pop_segment().unwrap().unwrap().0, vec![5]);
+
+        // New segments continue after the ids found on disk
+        queue.push_batch(&[4]);
+        assert_eq!(queue.pop_segment().unwrap().unwrap().0, vec![4]);
+    }
+
+    #[test]
+    fn counts_the_complete_records_of_a_segment() {
+        let dir = temp_dir();
+        let queue = open(&dir, 1 << 20, 1 << 20);
+        queue.push_batch(&[1, 2, 3]);
+        let path = queue.segment_path(0);
+        assert_eq!(count_records(&path).unwrap(), 3);
+
+        // A crash in the middle of a record leaves a truncated tail
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(30).unwrap();
+        assert_eq!(count_records(&path).unwrap(), 2);
+
+        let queue = open(&dir, 1 << 20, 1 << 20);
+        assert_eq!(queue.pop_segment().unwrap().unwrap().0, vec![1, 2]);
+    }
+}
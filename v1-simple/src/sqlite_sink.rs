@@ -0,0 +1,190 @@
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::Utc;
+use kafka_common::kafka_structs::UpdateSlotStatus;
+use log::info;
+use rusqlite::params;
+use rusqlite::Connection;
+use rusqlite::ErrorCode;
+
+use crate::config::PersistenceMode;
+use crate::db::DbAccountInfo;
+use crate::db::DbBlockInfo;
+use crate::db_retry::ErrorKind;
+use crate::db_statements::ACCOUNT_HISTORY_INSERT;
+use crate::db_statements::ACCOUNT_UPSERT;
+use crate::db_statements::BLOCK_INSERT;
+use crate::db_statements::SLOT_UPSERT_WITHOUT_PARENT;
+use crate::db_statements::SLOT_UPSERT_WITH_PARENT;
+use crate::sink::Sink;
+
+pub const SQLITE_SCHEME: &str = "sqlite://";
+
+const SQLITE_SCHEMA: &str = include_str!("../db/create_schema_sqlite.sql");
+
+fn insert_account(
+    connection: &Connection,
+    statement: &str,
+    account: &DbAccountInfo,
+    updated_on: &chrono::NaiveDateTime,
+) -> rusqlite::Result<usize> {
+    connection.prepare_cached(statement)?.execute(params![
+        account.pubkey,
+        account.slot,
+        account.owner,
+        account.lamports,
+        account.executable,
+        account.rent_epoch,
+        account.data,
+        account.write_version,
+        updated_on,
+        account.txn_signature,
+    ])
+}
+
+// Embedded replacement of the Postgres sink, the same statements are executed against a local file.
+// Every batch is written in a single transaction.
+pub struct SqliteSink {
+    mode: PersistenceMode,
+    connection: Mutex<Connection>,
+}
+
+impl SqliteSink {
+    // Accepts sqlite:///path/to/file.db or sqlite://:memory:
+    pub fn open(connection_str: &str, mode: PersistenceMode) -> Result<Self> {
+        let path = connection_str
+            .strip_prefix(SQLITE_SCHEME)
+            .ok_or_else(|| anyhow!("Not a SQLite connection string: {connection_str}"))?;
+
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.execute_batch(SQLITE_SCHEMA)?;
+
+        info!("Opened SQLite database {path}");
+
+        Ok(Self {
+            mode,
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn transaction<F>(&self, write: F) -> Result<()>
+    where
+        F: FnOnce(&Connection) -> rusqlite::Result<()>,
+    {
+        tokio::task::block_in_place(|| {
+            let mut connection = self
+                .connection
+                .lock()
+                .expect("SQLite connection lock is poisoned");
+            let transaction = connection.transaction()?;
+            write(&transaction)?;
+            transaction.commit()?;
+            Ok(())
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for SqliteSink {
+    fn name(&self) -> &str {
+        "sqlite"
+    }
+
+    fn batch_size(&self) -> usize {
+        1000
+    }
+
+    fn classify_error(&self, error: &anyhow::Error) -> ErrorKind {
+        match error.downcast_ref::<rusqlite::Error>() {
+            Some(rusqlite::Error::SqliteFailure(e, _)) => match e.code {
+                ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => ErrorKind::Transient,
+                ErrorCode::ConstraintViolation | ErrorCode::TypeMismatch | ErrorCode::TooBig => {
+                    ErrorKind::Permanent
+                }
+                _ => ErrorKind::Transient,
+            },
+            Some(rusqlite::Error::ToSqlConversionFailure(_)) => ErrorKind::Permanent,
+            _ => ErrorKind::Transient,
+        }
+    }
+
+    async fn write_accounts(&self, accounts: &[DbAccountInfo]) -> Result<()> {
+        let updated_on = Utc::now().naive_utc();
+
+        // SQLite has no data-modifying CTEs, both statements of the Both mode share the transaction instead
+        let statements: &[&str] = match self.mode {
+            PersistenceMode::LatestState => &[ACCOUNT_UPSERT],
+            PersistenceMode::History => &[ACCOUNT_HISTORY_INSERT],
+            PersistenceMode::Both => &[ACCOUNT_HISTORY_INSERT, ACCOUNT_UPSERT],
+        };
+
+        self.transaction(|connection| {
+            for account in accounts {
+                for statement in statements {
+                    insert_account(connection, statement, account, &updated_on)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    async fn write_account_history(&self, accounts: &[DbAccountInfo]) -> Result<()> {
+        if !self.mode.writes_history() {
+            return Ok(());
+        }
+
+        let updated_on = Utc::now().naive_utc();
+        self.transaction(|connection| {
+            for account in accounts {
+                insert_account(connection, ACCOUNT_HISTORY_INSERT, account, &updated_on)?;
+            }
+            Ok(())
+        })
+    }
+
+    async fn write_blocks(&self, blocks: &[DbBlockInfo]) -> Result<()> {
+        let updated_on = Utc::now().naive_utc();
+        let rewards = blocks
+            .iter()
+            .map(|block| serde_json::to_string(&block.rewards))
+            .collect::<serde_json::Result<Vec<_>>>()?;
+
+        self.transaction(|connection| {
+            let mut statement = connection.prepare_cached(BLOCK_INSERT)?;
+            for (block, rewards) in blocks.iter().zip(rewards) {
+                statement.execute(params![
+                    block.slot,
+                    block.blockhash,
+                    rewards,
+                    block.block_time,
+                    block.block_height,
+                    updated_on,
+                ])?;
+            }
+            Ok(())
+        })
+    }
+
+    async fn write_slots(&self, slots: &[UpdateSlotStatus]) -> Result<()> {
+        let updated_on = Utc::now().naive_utc();
+
+        self.transaction(|connection| {
+            for slot in slots {
+                let status = slot.status.to_string();
+                match slot.parent {
+                    Some(parent) => connection
+                        .prepare_cached(SLOT_UPSERT_WITH_PARENT)?
+                        .execute(params![slot.slot as i64, parent as i64, status, updated_on])?,
+                    None => connection
+                        .prepare_cached(SLOT_UPSERT_WITHOUT_PARENT)?
+                        .execute(params![slot.slot as i64, status, updated_on])?,
+                };
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,58 @@
+use anyhow::Result;
+use opentelemetry::global;
+use opentelemetry::global::BoxedTracer;
+use opentelemetry::sdk::export::trace::stdout;
+use opentelemetry::sdk::trace;
+use opentelemetry::sdk::trace::Sampler;
+use opentelemetry::sdk::Resource;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+
+use crate::config::FilterConfig;
+use crate::config::TracingExporter;
+
+const TRACER_NAME: &str = "geyser-neon-filter";
+
+// Installs the global tracer of the configured exporter.
+// Without one the spans are not recorded and their contexts are invalid, which costs next to nothing.
+pub fn init_tracing(config: &FilterConfig) -> Result<()> {
+    // The ratio applies to the Kafka messages, the spans of a message follow its decision
+    let trace_config = trace::config()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.tracing_sample_ratio,
+        ))))
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            TRACER_NAME,
+        )]));
+
+    match config.tracing_exporter {
+        TracingExporter::None => (),
+        TracingExporter::Otlp => {
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(&config.otlp_endpoint),
+                )
+                .with_trace_config(trace_config)
+                .install_batch(opentelemetry::runtime::Tokio)?;
+        }
+        TracingExporter::Stdout => {
+            stdout::new_pipeline()
+                .with_trace_config(trace_config)
+                .install_simple();
+        }
+    }
+    Ok(())
+}
+
+// Exports the spans still buffered
+pub fn shutdown_tracing() {
+    global::shutdown_tracer_provider();
+}
+
+pub fn tracer() -> BoxedTracer {
+    global::tracer(TRACER_NAME)
+}
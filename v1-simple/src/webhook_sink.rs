@@ -0,0 +1,199 @@
+use std::fmt;
+use std::fmt::Write;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use hmac::Hmac;
+use hmac::Mac;
+use kafka_common::kafka_structs::UpdateSlotStatus;
+use reqwest::Client;
+use reqwest::StatusCode;
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::config::WebhookSinkConfig;
+use crate::db::DbAccountInfo;
+use crate::db::DbBlockInfo;
+use crate::db_retry::ErrorKind;
+use crate::sink::Sink;
+
+#[derive(Debug)]
+struct WebhookError {
+    status: StatusCode,
+    message: String,
+}
+
+impl fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Webhook returned {}: {}",
+            self.status,
+            self.message.trim()
+        )
+    }
+}
+
+impl std::error::Error for WebhookError {}
+
+#[derive(Serialize)]
+struct AccountNotification {
+    pubkey: String,
+    owner: String,
+    lamports: i64,
+    executable: bool,
+    rent_epoch: i64,
+    // Base64
+    data: String,
+    slot: i64,
+    write_version: i64,
+    txn_signature: Option<String>,
+    retrieved_time: NaiveDateTime,
+}
+
+impl From<&DbAccountInfo> for AccountNotification {
+    fn from(account: &DbAccountInfo) -> Self {
+        Self {
+            pubkey: bs58::encode(&account.pubkey).into_string(),
+            owner: bs58::encode(&account.owner).into_string(),
+            lamports: account.lamports,
+            executable: account.executable,
+            rent_epoch: account.rent_epoch,
+            data: base64::encode(&account.data),
+            slot: account.slot,
+            write_version: account.write_version,
+            txn_signature: account
+                .txn_signature
+                .as_ref()
+                .map(|signature| bs58::encode(signature).into_string()),
+            retrieved_time: account.retrieved_time,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Notification {
+    accounts: Vec<AccountNotification>,
+}
+
+// Posts the changed accounts matching the endpoint rules to a single URL
+pub struct WebhookSink {
+    config: WebhookSinkConfig,
+    client: Client,
+}
+
+impl WebhookSink {
+    pub fn new(config: WebhookSinkConfig) -> Self {
+        let client = Client::builder()
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()
+            .expect("Failed to create the webhook HTTP client");
+
+        Self { config, client }
+    }
+
+    fn matches(&self, notification: &AccountNotification) -> bool {
+        (self.config.include_owners.is_empty() && self.config.include_pubkeys.is_empty())
+            || self.config.include_pubkeys.contains(&notification.pubkey)
+            || self.config.include_owners.contains(&notification.owner)
+    }
+
+    // sha256=<hex encoded HMAC-SHA256 of the request body>
+    fn sign(&self, secret: &str, body: &[u8]) -> Result<String> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .map_err(|e| anyhow!("Invalid webhook secret, error: {e}"))?;
+        mac.update(body);
+
+        let mut signature = String::from("sha256=");
+        for byte in mac.finalize().into_bytes() {
+            write!(signature, "{byte:02x}")?;
+        }
+        Ok(signature)
+    }
+}
+
+#[async_trait]
+impl Sink for WebhookSink {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn batch_size(&self) -> usize {
+        self.config.batch_size
+    }
+
+    fn classify_error(&self, error: &anyhow::Error) -> ErrorKind {
+        if let Some(e) = error.downcast_ref::<WebhookError>() {
+            return match e.status {
+                // The request itself is rejected, sending it again doesn't help
+                StatusCode::BAD_REQUEST
+                | StatusCode::PAYLOAD_TOO_LARGE
+                | StatusCode::UNPROCESSABLE_ENTITY => ErrorKind::Permanent,
+                // A wrong url or secret, the rows wait until the endpoint is fixed
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND => {
+                    ErrorKind::Misconfigured
+                }
+                _ => ErrorKind::Transient,
+            };
+        }
+
+        match error
+            .chain()
+            .find_map(|e| e.downcast_ref::<reqwest::Error>())
+        {
+            Some(e) if e.is_connect() => ErrorKind::Connection,
+            _ => ErrorKind::Transient,
+        }
+    }
+
+    async fn write_accounts(&self, accounts: &[DbAccountInfo]) -> Result<()> {
+        let accounts: Vec<AccountNotification> = accounts
+            .iter()
+            .map(AccountNotification::from)
+            .filter(|notification| self.matches(notification))
+            .collect();
+
+        if accounts.is_empty() {
+            return Ok(());
+        }
+
+        let body = serde_json::to_vec(&Notification { accounts })?;
+        let mut request = self
+            .client
+            .post(&self.config.url)
+            .header("Content-Type", "application/json");
+
+        if let Some(secret) = &self.config.secret {
+            request = request.header("X-Signature", self.sign(secret, &body)?);
+        }
+
+        let response = request.body(body).send().await.map_err(|e| {
+            anyhow!(e).context(format!("Failed to send webhook to {}", self.config.url))
+        })?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let message = response.text().await.unwrap_or_default();
+            return Err(anyhow!(WebhookError { status, message }));
+        }
+
+        Ok(())
+    }
+
+    // Only the latest version of an account in a coalescing window is sent
+    async fn write_account_history(&self, _accounts: &[DbAccountInfo]) -> Result<()> {
+        Ok(())
+    }
+
+    // Only account changes are sent
+    async fn write_blocks(&self, _blocks: &[DbBlockInfo]) -> Result<()> {
+        Ok(())
+    }
+
+    async fn write_slots(&self, _slots: &[UpdateSlotStatus]) -> Result<()> {
+        Ok(())
+    }
+}